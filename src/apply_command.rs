@@ -0,0 +1,59 @@
+use crate::{
+    log_array::{Index, LogEntryEnum},
+    raft::{Raft, ReplicableCommand},
+};
+
+/// Called with every committed command and its log index, in log order.
+pub trait ApplyCommandFnMut<Command>: 'static + Send + FnMut(Index, Command) {}
+
+impl<Command, T: 'static + Send + FnMut(Index, Command)> ApplyCommandFnMut<Command> for T {}
+
+impl<Command: ReplicableCommand> Raft<Command> {
+    /// Starts a task that hands committed commands to `apply_command`.
+    ///
+    /// The task is woken up each time the commit index moves forward. No-op
+    /// and configuration entries are skipped, but still count as applied.
+    /// `apply_command` may block, it is called on the blocking threads of the
+    /// runtime.
+    pub(crate) fn run_apply_command_daemon(
+        &self,
+        mut apply_command: impl ApplyCommandFnMut<Command>,
+    ) {
+        let this = self.clone();
+        self.daemons.spawn(&self.thread_pool, async move {
+            loop {
+                let entries = {
+                    let mut rf = this.inner_state.lock().unwrap();
+                    if rf.last_applied >= rf.commit_index {
+                        None
+                    } else {
+                        let entries = rf
+                            .log
+                            .between(rf.last_applied + 1, rf.commit_index + 1)
+                            .to_vec();
+                        rf.last_applied = rf.commit_index;
+                        Some(entries)
+                    }
+                };
+                let Some(entries) = entries else {
+                    this.apply_command_signal.notified().await;
+                    continue;
+                };
+
+                let apply = this.daemons.spawn_blocking(&this.thread_pool, move || {
+                    for entry in entries {
+                        if let LogEntryEnum::Command(command) = entry.command {
+                            apply_command(entry.index, command);
+                        }
+                    }
+                    apply_command
+                });
+                match apply.await {
+                    Ok(returned) => apply_command = returned,
+                    // `apply_command` panicked.
+                    Err(_) => return,
+                }
+            }
+        })
+    }
+}
@@ -1,97 +1,262 @@
-use std::{env, process};
+use std::{collections::HashMap, env, path::PathBuf, process, str::FromStr};
 
-use crate::raft::ClusterMember;
+use raft::{raft::ClusterMember, raft_config::RaftConfig};
 
 #[derive(Debug, Default)]
 pub struct Config {
+    // Position of this node in `cluster`, same as its ID
     pub index: usize,
-    pub id: String,
+    pub id: u64,
+    // Address of the Raft RPC server of this node
     pub address: String,
     pub http: String,
+    // All nodes in the cluster, this one included. Node `i` has ID `i`.
     pub cluster: Vec<ClusterMember>,
+    // HTTP addresses of the nodes in the cluster, by ID. Only this node and
+    // those given one in --cluster are known.
+    pub cluster_http: HashMap<u64, String>,
+    // Loaded from --config, then overridden by the flags of each tunable
+    pub raft: RaftConfig,
+    // Set to shard the keys, splitting shards that hold more keys than this
+    pub shard_max_keys: Option<usize>,
+}
+
+// Sets a tunable given on the command line
+type RaftConfigOverride = Box<dyn FnOnce(&mut RaftConfig)>;
+
+/// Reads the value of `flag` from `args`.
+fn parse_value<T: FromStr>(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+) -> Result<T, String> {
+    let Some(value) = args.next() else {
+        return Err(format!("Missing value for parameter: {}", flag));
+    };
+    value
+        .parse()
+        .map_err(|_| format!("Malformed value for parameter {}, got: {}", flag, value))
 }
 
 impl Config {
+    /// Parses the command line, or exits if it is not valid.
     pub fn new() -> Self {
+        match Self::parse(env::args().skip(1)) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Parses `args`, the command line without the name of the program.
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
         let mut config = Config::default();
+        let mut node = None;
+        let mut config_file = None;
+        // Applied on top of the config file, whatever the order of the flags
+        let mut overrides: Vec<RaftConfigOverride> = vec![];
 
-        let mut args = env::args().skip(1);
         while let Some(arg) = args.next() {
             match arg.as_str() {
                 "--node" => {
-                    if let Some(node) = args.next() {
-                        match node.parse::<usize>() {
-                            Ok(index) => config.index = index,
-                            Err(_) => {
-                                println!("Expected $value to be a valid integer in `--node $value`, got: {}", node);
-                                process::exit(1);
-                            }
+                    let Some(value) = args.next() else {
+                        return Err("Missing value for parameter: --node".to_owned());
+                    };
+                    match value.parse::<u64>() {
+                        Ok(id) => node = Some(id),
+                        Err(_) => {
+                            return Err(format!(
+                                "Expected $id to be a valid integer in `--node $id`, got: {}",
+                                value
+                            ))
                         }
-                    } else {
-                        println!("Missing value for parameter: --node");
-                        process::exit(1);
                     }
                 }
                 "--http" => {
-                    if let Some(http) = args.next() {
-                        config.http = http;
-                    } else {
-                        println!("Missing value for parameter: --http");
-                        process::exit(1)
-                    }
+                    let Some(http) = args.next() else {
+                        return Err("Missing value for parameter: --http".to_owned());
+                    };
+                    config.http = http;
                 }
                 "--cluster" => {
-                    if let Some(cluster) = args.next() {
-                        for part in cluster.split(';') {
-                            let id_address: Vec<&str> = part.split(',').collect();
-                            if id_address.len() != 2 {
-                                println!(
-                                    "Expected $id,$ip format in `--cluster $id,$ip`, got: {}",
-                                    part
-                                );
-                                process::exit(1);
-                            }
-                            match id_address[0].parse::<u64>() {
-                                Ok(id) => {
-                                    config.cluster.push(ClusterMember {
-                                        id,
-                                        address: id_address[1].to_string(),
-                                        ..Default::default()
-                                    });
-                                }
-                                Err(_) => {
-                                    println!("Expected $id to be a valid integer in `--cluster $id,$ip`, got: {}", id_address[0]);
-                                    process::exit(1);
-                                }
-                            }
+                    let Some(cluster) = args.next() else {
+                        return Err("Missing value for parameter: --cluster".to_owned());
+                    };
+                    for part in cluster.split(';') {
+                        let id_address: Vec<&str> = part.split(',').collect();
+                        if id_address.len() != 2 && id_address.len() != 3 {
+                            return Err(format!(
+                                "Expected $id,$ip or $id,$ip,$http format in `--cluster`, got: {}",
+                                part
+                            ));
+                        }
+                        let Ok(id) = id_address[0].parse::<u64>() else {
+                            return Err(format!(
+                                "Expected $id to be a valid integer in `--cluster $id,$ip`, got: {}",
+                                id_address[0]
+                            ));
+                        };
+                        config.cluster.push(ClusterMember {
+                            id,
+                            address: id_address[1].to_string(),
+                            ..Default::default()
+                        });
+                        if let Some(http) = id_address.get(2) {
+                            config.cluster_http.insert(id, http.to_string());
                         }
-                    } else {
-                        println!("Missing value for parameter: --cluster");
-                        process::exit(1);
                     }
                 }
-                _ => {
-                    println!("Unknown parameter: {}", arg);
-                    process::exit(1);
+                "--config" => config_file = Some(parse_value::<PathBuf>(&mut args, &arg)?),
+                "--heartbeat-interval-ms" => {
+                    let value = parse_value(&mut args, &arg)?;
+                    overrides.push(Box::new(move |raft| raft.heartbeat_interval_millis = value));
                 }
+                "--election-timeout-min-ms" => {
+                    let value = parse_value(&mut args, &arg)?;
+                    overrides.push(Box::new(move |raft| {
+                        raft.election_timeout_min_millis = value
+                    }));
+                }
+                "--election-timeout-max-ms" => {
+                    let value = parse_value(&mut args, &arg)?;
+                    overrides.push(Box::new(move |raft| {
+                        raft.election_timeout_max_millis = value
+                    }));
+                }
+                "--max-entries-per-append" => {
+                    let value = parse_value(&mut args, &arg)?;
+                    overrides.push(Box::new(move |raft| raft.max_entries_per_append = value));
+                }
+                "--max-inflight-appends" => {
+                    let value = parse_value(&mut args, &arg)?;
+                    overrides.push(Box::new(move |raft| raft.max_inflight_appends = value));
+                }
+                "--max-inflight-bytes" => {
+                    let value = parse_value(&mut args, &arg)?;
+                    overrides.push(Box::new(move |raft| raft.max_inflight_bytes = value));
+                }
+                "--proposal-batch-delay-us" => {
+                    let value = parse_value(&mut args, &arg)?;
+                    overrides.push(Box::new(move |raft| {
+                        raft.proposal_batch_delay_micros = value
+                    }));
+                }
+                "--shard-max-keys" => {
+                    config.shard_max_keys = Some(parse_value(&mut args, &arg)?);
+                }
+                "--data-dir" => {
+                    let value: PathBuf = parse_value(&mut args, &arg)?;
+                    overrides.push(Box::new(move |raft| raft.data_dir = Some(value)));
+                }
+                _ => return Err(format!("Unknown parameter: {}", arg)),
             }
         }
 
-        if config.index == 0 {
-            eprintln!("Missing required parameter: --node $index");
-            process::exit(1);
-        }
+        let Some(id) = node else {
+            return Err("Missing required parameter: --node $id".to_owned());
+        };
 
         if config.http.is_empty() {
-            eprintln!("Missing required parameter: --http $address");
-            process::exit(1);
+            return Err("Missing required parameter: --http $address".to_owned());
         }
 
         if config.cluster.is_empty() {
-            eprintln!("Missing required parameter: --cluster $node1Id,$node1Address;...;$nodeNId,$nodeNAddress");
-            process::exit(1);
+            return Err("Missing required parameter: --cluster $node1Id,$node1Address[,$node1Http];...;$nodeNId,$nodeNAddress[,$nodeNHttp]".to_owned());
         }
 
-        config
+        // Node IDs are the indexes of the nodes in the cluster, so that every
+        // node sees the same order whatever the order of `--cluster`.
+        config.cluster.sort_by_key(|member| member.id);
+        for (index, member) in config.cluster.iter().enumerate() {
+            if member.id != index as u64 {
+                return Err(format!(
+                    "Expected the node IDs in --cluster to be 0 to {}, got: {}",
+                    config.cluster.len() - 1,
+                    member.id
+                ));
+            }
+        }
+        let Some(member) = config.cluster.get(id as usize) else {
+            return Err(format!("Node {} given by --node is not in --cluster", id));
+        };
+        config.index = id as usize;
+        config.id = id;
+        config.address = member.address.clone();
+        config.cluster_http.insert(id, config.http.clone());
+
+        if let Some(config_file) = config_file {
+            config.raft = RaftConfig::load(&config_file).map_err(|e| e.to_string())?;
+        }
+        for apply_override in overrides {
+            apply_override(&mut config.raft);
+        }
+        config.raft.validate().map_err(|e| e.to_string())?;
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Config, String> {
+        Config::parse(args.split_whitespace().map(str::to_owned))
+    }
+
+    const CLUSTER: &str = "--cluster 1,127.0.0.1:9001,127.0.0.1:8001;0,127.0.0.1:9000";
+
+    #[test]
+    fn node_zero_is_parsed() {
+        let config = parse(&format!("--node 0 --http 127.0.0.1:8000 {}", CLUSTER)).unwrap();
+        assert_eq!(config.index, 0);
+        assert_eq!(config.id, 0);
+        assert_eq!(config.address, "127.0.0.1:9000");
+        let ids: Vec<_> = config.cluster.iter().map(|member| member.id).collect();
+        assert_eq!(ids, vec![0, 1]);
+        assert_eq!(
+            config.cluster_http,
+            HashMap::from([
+                (0, "127.0.0.1:8000".to_owned()),
+                (1, "127.0.0.1:8001".to_owned())
+            ])
+        );
+        assert_eq!(config.shard_max_keys, None);
+    }
+
+    #[test]
+    fn node_is_required() {
+        let error = parse(&format!("--http 127.0.0.1:8000 {}", CLUSTER))
+            .err()
+            .unwrap();
+        assert_eq!(error, "Missing required parameter: --node $id");
+        let error = parse("--node").err().unwrap();
+        assert_eq!(error, "Missing value for parameter: --node");
+        let error = parse(&format!("--node 2 --http 127.0.0.1:8000 {}", CLUSTER))
+            .err()
+            .unwrap();
+        assert_eq!(error, "Node 2 given by --node is not in --cluster");
+    }
+
+    #[test]
+    fn flags_override_tunables() {
+        let args = format!(
+            "--node 1 --http 127.0.0.1:8001 {} --max-inflight-appends 9 --shard-max-keys 100",
+            CLUSTER
+        );
+        let config = parse(&args).unwrap();
+        assert_eq!(config.raft.max_inflight_appends, 9);
+        assert_eq!(config.shard_max_keys, Some(100));
+
+        let error = parse(&format!("{} --max-inflight-appends x", args))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            "Malformed value for parameter --max-inflight-appends, got: x"
+        );
+        assert!(parse(&format!("{} --election-timeout-min-ms 1000", args)).is_err());
+        assert!(parse(&format!("{} --verbose", args)).is_err());
     }
 }
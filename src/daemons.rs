@@ -0,0 +1,100 @@
+use std::{
+    future::Future,
+    pin::pin,
+    sync::{Arc, Condvar, Mutex},
+    time::Instant,
+};
+
+use tokio::sync::watch;
+
+#[derive(Default)]
+struct Running {
+    count: Mutex<usize>,
+    // Notified each time a daemon exits
+    exited: Condvar,
+}
+
+/// The tasks started by a Raft instance, so that they can be stopped and
+/// waited for when the instance is killed.
+#[derive(Clone)]
+pub(crate) struct Daemons {
+    running: Arc<Running>,
+    stopped: Arc<watch::Sender<bool>>,
+}
+
+// Held by a daemon while it runs
+struct RunningGuard(Arc<Running>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        *self.0.count.lock().unwrap() -= 1;
+        self.0.exited.notify_all();
+    }
+}
+
+impl Daemons {
+    pub fn create() -> Self {
+        Daemons {
+            running: Arc::default(),
+            stopped: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    fn guard(&self) -> RunningGuard {
+        *self.running.count.lock().unwrap() += 1;
+        RunningGuard(self.running.clone())
+    }
+
+    /// Starts a task on `runtime`. The task is cancelled by `stop()`.
+    pub fn spawn(
+        &self,
+        runtime: &tokio::runtime::Handle,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) {
+        let guard = self.guard();
+        let mut stopped = self.stopped.subscribe();
+        runtime.spawn(async move {
+            let _guard = guard;
+            let stop = pin!(stopped.wait_for(|stopped| *stopped));
+            futures_util::future::select(pin!(future), stop).await;
+        });
+    }
+
+    /// Runs `f` on the blocking threads of `runtime`. The call counts as
+    /// running until `f` returns, even if the task awaiting it is cancelled.
+    pub fn spawn_blocking<T: Send + 'static>(
+        &self,
+        runtime: &tokio::runtime::Handle,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> tokio::task::JoinHandle<T> {
+        let guard = self.guard();
+        runtime.spawn_blocking(move || {
+            let _guard = guard;
+            f()
+        })
+    }
+
+    /// Cancels the tasks.
+    pub fn stop(&self) {
+        self.stopped.send_replace(true);
+    }
+
+    /// Waits for all tasks and blocking calls to exit, or for `deadline`.
+    /// Returns the number of those still running.
+    pub fn wait(&self, deadline: Instant) -> usize {
+        let mut count = self.running.count.lock().unwrap();
+        while *count > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            count = self
+                .running
+                .exited
+                .wait_timeout(count, deadline - now)
+                .unwrap()
+                .0;
+        }
+        *count
+    }
+}
@@ -1,17 +1,621 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Once, RwLock, Weak,
+    },
+    time::Duration,
+};
 
-use tokio::sync::OnceCell;
+use async_trait::async_trait;
+use futures_util::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{mpsc, oneshot},
+    time::MissedTickBehavior,
+};
 
-use crate::remote::remote_raft::RemoteRaft;
+use crate::{
+    messages::{
+        AppendEntriesArgs, AppendEntriesReply, RequestVoteArgs, RequestVoteReply, TimeoutNowArgs,
+        TimeoutNowReply,
+    },
+    raft::Raft,
+    raft_builder::BuildError,
+    raft_state::GroupId,
+    remote::remote_raft::{RemoteRaft, RemoteRaftConnector},
+};
 
-pub(crate) trait RaftService {}
+// Frames larger than this are refused, so that a corrupted length does not
+// make us allocate without bound.
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+// Heartbeats to the same host that are not urgent are collected, and sent as
+// one frame on each tick.
+const HEARTBEAT_TICK: Duration = Duration::from_millis(5);
 
-pub struct LazyRaftServiceClient {
+/// An RPC sent to a Raft instance.
+#[derive(Debug, Serialize, Deserialize)]
+enum RaftRequest {
+    RequestVote(RequestVoteArgs),
+    AppendEntries(AppendEntriesArgs<Vec<u8>>),
+    TimeoutNow(TimeoutNowArgs),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RaftReply {
+    RequestVote(RequestVoteReply),
+    AppendEntries(AppendEntriesReply),
+    TimeoutNow(TimeoutNowReply),
+}
+
+/// A message on a connection between two hosts. Each frame is its length as
+/// u32 in little endian, then the frame in bincode.
+///
+/// The RPCs of all groups share the connection. A reply carries the `id` of
+/// its request, and replies may come back in any order. An error reply holds
+/// why the RPC was not delivered, e.g. the group is not on the host.
+#[derive(Debug, Serialize, Deserialize)]
+enum Frame {
+    Request {
+        id: u64,
+        group: GroupId,
+        request: RaftRequest,
+    },
+    /// Heartbeats of many groups, answered by one `HeartbeatReplies`.
+    Heartbeats {
+        id: u64,
+        heartbeats: Vec<(GroupId, AppendEntriesArgs<Vec<u8>>)>,
+    },
+    Reply {
+        id: u64,
+        reply: Result<RaftReply, String>,
+    },
+    /// Replies in the order of the heartbeats.
+    HeartbeatReplies {
+        id: u64,
+        replies: Vec<Result<AppendEntriesReply, String>>,
+    },
+}
+
+impl Frame {
+    fn id(&self) -> u64 {
+        match self {
+            Self::Request { id, .. }
+            | Self::Heartbeats { id, .. }
+            | Self::Reply { id, .. }
+            | Self::HeartbeatReplies { id, .. } => *id,
+        }
+    }
+}
+
+async fn write_frame(stream: &mut OwnedWriteHalf, message: &impl Serialize) -> io::Result<()> {
+    let frame = bincode::serialize(message).map_err(io::Error::other)?;
+    let len = u32::try_from(frame.len())
+        .ok()
+        .filter(|len| *len as usize <= MAX_FRAME_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    stream.write_all(&len.to_le_bytes()).await?;
+    stream.write_all(&frame).await
+}
+
+async fn read_frame<T: DeserializeOwned>(stream: &mut OwnedReadHalf) -> io::Result<T> {
+    let len = stream.read_u32_le().await? as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame).await?;
+    bincode::deserialize(&frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes the frames sent to `frames` until the channel is closed. Frames that
+/// are queued together are flushed together.
+async fn run_writer(mut stream: OwnedWriteHalf, mut frames: mpsc::UnboundedReceiver<Frame>) {
+    while let Some(frame) = frames.recv().await {
+        let mut result = write_frame(&mut stream, &frame).await;
+        while let (Ok(()), Ok(frame)) = (&result, frames.try_recv()) {
+            result = write_frame(&mut stream, &frame).await;
+        }
+        if let Err(e) = result.and(stream.flush().await) {
+            log::debug!("Raft connection closed: {}", e);
+            return;
+        }
+    }
+}
+
+/// Serves the RPCs sent by `HostClient`s to the Raft groups on this host.
+#[derive(Clone, Default)]
+pub struct RaftService {
+    groups: Arc<RwLock<HashMap<GroupId, Raft<Vec<u8>>>>>,
+}
+
+impl RaftService {
+    /// Creates a service of the group of `raft`.
+    pub fn new(raft: Raft<Vec<u8>>) -> Self {
+        let service = Self::default();
+        service.groups.write().unwrap().insert(raft.group(), raft);
+        service
+    }
+
+    /// Serves the group of `raft`. Fails if the group is already served, by
+    /// another instance that must be removed first.
+    pub fn add_group(&self, raft: Raft<Vec<u8>>) -> Result<(), BuildError> {
+        match self.groups.write().unwrap().entry(raft.group()) {
+            Entry::Occupied(_) => Err(BuildError::GroupExists(raft.group())),
+            Entry::Vacant(entry) => {
+                entry.insert(raft);
+                Ok(())
+            }
+        }
+    }
+
+    /// Stops serving `group`. Returns the instance that served it.
+    pub fn remove_group(&self, group: GroupId) -> Option<Raft<Vec<u8>>> {
+        self.groups.write().unwrap().remove(&group)
+    }
+
+    pub fn group(&self, group: GroupId) -> Option<Raft<Vec<u8>>> {
+        self.groups.read().unwrap().get(&group).cloned()
+    }
+
+    /// Serves the connections accepted by `listener`, until accepting fails.
+    pub async fn run(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            stream.set_nodelay(true)?;
+            let this = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = this.serve_connection(stream).await {
+                    if e.kind() != io::ErrorKind::UnexpectedEof {
+                        log::debug!("Raft connection closed: {}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Reads requests from `stream`, and answers each of them as soon as it
+    /// is processed, so that a slow group does not hold up the others.
+    ///
+    /// Requests of one group are processed in the order they arrive, so that
+    /// `AppendEntries` requests sent back to back are not reordered.
+    async fn serve_connection(&self, stream: TcpStream) -> io::Result<()> {
+        let (mut reader, writer) = stream.into_split();
+        let (replies, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_writer(writer, receiver));
+        let mut group_queues: HashMap<GroupId, mpsc::UnboundedSender<Frame>> = HashMap::new();
+        loop {
+            let frame = read_frame(&mut reader).await?;
+            let Frame::Request { group, .. } = &frame else {
+                let this = self.clone();
+                let replies = replies.clone();
+                tokio::spawn(async move {
+                    let _ = replies.send(this.process(frame).await);
+                });
+                continue;
+            };
+            let queue = group_queues.entry(*group).or_insert_with(|| {
+                let (queue, mut requests) = mpsc::unbounded_channel();
+                let this = self.clone();
+                let replies = replies.clone();
+                tokio::spawn(async move {
+                    while let Some(frame) = requests.recv().await {
+                        if replies.is_closed() {
+                            break;
+                        }
+                        // Entries are handed to storage in order, the replies
+                        // wait for them to be written concurrently.
+                        let reply = this.process(frame);
+                        let replies = replies.clone();
+                        tokio::spawn(async move {
+                            let _ = replies.send(reply.await);
+                        });
+                    }
+                });
+                queue
+            });
+            let _ = queue.send(frame);
+        }
+    }
+
+    /// Processes `frame`, and returns its reply once it is ready, see
+    /// `Raft::process_append_entries()`.
+    fn process(&self, frame: Frame) -> BoxFuture<'static, Frame> {
+        match frame {
+            Frame::Request { id, group, request } => match self.process_request(group, request) {
+                Ok(reply) => reply
+                    .map(move |reply| Frame::Reply {
+                        id,
+                        reply: Ok(reply),
+                    })
+                    .boxed(),
+                Err(e) => future::ready(Frame::Reply { id, reply: Err(e) }).boxed(),
+            },
+            Frame::Heartbeats { id, heartbeats } => {
+                let pending: Vec<_> = heartbeats
+                    .into_iter()
+                    .map(|(group, args)| {
+                        self.raft(group)
+                            .map(|raft| raft.process_append_entries(args))
+                    })
+                    .collect();
+                async move {
+                    let mut replies = Vec::with_capacity(pending.len());
+                    for reply in pending {
+                        replies.push(match reply {
+                            Ok(reply) => Ok(reply.wait().await),
+                            Err(e) => Err(e),
+                        });
+                    }
+                    Frame::HeartbeatReplies { id, replies }
+                }
+                .boxed()
+            }
+            Frame::Reply { id, .. } | Frame::HeartbeatReplies { id, .. } => {
+                future::ready(Frame::Reply {
+                    id,
+                    reply: Err("unexpected reply".to_owned()),
+                })
+                .boxed()
+            }
+        }
+    }
+
+    fn process_request(
+        &self,
+        group: GroupId,
+        request: RaftRequest,
+    ) -> Result<BoxFuture<'static, RaftReply>, String> {
+        let raft = self.raft(group)?;
+        Ok(match request {
+            RaftRequest::RequestVote(args) => raft
+                .process_request_vote(args)
+                .map(RaftReply::RequestVote)
+                .boxed(),
+            RaftRequest::AppendEntries(args) => raft
+                .process_append_entries(args)
+                .wait()
+                .map(RaftReply::AppendEntries)
+                .boxed(),
+            RaftRequest::TimeoutNow(args) => {
+                future::ready(RaftReply::TimeoutNow(raft.process_timeout_now(args))).boxed()
+            }
+        })
+    }
+
+    fn raft(&self, group: GroupId) -> Result<Raft<Vec<u8>>, String> {
+        self.group(group)
+            .ok_or_else(|| format!("group {} is not on this host", group.0))
+    }
+}
+
+/// A connection to a host, shared by the RPCs of all groups.
+struct Connection {
+    frames: mpsc::UnboundedSender<Frame>,
+    // Requests waiting for their reply, by id
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<Frame>>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Connection {
+    async fn open(socket_addr: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect(socket_addr).await?;
+        stream.set_nodelay(true)?;
+        let (mut reader, writer) = stream.into_split();
+        let (frames, receiver) = mpsc::unbounded_channel();
+        let pending: Arc<Mutex<HashMap<u64, oneshot::Sender<Frame>>>> = Arc::default();
+        let closed = Arc::new(AtomicBool::new(false));
+
+        tokio::spawn(run_writer(writer, receiver));
+        let reader_pending = pending.clone();
+        let reader_closed = closed.clone();
+        tokio::spawn(async move {
+            loop {
+                match read_frame::<Frame>(&mut reader).await {
+                    Ok(frame) => {
+                        let sender = reader_pending.lock().unwrap().remove(&frame.id());
+                        if let Some(sender) = sender {
+                            let _ = sender.send(frame);
+                        }
+                    }
+                    Err(e) => {
+                        log::debug!("Raft connection to {} closed: {}", socket_addr, e);
+                        break;
+                    }
+                }
+            }
+            // Dropping the senders fails the requests still waiting.
+            reader_closed.store(true, Ordering::Release);
+            reader_pending.lock().unwrap().clear();
+        });
+
+        Ok(Connection {
+            frames,
+            pending,
+            closed,
+        })
+    }
+
+    fn is_open(&self) -> bool {
+        !self.closed.load(Ordering::Acquire) && !self.frames.is_closed()
+    }
+
+    /// Sends `frame` and waits for the frame that answers it.
+    async fn call(&self, frame: Frame) -> io::Result<Frame> {
+        let id = frame.id();
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+        // Forgets the request if the caller gives up on it.
+        let _guard = PendingGuard {
+            id,
+            pending: &self.pending,
+        };
+        if self.frames.send(frame).is_err() {
+            return Err(io::ErrorKind::ConnectionAborted.into());
+        }
+        receiver
+            .await
+            .map_err(|_| io::ErrorKind::ConnectionAborted.into())
+    }
+}
+
+struct PendingGuard<'a> {
+    id: u64,
+    pending: &'a Mutex<HashMap<u64, oneshot::Sender<Frame>>>,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Heartbeats waiting for the next tick of the host.
+#[derive(Default)]
+struct HeartbeatBatch {
+    heartbeats: Vec<(GroupId, AppendEntriesArgs<Vec<u8>>)>,
+    senders: Vec<oneshot::Sender<io::Result<AppendEntriesReply>>>,
+}
+
+/// A client of the Raft groups on one host, served by `RaftService`. All
+/// groups share one connection, opened when an RPC needs it. A connection
+/// that fails is dropped, the next RPC opens a new one.
+pub struct HostClient {
     pub socket_addr: SocketAddr,
+    connection: tokio::sync::Mutex<Option<Arc<Connection>>>,
+    next_id: AtomicU64,
+    heartbeats: Mutex<HeartbeatBatch>,
+    // Set once the ticker that sends the heartbeats is running.
+    ticker: Once,
+}
+
+impl HostClient {
+    pub fn new(socket_addr: SocketAddr) -> Self {
+        HostClient {
+            socket_addr,
+            connection: tokio::sync::Mutex::new(None),
+            next_id: AtomicU64::new(0),
+            heartbeats: Mutex::new(HeartbeatBatch::default()),
+            ticker: Once::new(),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    async fn connection(&self) -> io::Result<Arc<Connection>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref().filter(|c| c.is_open()) {
+            return Ok(connection.clone());
+        }
+        let opened = Arc::new(Connection::open(self.socket_addr).await?);
+        *connection = Some(opened.clone());
+        Ok(opened)
+    }
+
+    /// Sends `request` to `group` and waits for the reply.
+    async fn call(&self, group: GroupId, request: RaftRequest) -> io::Result<RaftReply> {
+        let id = self.next_id();
+        let frame = Frame::Request { id, group, request };
+        match self.connection().await?.call(frame).await? {
+            Frame::Reply { reply, .. } => reply.map_err(io::Error::other),
+            frame => Err(unexpected_reply(frame)),
+        }
+    }
+
+    /// Sends a heartbeat to `group`. The heartbeat waits for the next tick of
+    /// this host, so that it goes out in the same frame as the heartbeats of
+    /// other groups.
+    async fn heartbeat(
+        self: &Arc<Self>,
+        group: GroupId,
+        args: AppendEntriesArgs<Vec<u8>>,
+    ) -> io::Result<AppendEntriesReply> {
+        self.ticker.call_once(|| {
+            tokio::spawn(Self::tick(Arc::downgrade(self)));
+        });
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut batch = self.heartbeats.lock().unwrap();
+            batch.heartbeats.push((group, args));
+            batch.senders.push(sender);
+        }
+        receiver
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))?
+    }
+
+    /// Sends the heartbeats collected for this host, once every
+    /// `HEARTBEAT_TICK`, until the client is dropped.
+    async fn tick(this: Weak<Self>) {
+        let mut interval = tokio::time::interval(HEARTBEAT_TICK);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(this) = this.upgrade() else {
+                return;
+            };
+            let batch = std::mem::take(&mut *this.heartbeats.lock().unwrap());
+            if !batch.senders.is_empty() {
+                tokio::spawn(async move { this.send_heartbeats(batch).await });
+            }
+        }
+    }
+
+    async fn send_heartbeats(&self, batch: HeartbeatBatch) {
+        let id = self.next_id();
+        let frame = Frame::Heartbeats {
+            id,
+            heartbeats: batch.heartbeats,
+        };
+        let replies = match self.connection().await {
+            Ok(connection) => connection.call(frame).await,
+            Err(e) => Err(e),
+        };
+        match replies {
+            Ok(Frame::HeartbeatReplies { replies, .. }) if replies.len() == batch.senders.len() => {
+                for (sender, reply) in batch.senders.into_iter().zip(replies) {
+                    let _ = sender.send(reply.map_err(io::Error::other));
+                }
+            }
+            Ok(frame) => {
+                let message = format!("unexpected reply {:?}", frame);
+                for sender in batch.senders {
+                    let _ = sender.send(Err(io::Error::new(io::ErrorKind::InvalidData, &*message)));
+                }
+            }
+            Err(e) => {
+                for sender in batch.senders {
+                    let _ = sender.send(Err(io::Error::new(e.kind(), e.to_string())));
+                }
+            }
+        }
+    }
+}
+
+fn unexpected_reply(reply: impl std::fmt::Debug) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected reply {:?}", reply),
+    )
+}
+
+/// The clients of all hosts this process talks to, so that each host gets one
+/// connection, whatever the number of groups.
+#[derive(Default)]
+pub struct HostClients {
+    hosts: Mutex<HashMap<SocketAddr, Arc<HostClient>>>,
+}
+
+impl HostClients {
+    pub fn host(&self, socket_addr: SocketAddr) -> Arc<HostClient> {
+        self.hosts
+            .lock()
+            .unwrap()
+            .entry(socket_addr)
+            .or_insert_with(|| Arc::new(HostClient::new(socket_addr)))
+            .clone()
+    }
+
+    /// Returns a client of `group` on the host at `socket_addr`.
+    pub fn client(&self, group: GroupId, socket_addr: SocketAddr) -> LazyRaftServiceClient {
+        LazyRaftServiceClient {
+            host: self.host(socket_addr),
+            group,
+        }
+    }
+}
+
+/// A client of one Raft group on a host.
+#[derive(Clone)]
+pub struct LazyRaftServiceClient {
+    host: Arc<HostClient>,
+    group: GroupId,
+}
+
+#[async_trait]
+impl RemoteRaft<Vec<u8>> for LazyRaftServiceClient {
+    async fn request_vote(&self, args: RequestVoteArgs) -> io::Result<RequestVoteReply> {
+        match self
+            .host
+            .call(self.group, RaftRequest::RequestVote(args))
+            .await?
+        {
+            RaftReply::RequestVote(reply) => Ok(reply),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    async fn append_entries(
+        &self,
+        args: AppendEntriesArgs<Vec<u8>>,
+    ) -> io::Result<AppendEntriesReply> {
+        match self
+            .host
+            .call(self.group, RaftRequest::AppendEntries(args))
+            .await?
+        {
+            RaftReply::AppendEntries(reply) => Ok(reply),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    async fn heartbeat(
+        &self,
+        args: AppendEntriesArgs<Vec<u8>>,
+        urgent: bool,
+    ) -> io::Result<AppendEntriesReply> {
+        if urgent {
+            self.append_entries(args).await
+        } else {
+            self.host.heartbeat(self.group, args).await
+        }
+    }
+
+    async fn timeout_now(&self, args: TimeoutNowArgs) -> io::Result<TimeoutNowReply> {
+        match self
+            .host
+            .call(self.group, RaftRequest::TimeoutNow(args))
+            .await?
+        {
+            RaftReply::TimeoutNow(reply) => Ok(reply),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+}
+
+/// Connects to the servers that join `group`, through shared `HostClients`.
+pub struct LazyRaftServiceConnector {
+    group: GroupId,
+    hosts: Arc<HostClients>,
+}
+
+impl LazyRaftServiceConnector {
+    pub fn new(group: GroupId, hosts: Arc<HostClients>) -> Self {
+        LazyRaftServiceConnector { group, hosts }
+    }
 }
 
-impl RemoteRaft<String> for LazyRaftServiceClient {
-    async fn request_vote(&self) -> std::io::Result<()> {
-        todo!()
+impl RemoteRaftConnector<Vec<u8>> for LazyRaftServiceConnector {
+    fn connect(&self, address: &str) -> io::Result<Arc<dyn RemoteRaft<Vec<u8>>>> {
+        let socket_addr = address
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Arc::new(self.hosts.client(self.group, socket_addr)))
     }
 }
@@ -1,9 +1,22 @@
 use std::{
-    sync::{Condvar, Mutex},
+    collections::HashSet,
+    future::Future,
+    pin::pin,
+    sync::{atomic::Ordering, Mutex},
     time::{Duration, Instant},
 };
 
+use futures_util::{stream::FuturesUnordered, StreamExt};
 use rand::{thread_rng, Rng};
+use tokio::sync::Notify;
+
+use crate::{
+    membership::Membership,
+    messages::{RequestVoteArgs, RequestVoteReply},
+    raft::{Raft, ReplicableCommand},
+    raft_config::RaftConfig,
+    raft_state::{RaftState, State},
+};
 
 #[derive(Debug)]
 struct VersionedDeadline {
@@ -16,36 +29,320 @@ pub(crate) struct ElectionState {
     // Timer will be removed upon shutdown or elected
     timer: Mutex<VersionedDeadline>,
 
-    // Wake up the timer thread when the timer is reset or cancelled
-    signal: Condvar,
-}
+    // Wake up the timer task when the timer is reset or cancelled
+    signal: Notify,
 
-const ELECTION_TIMEOUT_BASE_MILLIS: u64 = 200;
-const ELECTION_TIMEOUT_VAR_MILLIS: u64 = 200;
+    min_timeout: Duration,
+    max_timeout: Duration,
+}
 
 impl ElectionState {
-    pub(crate) fn create() -> Self {
+    pub(crate) fn create(config: &RaftConfig) -> Self {
         Self {
             timer: Mutex::new(VersionedDeadline {
                 version: 0,
                 deadline: None,
             }),
-            signal: Condvar::new(),
+            signal: Notify::new(),
+            min_timeout: config.min_election_timeout(),
+            max_timeout: config.max_election_timeout(),
         }
     }
 
     pub(crate) fn reset_election_timer(&self) {
         let mut guard = self.timer.lock().unwrap();
         guard.version += 1;
-        guard.deadline.replace(Self::election_timeout());
+        guard.deadline.replace(self.election_timeout());
+        self.signal.notify_one();
+    }
+
+    /// Resets the timer only if it has not been changed since `version`.
+    fn try_reset_election_timer(&self, version: usize) -> bool {
+        let mut guard = self.timer.lock().unwrap();
+        if guard.version != version {
+            return false;
+        }
+        guard.version += 1;
+        guard.deadline.replace(self.election_timeout());
+        true
+    }
+
+    /// Removes the timer. No election will be started until the timer is
+    /// reset again.
+    pub(crate) fn stop_election_timer(&self) {
+        let mut guard = self.timer.lock().unwrap();
+        guard.version += 1;
+        guard.deadline.take();
         self.signal.notify_one();
     }
 
-    fn election_timeout() -> Instant {
-        Instant::now()
-            + Duration::from_millis(
-                ELECTION_TIMEOUT_BASE_MILLIS
-                    + thread_rng().gen_range(0..ELECTION_TIMEOUT_VAR_MILLIS),
-            )
+    /// The longest time a follower waits before starting an election.
+    pub(crate) fn max_election_timeout(&self) -> Duration {
+        self.max_timeout
+    }
+
+    /// The shortest time a follower waits before starting an election.
+    fn min_election_timeout(&self) -> Duration {
+        self.min_timeout
+    }
+
+    fn election_timeout(&self) -> Instant {
+        Instant::now() + thread_rng().gen_range(self.min_timeout..self.max_timeout)
+    }
+}
+
+impl<Command: ReplicableCommand> Raft<Command> {
+    /// Starts a task that waits for the election timer to fire. An election
+    /// is started each time the timer fires.
+    ///
+    /// The timer is reset by heartbeats from the leader and by votes granted
+    /// to candidates. It is removed when this instance becomes the leader.
+    pub(crate) fn run_election_timer(&self) {
+        let this = self.clone();
+        self.daemons.spawn(&self.thread_pool, async move {
+            let election = this.election.clone();
+            loop {
+                let (version, deadline) = {
+                    let guard = election.timer.lock().unwrap();
+                    (guard.version, guard.deadline)
+                };
+                match deadline {
+                    Some(deadline) if deadline > Instant::now() => {
+                        let sleep = pin!(tokio::time::sleep_until(deadline.into()));
+                        let signal = pin!(election.signal.notified());
+                        futures_util::future::select(sleep, signal).await;
+                    }
+                    Some(_) => this.run_election(version),
+                    None => election.signal.notified().await,
+                }
+            }
+        })
+    }
+
+    /// Runs an election when the timer fires. The election is not run if the
+    /// timer was changed after it fired.
+    fn run_election(&self, version: usize) {
+        let mut rf = self.inner_state.lock().unwrap();
+        // Someone else reset the timer before us, e.g. we heard from a new
+        // leader.
+        if !self.election.try_reset_election_timer(version) || rf.is_leader() {
+            return;
+        }
+        self.start_election(&mut rf, false);
+    }
+
+    /// Becomes a candidate of the next term and asks all peers for votes.
+    /// `leadership_transfer` is set if the current leader asked us to.
+    pub(crate) fn start_election(&self, rf: &mut RaftState<Command>, leadership_transfer: bool) {
+        // Servers outside of the cluster must not disrupt it.
+        if !rf.membership.is_voter(self.peer) {
+            return;
+        }
+
+        rf.current_term.0 += 1;
+        rf.voted_for = Some(self.peer);
+        rf.state = State::Candidate;
+        rf.leader_id = None;
+        rf.leader_contact = None;
+        self.persist_term_vote(rf);
+        // Votes are asked for once our own vote is written.
+        let term_vote = self.wait_term_vote(rf);
+
+        let last_log = rf.log.last_index_term();
+        let args = RequestVoteArgs {
+            term: rf.current_term,
+            candidate_id: self.peer,
+            last_log_index: last_log.index,
+            last_log_term: last_log.term,
+            leadership_transfer,
+        };
+        let membership = rf.membership.clone();
+
+        let this = self.clone();
+        self.daemons.spawn(&self.thread_pool, async move {
+            if let Some(term_vote) = term_vote {
+                term_vote.await;
+            }
+            this.count_votes(args, membership).await
+        });
+    }
+
+    async fn count_votes(self, args: RequestVoteArgs, membership: Membership) {
+        let term = args.term;
+        let mut votes: FuturesUnordered<_> = self
+            .remote_peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| membership.is_voter(peer.unique_id))
+            .map(|peer| {
+                let peer = peer.clone();
+                let args = args.clone();
+                async move { (peer.unique_id, peer.request_vote(args).await) }
+            })
+            .collect();
+
+        // We always vote for ourselves.
+        let mut granted = HashSet::from([self.peer]);
+        while !membership.has_quorum(&granted) {
+            let Some((peer, reply)) = votes.next().await else {
+                break;
+            };
+            let Ok(reply) = reply else {
+                continue;
+            };
+            if reply.term > term {
+                let mut rf = self.inner_state.lock().unwrap();
+                if reply.term > rf.current_term {
+                    self.step_down(&mut rf, reply.term);
+                }
+                return;
+            }
+            if reply.vote_granted {
+                granted.insert(peer);
+            }
+        }
+        drop(votes);
+
+        if !membership.has_quorum(&granted) {
+            return;
+        }
+        let mut rf = self.inner_state.lock().unwrap();
+        if rf.current_term == term && rf.state == State::Candidate {
+            self.become_leader(&mut rf);
+        }
+    }
+
+    fn become_leader(&self, rf: &mut RaftState<Command>) {
+        log::info!(
+            "{:?} became the leader of term {:?}",
+            self.peer,
+            rf.current_term
+        );
+        // We are the leader now. The election timer can be stopped.
+        self.election.stop_election_timer();
+        rf.state = State::Leader;
+        rf.leader_id = Some(self.peer);
+
+        let log_end = rf.log.end();
+        for member in rf.cluster.values_mut() {
+            member.next_index = log_end;
+            member.match_index = 0;
+            member.probe();
+            member.inflight_appends = 0;
+            member.inflight_bytes = 0;
+        }
+
+        // An entry of the current term is needed to commit entries left by
+        // previous leaders.
+        rf.log.add_noop(rf.current_term);
+        self.persist_log_signal.notify_one();
+
+        self.heartbeats_daemon.trigger(true);
+        self.sync_log_entries_daemon.trigger(None);
+    }
+
+    /// Decides whether to vote for the candidate of `args`. The reply is
+    /// ready once our term and vote are written.
+    pub fn process_request_vote(
+        &self,
+        args: RequestVoteArgs,
+    ) -> impl Future<Output = RequestVoteReply> + Send + 'static {
+        let mut rf = self.inner_state.lock().unwrap();
+        let reply = self.handle_request_vote(&mut rf, args);
+        let term_vote = self.wait_term_vote(&rf);
+        async move {
+            if let Some(term_vote) = term_vote {
+                term_vote.await;
+            }
+            reply
+        }
+    }
+
+    fn handle_request_vote(
+        &self,
+        rf: &mut RaftState<Command>,
+        args: RequestVoteArgs,
+    ) -> RequestVoteReply {
+        // A killed instance takes no part in the cluster anymore.
+        if args.term < rf.current_term || !self.keep_running.load(Ordering::Relaxed) {
+            return RequestVoteReply {
+                term: rf.current_term,
+                vote_granted: false,
+            };
+        }
+        // While we hear from the leader, candidates are ignored. This keeps
+        // servers that were removed from the cluster, and do not know it yet,
+        // from disrupting it.
+        let leader_alive = rf.is_leader()
+            || rf
+                .leader_contact
+                .is_some_and(|contact| contact.elapsed() < self.election.min_election_timeout());
+        if leader_alive && !args.leadership_transfer {
+            return RequestVoteReply {
+                term: rf.current_term,
+                vote_granted: false,
+            };
+        }
+        if args.term > rf.current_term {
+            self.step_down(rf, args.term);
+        }
+
+        let last_log = rf.log.last_index_term();
+        let up_to_date = args.last_log_term > last_log.term
+            || (args.last_log_term == last_log.term && args.last_log_index >= last_log.index);
+        let can_vote = rf.voted_for.is_none() || rf.voted_for == Some(args.candidate_id);
+
+        let vote_granted = can_vote && up_to_date;
+        if vote_granted {
+            rf.voted_for = Some(args.candidate_id);
+            self.persist_term_vote(rf);
+            self.election.reset_election_timer();
+        }
+
+        RequestVoteReply {
+            term: rf.current_term,
+            vote_granted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        raft_state::{Peer, Term},
+        test_utils::{Command, HeldStorage, LocalNetwork},
+    };
+
+    #[test]
+    fn votes_are_sent_once_written() {
+        let network = Arc::new(LocalNetwork::default());
+        let storage = HeldStorage::default();
+        let raft = Raft::new(
+            network.clients(Peer(0), 3),
+            0,
+            storage.clone(),
+            network.connector(Peer(0)),
+            RaftConfig::default(),
+            |_, _: Command| {},
+        );
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let reply = runtime.spawn(raft.process_request_vote(RequestVoteArgs {
+            term: Term(1000),
+            candidate_id: Peer(1),
+            last_log_index: 0,
+            last_log_term: Term(0),
+            leadership_transfer: false,
+        }));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!reply.is_finished());
+
+        storage.release();
+        assert!(runtime.block_on(reply).unwrap().vote_granted);
+
+        raft.kill().join();
     }
 }
@@ -1,4 +1,11 @@
-use crate::raft::Raft;
+use crate::{
+    messages::AppendEntriesArgs,
+    raft::{Raft, ReplicableCommand},
+    raft_config::RaftConfig,
+    raft_state::Peer,
+    remote::remote_peer::RemotePeer,
+};
+use futures_util::future::Either;
 use std::{
     pin::pin,
     sync::{
@@ -8,55 +15,117 @@ use std::{
     time::{Duration, Instant},
 };
 
-pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(1000);
-
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct HeartbeatsDaemon {
     start: Instant,
     last_trigger: Arc<AtomicU64>,
     sender: tokio::sync::broadcast::Sender<()>,
+    interval: Duration,
+    max_delay_millis: u64,
 }
 
 impl HeartbeatsDaemon {
-    const HEARTBEAT_MAX_DELAY_MILLIS: u64 = 30;
-
-    pub fn create() -> Self {
+    pub fn create(config: &RaftConfig) -> Self {
         let (sender, _) = tokio::sync::broadcast::channel(1);
         Self {
             start: Instant::now(),
             last_trigger: Arc::new(AtomicU64::new(0)),
             sender,
+            interval: config.heartbeat_interval(),
+            max_delay_millis: config.heartbeat_max_delay_millis,
+        }
+    }
+
+    /// Sends heartbeats to all peers right away, instead of waiting for the
+    /// next tick. Heartbeats are not triggered more than once every
+    /// `heartbeat_max_delay_millis` of the config, unless `force` is set.
+    pub fn trigger(&self, force: bool) {
+        let now = self.start.elapsed().as_millis() as u64;
+        let last_trigger = self.last_trigger.load(Ordering::Acquire);
+        let next_trigger = last_trigger.wrapping_add(self.max_delay_millis);
+
+        if force || next_trigger < now {
+            let previous_trigger = self.last_trigger.fetch_max(now, Ordering::AcqRel);
+            if previous_trigger == last_trigger {
+                let _ = self.sender.send(());
+            }
         }
     }
 }
 
-impl<Command> Raft<Command> {
-    /// Schedules tasks that send heartbeats to peers.
+impl<Command: ReplicableCommand> Raft<Command> {
+    /// Schedules a task that sends heartbeats to `peer`.
     ///
-    /// One task is scheduled for each peer. The task sleeps for a duration
-    /// specified by `interval`, wakes up, builds the request message to send
-    /// and delegates the actual RPC-sending to another task before going back
-    /// to sleep.
+    /// One task is scheduled for each peer. The task sleeps for
+    /// `heartbeat_interval_millis` of the config, wakes up, builds the request
+    /// message to send and delegates the actual RPC-sending to another task
+    /// before going back to sleep. The task exits when the peer leaves the
+    /// cluster.
     ///
     /// The sleeping task does nothing if we are not the leader.
     ///
-    /// The request message is a stripped down version of `AppendEntries`. The
-    /// response from the peer is ignored.
-    pub(crate) fn schedule_heartbeats(&self, interval: Duration) {
-        let rf = self.inner_state.clone();
+    /// The request message is a stripped down version of `AppendEntries`. If
+    /// the peer rejects it, its log is behind ours and log entries are synced
+    /// to it. Heartbeats that are triggered, or that carry a new commit index,
+    /// are sent as urgent, the others can wait to be batched.
+    pub(crate) fn schedule_heartbeats(&self, peer: RemotePeer<Command, Peer>) {
+        let this = self.clone();
         let mut trigger = self.heartbeats_daemon.sender.subscribe();
-        let peers = self.peers.clone();
-        let keep_running = self.keep_running.clone();
 
-        self.thread_pool.spawn(async move {
-            let mut interval = tokio::time::interval(interval);
-            while keep_running.load(Ordering::Relaxed) {
+        self.daemons.spawn(&self.thread_pool, async move {
+            let mut interval = tokio::time::interval(this.heartbeats_daemon.interval);
+            let mut sent_commit = 0;
+            while this.keep_running.load(Ordering::Relaxed) && !peer.is_stopped() {
                 let tick = pin!(interval.tick());
                 let trigger = pin!(trigger.recv());
 
-                let _ = futures_util::future::select(tick, trigger).await;
-                println!("send heartbeat");
+                let triggered = matches!(
+                    futures_util::future::select(tick, trigger).await,
+                    Either::Right(_)
+                );
+                if let Some(args) = this.build_heartbeat() {
+                    let urgent = triggered || args.leader_commit > sent_commit;
+                    sent_commit = args.leader_commit;
+                    let send = this.clone().send_heartbeat(peer.clone(), args, urgent);
+                    this.daemons.spawn(&this.thread_pool, send);
+                }
             }
         });
     }
+
+    fn build_heartbeat(&self) -> Option<AppendEntriesArgs<Command>> {
+        let rf = self.inner_state.lock().unwrap();
+        if !rf.is_leader() {
+            return None;
+        }
+
+        let last_log = rf.log.last_index_term();
+        Some(AppendEntriesArgs {
+            term: rf.current_term,
+            leader_id: self.peer,
+            prev_log_index: last_log.index,
+            prev_log_term: last_log.term,
+            entries: vec![],
+            leader_commit: rf.commit_index,
+        })
+    }
+
+    async fn send_heartbeat(
+        self,
+        peer: RemotePeer<Command, Peer>,
+        args: AppendEntriesArgs<Command>,
+        urgent: bool,
+    ) {
+        let term = args.term;
+        let Ok(reply) = peer.heartbeat(args, urgent).await else {
+            return;
+        };
+
+        let mut rf = self.inner_state.lock().unwrap();
+        if reply.term > rf.current_term {
+            self.step_down(&mut rf, reply.term);
+        } else if !reply.success && rf.current_term == term && rf.is_leader() {
+            self.sync_log_entries_daemon.trigger(Some(peer.unique_id));
+        }
+    }
 }
@@ -0,0 +1,620 @@
+use std::{collections::HashMap, io};
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    kv::{
+        server::{KVServer, ProposeError},
+        sharded::{ShardError, ShardedKV},
+    },
+    raft_state::{GroupId, Peer},
+    state_machine::{ApplyResult, Command, CommandKind},
+};
+
+// Requests with a larger body are refused.
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+// Longest request line or header line accepted
+const MAX_LINE_LEN: usize = 64 * 1024;
+// Requests with more header lines are refused.
+const MAX_HEADERS: usize = 100;
+
+/// An HTTP/1.1 front end of a key-value server.
+///
+/// - `GET /kv/{key}`, `PUT /kv/{key}` and `DELETE /kv/{key}` read, write and
+///   delete a key. Keys are percent-encoded in the path, values are the raw
+///   request and response bodies. All three go through the log.
+/// - `GET /status` describes this server.
+/// - `GET /members` lists the servers in the cluster.
+/// - `GET /shards` lists the shards of a sharded store.
+///
+/// A server that is not the leader redirects key requests to the leader if it
+/// knows the HTTP address of the leader, and otherwise answers 503 with the
+/// leader in the `X-Raft-Leader` header. In a sharded store, that is the
+/// leader of the shard of the key, and the status and members are those of
+/// `GroupId(0)`.
+#[derive(Clone)]
+pub struct HttpServer {
+    kv: KVServer,
+    // Routes key requests to their shard, if the store is sharded
+    sharded: Option<ShardedKV>,
+    // HTTP addresses of the servers in the cluster
+    peers: HashMap<Peer, String>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    fn json(status: u16, value: serde_json::Value) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type", "application/json".to_owned())],
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Self::json(status, json!({ "error": message.to_string() }))
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+impl HttpServer {
+    /// Creates a front end of `kv`. `peers` are the HTTP addresses of the
+    /// servers in the cluster, used to redirect clients to the leader.
+    pub fn new(kv: KVServer, peers: HashMap<Peer, String>) -> Self {
+        HttpServer {
+            kv,
+            sharded: None,
+            peers,
+        }
+    }
+
+    /// Creates a front end of the sharded store `kv`, see `new()`.
+    pub fn sharded(kv: ShardedKV, peers: HashMap<Peer, String>) -> Self {
+        HttpServer {
+            // Created with the store, and never merged into another shard.
+            kv: kv
+                .server(GroupId(0))
+                .expect("GroupId(0) should be on every host"),
+            sharded: Some(kv),
+            peers,
+        }
+    }
+
+    /// Serves the connections accepted by `listener`, until accepting fails.
+    pub async fn run(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let this = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = this.serve_connection(stream).await {
+                    log::debug!("HTTP connection closed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn serve_connection(&self, stream: TcpStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        loop {
+            let request = match read_request(&mut reader).await? {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            let response = self.handle(&request).await;
+            write_response(&mut writer, response, request.keep_alive).await?;
+            if !request.keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn handle(&self, request: &Request) -> Response {
+        let path = request.path.split('?').next().unwrap_or_default();
+        if let Some(key) = path.strip_prefix("/kv/") {
+            let Some(key) = percent_decode(key) else {
+                return Response::error(400, "malformed key");
+            };
+            return self.handle_kv(request, key).await;
+        }
+        match (request.method.as_str(), path) {
+            ("GET", "/status") => self.status(),
+            ("GET", "/members") => self.members(),
+            ("GET", "/shards") => self.shards(),
+            (_, "/status" | "/members" | "/shards") => Response::new(405).header("Allow", "GET"),
+            _ => Response::error(404, "not found"),
+        }
+    }
+
+    async fn handle_kv(&self, request: &Request, key: Vec<u8>) -> Response {
+        let command = match request.method.as_str() {
+            "GET" => Command::new(CommandKind::GetCommand, key, None),
+            "PUT" => Command::new(CommandKind::SetCommand, key, Some(request.body.clone())),
+            "DELETE" => Command::new(CommandKind::DeleteCommand, key, None),
+            _ => return Response::new(405).header("Allow", "GET, PUT, DELETE"),
+        };
+        let is_get = request.method == "GET";
+
+        let result = match &self.sharded {
+            // Sent again by the store while its routing cache is stale.
+            Some(sharded) => sharded.propose(command).await,
+            None => self.kv.propose(command).await.map_err(ShardError::Propose),
+        };
+        match result {
+            Ok(ApplyResult::Found(kv)) if is_get => Response {
+                status: 200,
+                headers: vec![("Content-Type", "application/octet-stream".to_owned())],
+                body: kv.value,
+            }
+            .header("X-Create-Revision", kv.create_revision)
+            .header("X-Mod-Revision", kv.mod_revision)
+            .header("X-Version", kv.version),
+            Ok(ApplyResult::Found(_)) | Ok(ApplyResult::Stored) => Response::new(204),
+            Ok(ApplyResult::NotFound) => Response::error(404, "key not found"),
+            Ok(result) => Response::error(500, format!("unexpected result {:?}", result)),
+            Err(ShardError::Propose(ProposeError::NotLeader(leader))) => {
+                self.not_leader(request, leader)
+            }
+            Err(e @ ShardError::Propose(ProposeError::Lost)) => Response::error(503, e),
+            Err(e @ ShardError::Propose(ProposeError::Timeout)) => Response::error(504, e),
+            Err(e @ ShardError::NoRoute(_)) => Response::error(503, e),
+            Err(e) => Response::error(500, e),
+        }
+    }
+
+    fn not_leader(&self, request: &Request, leader: Option<Peer>) -> Response {
+        let Some(leader) = leader else {
+            return Response::error(503, ProposeError::NotLeader(None));
+        };
+        match self.peers.get(&leader) {
+            Some(address) => Response::new(307)
+                .header("Location", format!("http://{}{}", address, request.path))
+                .header("X-Raft-Leader", leader.0),
+            None => Response::json(
+                503,
+                json!({ "error": "not the leader", "leader": leader.0 }),
+            )
+            .header("X-Raft-Leader", leader.0),
+        }
+    }
+
+    fn status(&self) -> Response {
+        let status = self.kv.raft.status();
+        let revision = self.kv.state_machine.lock().unwrap().revision;
+        Response::json(
+            200,
+            json!({
+                "id": status.peer.0,
+                "term": status.term.0,
+                "role": status.role,
+                "leader": status.leader.map(|leader| leader.0),
+                "last_log_index": status.last_log_index,
+                "commit_index": status.commit_index,
+                "last_applied": status.last_applied,
+                "revision": revision,
+            }),
+        )
+    }
+
+    fn shards(&self) -> Response {
+        let Some(sharded) = &self.sharded else {
+            return Response::error(404, "the store is not sharded");
+        };
+        let shards: Vec<_> = sharded
+            .shards()
+            .into_iter()
+            .map(|shard| {
+                json!({
+                    "group": shard.group.0,
+                    "start": String::from_utf8_lossy(&shard.start),
+                    "end": shard.end.as_ref().map(|end| String::from_utf8_lossy(end)),
+                    "generation": shard.generation,
+                })
+            })
+            .collect();
+        Response::json(200, json!({ "shards": shards }))
+    }
+
+    fn members(&self) -> Response {
+        let membership = self.kv.raft.membership();
+        // Only known on the leader
+        let replication = self.kv.raft.replication().unwrap_or_default();
+        let members: Vec<_> = membership
+            .servers
+            .iter()
+            .map(|(peer, address)| {
+                let mut member = json!({
+                    "id": peer.0,
+                    "address": address,
+                    "http": self.peers.get(peer),
+                    "voter": membership.is_voter(*peer),
+                });
+                if let Some(progress) = replication.get(peer) {
+                    member["match_index"] = json!(progress.match_index);
+                    member["next_index"] = json!(progress.next_index);
+                    member["replication"] = json!(progress.replication);
+                    member["paused"] = json!(progress.is_paused(&self.kv.raft.config));
+                    member["inflight_appends"] = json!(progress.inflight_appends);
+                    member["inflight_bytes"] = json!(progress.inflight_bytes);
+                }
+                member
+            })
+            .collect();
+        Response::json(
+            200,
+            json!({
+                "members": members,
+                "joint": membership.is_joint(),
+                "leader": self.kv.raft.leader().map(|leader| leader.0),
+            }),
+        )
+    }
+}
+
+/// Reads the next request on the connection. Returns `None` if the client
+/// closed the connection between requests.
+async fn read_request(reader: &mut (impl AsyncBufReadExt + Unpin)) -> io::Result<Option<Request>> {
+    let Some(request_line) = read_line(reader).await? else {
+        return Ok(None);
+    };
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid_data("malformed request line"));
+    };
+    let mut request = Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        body: vec![],
+        keep_alive: version == "HTTP/1.1",
+    };
+
+    let mut content_length = 0;
+    for headers in 0.. {
+        let line = read_line(reader)
+            .await?
+            .ok_or_else(|| invalid_data("connection closed in headers"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers == MAX_HEADERS {
+            return Err(invalid_data("too many headers"));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid_data("malformed header"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Content-Length") {
+            content_length = value
+                .parse()
+                .map_err(|_| invalid_data("malformed Content-Length"))?;
+        } else if name.eq_ignore_ascii_case("Connection") {
+            if value.eq_ignore_ascii_case("close") {
+                request.keep_alive = false;
+            } else if value.eq_ignore_ascii_case("keep-alive") {
+                request.keep_alive = true;
+            }
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            return Err(invalid_data("Transfer-Encoding is not supported"));
+        }
+    }
+
+    if content_length > MAX_BODY_LEN {
+        return Err(invalid_data("request body too large"));
+    }
+    request.body.resize(content_length, 0);
+    reader.read_exact(&mut request.body).await?;
+    Ok(Some(request))
+}
+
+/// Reads a line without its line ending. Returns `None` at end of stream.
+async fn read_line(reader: &mut (impl AsyncBufReadExt + Unpin)) -> io::Result<Option<String>> {
+    let mut line = vec![];
+    let len = reader
+        .take(MAX_LINE_LEN as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid_data("line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid_data("line is not UTF-8"))
+}
+
+async fn write_response(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    response: Response,
+    keep_alive: bool,
+) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+        response.status,
+        reason(response.status),
+        response.body.len()
+    );
+    if !keep_alive {
+        head.push_str("Connection: close\r\n");
+    }
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&response.body).await?;
+    writer.flush().await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        307 => "Temporary Redirect",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// Decodes `%XX` escapes. Keys may hold any byte this way.
+fn percent_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Some(decoded)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{multi_raft::MultiRaftHost, raft_config::RaftConfig, test_utils::wait_until};
+
+    fn read(request: &str) -> io::Result<Option<Request>> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(read_request(&mut request.as_bytes()))
+    }
+
+    fn read_error(request: &str) -> io::ErrorKind {
+        match read(request) {
+            Ok(_) => panic!("{:?} should not be read", request),
+            Err(e) => e.kind(),
+        }
+    }
+
+    #[test]
+    fn percent_decode_accepts_escapes_and_raw_bytes() {
+        assert_eq!(percent_decode("a%20b"), Some(b"a b".to_vec()));
+        assert_eq!(percent_decode("%00%fF"), Some(vec![0, 0xff]));
+        // The euro sign, escaped and raw
+        assert_eq!(percent_decode("%E2%82%AC"), Some("€".as_bytes().to_vec()));
+        assert_eq!(percent_decode("€"), Some("€".as_bytes().to_vec()));
+        assert_eq!(percent_decode(""), Some(vec![]));
+    }
+
+    #[test]
+    fn percent_decode_rejects_malformed_escapes() {
+        for encoded in ["%", "a%", "%2", "%zz", "%g0", "%+1", "%-1", "% 1", "%€"] {
+            assert_eq!(percent_decode(encoded), None, "{:?}", encoded);
+        }
+    }
+
+    #[test]
+    fn requests_are_read() {
+        let request = read("PUT /kv/a HTTP/1.1\r\nContent-Length: 5\r\n\r\nvalue")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/kv/a");
+        assert_eq!(request.body, b"value");
+        assert!(request.keep_alive);
+
+        // No Content-Length means no body, bare line feeds are accepted.
+        let request = read("GET /kv/a HTTP/1.1\nConnection: close\n\n")
+            .unwrap()
+            .unwrap();
+        assert!(request.body.is_empty());
+        assert!(!request.keep_alive);
+
+        let request = read("GET /status HTTP/1.0\r\n\r\n").unwrap().unwrap();
+        assert!(!request.keep_alive);
+        let request = read("GET /status HTTP/1.0\r\nconnection: Keep-Alive\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert!(request.keep_alive);
+
+        assert!(read("").unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_requests_are_refused() {
+        use io::ErrorKind::{InvalidData, UnexpectedEof};
+
+        assert_eq!(read_error("GET /kv/a\r\n\r\n"), InvalidData);
+        assert_eq!(
+            read_error("GET /kv/a HTTP/1.1\r\nHost\r\n\r\n"),
+            InvalidData
+        );
+        assert_eq!(read_error("GET /kv/a HTTP/1.1\r\nHost: a\r\n"), InvalidData);
+        assert_eq!(
+            read_error("PUT /kv/a HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            InvalidData
+        );
+        assert_eq!(
+            read_error("PUT /kv/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            InvalidData
+        );
+        // The body is shorter than announced.
+        assert_eq!(
+            read_error("PUT /kv/a HTTP/1.1\r\nContent-Length: 6\r\n\r\nvalue"),
+            UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        use io::ErrorKind::InvalidData;
+
+        let request = format!(
+            "PUT /kv/a HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_LEN + 1
+        );
+        assert_eq!(read_error(&request), InvalidData);
+
+        let path = "a".repeat(MAX_LINE_LEN);
+        assert_eq!(
+            read_error(&format!("GET /{} HTTP/1.1\r\n\r\n", path)),
+            InvalidData
+        );
+        let value = "a".repeat(MAX_LINE_LEN);
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", value);
+        assert_eq!(read_error(&request), InvalidData);
+
+        let headers = "Host: a\r\n".repeat(MAX_HEADERS);
+        let request = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+        assert!(read(&request).unwrap().is_some());
+        let request = format!("GET / HTTP/1.1\r\n{}Host: a\r\n\r\n", headers);
+        assert_eq!(read_error(&request), InvalidData);
+    }
+
+    fn request(method: &str, path: &str, body: &[u8]) -> Request {
+        Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            body: body.to_vec(),
+            keep_alive: true,
+        }
+    }
+
+    #[test]
+    fn requests_are_routed() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let host = MultiRaftHost::new(runtime.handle().clone());
+        let peers = vec!["127.0.0.1:1".parse().unwrap()];
+        let kv = ShardedKV::new(host, peers, 0, RaftConfig::default()).unwrap();
+        assert!(wait_until(Duration::from_secs(5), || kv
+            .server(GroupId(0))
+            .is_some_and(|server| server.raft.get_state().1)));
+        let server = HttpServer::new(kv.server(GroupId(0)).unwrap(), HashMap::new());
+        let sharded = HttpServer::sharded(kv, HashMap::new());
+        let status = |server: &HttpServer, method, path, body: &[u8]| {
+            runtime
+                .block_on(server.handle(&request(method, path, body)))
+                .status
+        };
+
+        for server in [&server, &sharded] {
+            assert_eq!(status(server, "PUT", "/kv/a%20b", b"1"), 204);
+            let response = runtime.block_on(server.handle(&request("GET", "/kv/a%20b", b"")));
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"1");
+            assert!(response
+                .headers
+                .iter()
+                .any(|(name, _)| *name == "X-Mod-Revision"));
+            assert_eq!(status(server, "DELETE", "/kv/a%20b?x=1", b""), 204);
+            assert_eq!(status(server, "GET", "/kv/a%20b", b""), 404);
+            assert_eq!(status(server, "DELETE", "/kv/a%20b", b""), 404);
+            assert_eq!(status(server, "POST", "/kv/a", b""), 405);
+            assert_eq!(status(server, "GET", "/kv/%zz", b""), 400);
+
+            assert_eq!(status(server, "GET", "/status", b""), 200);
+            assert_eq!(status(server, "GET", "/members", b""), 200);
+            assert_eq!(status(server, "PUT", "/status", b""), 405);
+            assert_eq!(status(server, "GET", "/kv", b""), 404);
+            assert_eq!(status(server, "GET", "/", b""), 404);
+        }
+        assert_eq!(status(&server, "GET", "/shards", b""), 404);
+        assert_eq!(status(&sharded, "GET", "/shards", b""), 200);
+    }
+
+    #[test]
+    fn followers_redirect_to_the_leader() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let host = MultiRaftHost::new(runtime.handle().clone());
+        let peers = vec!["127.0.0.1:1".parse().unwrap()];
+        let kv = ShardedKV::new(host, peers, 0, RaftConfig::default()).unwrap();
+        let http_peers = HashMap::from([(Peer(1), "10.0.0.1:8080".to_owned())]);
+        let server = HttpServer::sharded(kv, http_peers);
+        let request = request("GET", "/kv/a?x=1", b"");
+
+        let response = server.not_leader(&request, Some(Peer(1)));
+        assert_eq!(response.status, 307);
+        assert!(response
+            .headers
+            .contains(&("Location", "http://10.0.0.1:8080/kv/a?x=1".to_owned())));
+        assert!(response
+            .headers
+            .contains(&("X-Raft-Leader", "1".to_owned())));
+
+        let response = server.not_leader(&request, Some(Peer(2)));
+        assert_eq!(response.status, 503);
+        assert!(response
+            .headers
+            .contains(&("X-Raft-Leader", "2".to_owned())));
+
+        let response = server.not_leader(&request, None);
+        assert_eq!(response.status, 503);
+        assert!(response
+            .headers
+            .iter()
+            .all(|(name, _)| *name != "X-Raft-Leader"));
+    }
+}
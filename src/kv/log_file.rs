@@ -0,0 +1,624 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+};
+
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    log_array::Index,
+    raft_state::{GroupId, Term},
+};
+
+const LOG_FILE_NAME: &str = "raft.log";
+const REWRITE_FILE_NAME: &str = "raft.log.rewrite";
+
+// The file is rewritten once it is twice as long as after the last rewrite
+// or when opened, and at least this long.
+const MIN_REWRITE_LEN: u64 = 64 << 20;
+
+// Each record is framed by its length, the CRC32 checksum of the length and
+// the CRC32 checksum of its bytes. The length has its own checksum so that a
+// damaged length cannot pass for a record torn off at the end of the file.
+const HEADER_LEN: u64 = 12;
+
+/// One change to the saved state of a group, as written to the log file.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) enum Record {
+    TermVote {
+        term: Term,
+        voted_for: String,
+    },
+    /// Replaces the saved entries from `index` on.
+    Entry {
+        index: Index,
+        term: Term,
+        command: Vec<u8>,
+    },
+    Membership(Vec<u8>),
+    /// The group is removed from the host. Its saved state is dropped.
+    Removed,
+}
+
+/// Called once the records of an `append()` call are durable. Dropped
+/// instead if they cannot be written.
+pub(crate) type OnDurable = Box<dyn FnOnce() + Send>;
+
+// Returns the records of the saved state of all groups, see `LogFile::open()`.
+type Snapshot = Box<dyn Fn() -> Vec<(GroupId, Record)> + Send>;
+
+// Records not written yet, and what to do once they are durable
+#[derive(Default)]
+struct Queue {
+    buffer: Vec<u8>,
+    // Callbacks of `append()`
+    on_durable: Vec<OnDurable>,
+    // Number of `append()` calls so far
+    appended: u64,
+    // Number of calls whose records are durable
+    durable: u64,
+    // Set while a blocking call of the runtime writes the records
+    writing: bool,
+    failed: bool,
+    closed: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    // Wakes up callers waiting for their records to be durable, and for the
+    // writes to stop
+    written: Condvar,
+    writer: Mutex<Writer>,
+    runtime: tokio::runtime::Handle,
+    // Callbacks are called by a task, so that a callback that takes a lock
+    // held by a caller of `wait()` does not hold up the writes that caller
+    // is waiting for.
+    notifier: mpsc::UnboundedSender<Vec<OnDurable>>,
+}
+
+/// A file that the saved state of the groups on a host is appended to, record
+/// by record.
+///
+/// Records are written and synced on the blocking threads of a runtime.
+/// Records appended by concurrent callers while the previous write is in
+/// progress are written together, with a single sync, so that the number of
+/// syncs grows with neither the number of callers nor the number of groups.
+///
+/// Records that are replaced by later ones are dropped when the file grows:
+/// the file is rewritten with the records of the saved state only, so that
+/// the time it takes to read it back is bounded by the size of the state.
+pub(crate) struct LogFile {
+    shared: Arc<Shared>,
+}
+
+impl LogFile {
+    /// Opens the log file in `dir`, creating both if needed. Returns the file
+    /// and the records in it, with the group of each. The file is written on
+    /// `runtime`.
+    ///
+    /// A record at the end of the file that was only partly written before a
+    /// crash is discarded. A record that is damaged before the end is an
+    /// error of kind `InvalidData`, since records after it would be lost.
+    ///
+    /// `snapshot` returns the records of the saved state when the file is
+    /// rewritten. The state must include every record appended before the
+    /// call; replaying records appended during the call on top of it must
+    /// give the same state again.
+    pub fn open(
+        dir: &Path,
+        runtime: tokio::runtime::Handle,
+        snapshot: impl Fn() -> Vec<(GroupId, Record)> + Send + 'static,
+    ) -> io::Result<(Self, Vec<(GroupId, Record)>)> {
+        Self::open_with(dir, runtime, MIN_REWRITE_LEN, Box::new(snapshot))
+    }
+
+    fn open_with(
+        dir: &Path,
+        runtime: tokio::runtime::Handle,
+        min_rewrite_len: u64,
+        snapshot: Snapshot,
+    ) -> io::Result<(Self, Vec<(GroupId, Record)>)> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE_NAME);
+        let created = !path.exists();
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        if created {
+            File::open(dir)?.sync_all()?;
+        }
+
+        let file_len = file.metadata()?.len();
+        let (records, valid_len) = read_records(&file, file_len)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        if valid_len < file_len {
+            log::warn!(
+                "Discarding {} bytes at the end of {}",
+                file_len - valid_len,
+                path.display()
+            );
+            file.set_len(valid_len)?;
+            file.sync_data()?;
+        }
+
+        let writer = Writer {
+            file,
+            dir: dir.to_owned(),
+            file_len: valid_len,
+            min_rewrite_len,
+            rewrite_len: rewrite_len(valid_len, min_rewrite_len),
+            snapshot,
+        };
+        let (notifier, mut notifications) = mpsc::unbounded_channel::<Vec<OnDurable>>();
+        // Exits once the file is dropped, and no write is in progress.
+        runtime.spawn(async move {
+            while let Some(callbacks) = notifications.recv().await {
+                for on_durable in callbacks {
+                    on_durable();
+                }
+            }
+        });
+        let shared = Arc::new(Shared {
+            queue: Mutex::default(),
+            written: Condvar::new(),
+            writer: Mutex::new(writer),
+            runtime,
+            notifier,
+        });
+        Ok((LogFile { shared }, records))
+    }
+
+    /// Appends `records` of `group` without waiting for them to be durable.
+    /// Returns the number to pass to `wait()`. `on_durable`, if any, is
+    /// called once the records are durable.
+    pub fn append(&self, group: GroupId, records: &[Record], on_durable: Option<OnDurable>) -> u64 {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.appended += 1;
+        // Dropping the callback tells the caller the records are not written.
+        if queue.failed || queue.closed {
+            return queue.appended;
+        }
+        for record in records {
+            encode_record(group, record, &mut queue.buffer);
+        }
+        queue.on_durable.extend(on_durable);
+        if !queue.writing {
+            queue.writing = true;
+            let shared = self.shared.clone();
+            self.shared
+                .runtime
+                .spawn_blocking(move || shared.write_records());
+        }
+        queue.appended
+    }
+
+    /// Returns once the records of the `append()` call that returned `call`
+    /// are durable.
+    ///
+    /// Panics if the records cannot be written, or are appended after the
+    /// file is closed: the saved state would be lost.
+    pub fn wait(&self, call: u64) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        while queue.durable < call {
+            assert!(!queue.failed, "Writing the Raft log file failed");
+            assert!(!queue.closed, "The Raft log file is closed");
+            queue = self.shared.written.wait(queue).unwrap();
+        }
+    }
+
+    /// Returns once the records appended so far are durable, or cannot be
+    /// written.
+    pub fn flush(&self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let call = queue.appended;
+        while queue.durable < call && queue.writing {
+            queue = self.shared.written.wait(queue).unwrap();
+        }
+    }
+
+    /// Writes everything appended so far, then stops writing. Records
+    /// appended afterwards are dropped.
+    pub fn close(&self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.closed = true;
+        while queue.writing {
+            queue = self.shared.written.wait(queue).unwrap();
+        }
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Shared {
+    // Writes what is appended until everything is durable, or a write fails.
+    // Runs on the blocking threads of the runtime, started by `append()`.
+    fn write_records(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        loop {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.durable == queue.appended {
+                queue.writing = false;
+                self.written.notify_all();
+                return;
+            }
+            let buffer = std::mem::take(&mut queue.buffer);
+            let on_durable = std::mem::take(&mut queue.on_durable);
+            let appended = queue.appended;
+            drop(queue);
+
+            let result = if writer.file_len + buffer.len() as u64 >= writer.rewrite_len {
+                // The records taken are part of the snapshot.
+                writer.rewrite()
+            } else {
+                writer.append(&buffer)
+            };
+
+            let mut queue = self.queue.lock().unwrap();
+            if let Err(e) = result {
+                log::error!("Cannot write the Raft log file: {}", e);
+                // Dropping the callbacks tells the callers.
+                queue.failed = true;
+                queue.writing = false;
+                queue.buffer.clear();
+                queue.on_durable.clear();
+                self.written.notify_all();
+                return;
+            }
+            queue.durable = appended;
+            self.written.notify_all();
+            drop(queue);
+            let _ = self.notifier.send(on_durable);
+        }
+    }
+}
+
+// The file, owned by the write in progress
+struct Writer {
+    file: File,
+    dir: PathBuf,
+    file_len: u64,
+    min_rewrite_len: u64,
+    // The file is rewritten once it is this long
+    rewrite_len: u64,
+    snapshot: Snapshot,
+}
+
+impl Writer {
+    fn append(&mut self, buffer: &[u8]) -> io::Result<()> {
+        self.file.write_all(buffer)?;
+        self.file.sync_data()?;
+        self.file_len += buffer.len() as u64;
+        Ok(())
+    }
+
+    // Replaces the file with one that holds the records of `snapshot` only.
+    // A crash leaves either file in place.
+    fn rewrite(&mut self) -> io::Result<()> {
+        let mut buffer = vec![];
+        for (group, record) in (self.snapshot)() {
+            encode_record(group, &record, &mut buffer);
+        }
+        let path = self.dir.join(REWRITE_FILE_NAME);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        file.set_len(0)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        std::fs::rename(&path, self.dir.join(LOG_FILE_NAME))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        log::info!(
+            "Rewrote the Raft log file in {} from {} to {} bytes",
+            self.dir.display(),
+            self.file_len,
+            buffer.len()
+        );
+        self.file = file;
+        self.file_len = buffer.len() as u64;
+        self.rewrite_len = rewrite_len(self.file_len, self.min_rewrite_len);
+        Ok(())
+    }
+}
+
+// The length a file that is `len` long after a rewrite is rewritten at
+fn rewrite_len(len: u64, min_rewrite_len: u64) -> u64 {
+    len.saturating_mul(2).max(min_rewrite_len)
+}
+
+fn encode_record(group: GroupId, record: &Record, buffer: &mut Vec<u8>) {
+    let bytes = bincode::serialize(&(group, record)).expect("Encoding a record should not fail");
+    let len = (bytes.len() as u32).to_le_bytes();
+    buffer.extend_from_slice(&len);
+    buffer.extend_from_slice(&crc32fast::hash(&len).to_le_bytes());
+    buffer.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+    buffer.extend_from_slice(&bytes);
+}
+
+// Reads the records of `file`, which is `file_len` bytes long. Returns them,
+// and the length of the bytes they were read from. That is less than
+// `file_len` if the last record was only partly written.
+fn read_records(file: &File, file_len: u64) -> io::Result<(Vec<(GroupId, Record)>, u64)> {
+    let mut reader = BufReader::new(file);
+    let mut records = vec![];
+    let mut offset = 0;
+    while offset < file_len {
+        let remaining = file_len - offset;
+        let (record, len) = read_record(&mut reader, remaining)?;
+        if let Some(record) = record {
+            records.push(record);
+            offset += len;
+            continue;
+        }
+        // A crash can leave the last write partly done, or followed by
+        // zeros, but cannot damage what was synced before.
+        if len == remaining || is_zeros(&mut reader, remaining - len)? {
+            break;
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("damaged record at offset {}", offset),
+        ));
+    }
+    Ok((records, offset))
+}
+
+// Reads the record at the start of `reader`, which has `remaining` bytes.
+// Returns the record, with its group, and the length of its frame. If the frame is cut short
+// or does not match its checksum, returns `None` and the number of bytes
+// the frame takes, up to `remaining`.
+fn read_record(
+    reader: &mut impl Read,
+    remaining: u64,
+) -> io::Result<(Option<(GroupId, Record)>, u64)> {
+    if remaining < HEADER_LEN {
+        return Ok((None, remaining));
+    }
+    let mut header = [0; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+    let len_crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let crc = u32::from_le_bytes(header[8..].try_into().unwrap());
+    // Zeros are not a valid header either. Leave it to the caller whether
+    // what follows a bad header is damage or the unwritten end of the file.
+    if crc32fast::hash(&header[..4]) != len_crc {
+        return Ok((None, HEADER_LEN));
+    }
+    if HEADER_LEN + len > remaining {
+        return Ok((None, remaining));
+    }
+
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    // Records are never empty, zeros are not a valid frame.
+    if len == 0 || crc32fast::hash(&bytes) != crc {
+        return Ok((None, HEADER_LEN + len));
+    }
+    let record =
+        bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((Some(record), HEADER_LEN + len))
+}
+
+// Whether the next `len` bytes of `reader` are all zeros.
+fn is_zeros(reader: &mut impl Read, len: u64) -> io::Result<bool> {
+    let mut buffer = [0; 4096];
+    let mut reader = reader.take(len);
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        if buffer[..read].iter().any(|byte| *byte != 0) {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Seek, path::PathBuf};
+
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("raft-log-file-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(index: Index) -> Record {
+        Record::Entry {
+            index,
+            term: Term(1),
+            command: vec![index as u8; 16],
+        }
+    }
+
+    // The records of group 0
+    fn of_group_0(records: impl IntoIterator<Item = Record>) -> Vec<(GroupId, Record)> {
+        records
+            .into_iter()
+            .map(|record| (GroupId(0), record))
+            .collect()
+    }
+
+    fn open(dir: &Path) -> io::Result<(LogFile, Vec<(GroupId, Record)>)> {
+        LogFile::open(dir, runtime().handle().clone(), Vec::new)
+    }
+
+    // Shared by the tests, so that it outlives the files.
+    fn runtime() -> &'static tokio::runtime::Runtime {
+        static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+        RUNTIME.get_or_init(|| tokio::runtime::Runtime::new().unwrap())
+    }
+
+    // Writes the entries 1 to 3 to a new log file in `dir`, and returns the
+    // length of the file.
+    fn write_entries(dir: &Path) -> u64 {
+        let (log_file, records) = open(dir).unwrap();
+        assert!(records.is_empty());
+        for index in 1..=3 {
+            let call = log_file.append(GroupId(0), &[entry(index)], None);
+            log_file.wait(call);
+        }
+        log_file.close();
+        fs::metadata(dir.join(LOG_FILE_NAME)).unwrap().len()
+    }
+
+    #[test]
+    fn records_are_read_back() {
+        let dir = test_dir("read-back");
+        write_entries(&dir);
+        let (_log_file, records) = open(&dir).unwrap();
+        assert_eq!(records, of_group_0([entry(1), entry(2), entry(3)]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_discarded() {
+        let dir = test_dir("torn-tail");
+        let len = write_entries(&dir);
+        let path = dir.join(LOG_FILE_NAME);
+        // Every prefix of the last record, from a part of its header to all
+        // but its last byte.
+        let record_len = HEADER_LEN + bincode::serialized_size(&(GroupId(0), entry(3))).unwrap();
+        for cut in 1..record_len {
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_len(len - cut)
+                .unwrap();
+            let (log_file, records) = open(&dir).unwrap();
+            assert_eq!(records, of_group_0([entry(1), entry(2)]));
+            assert_eq!(fs::metadata(&path).unwrap().len(), len - record_len);
+
+            // The file is appended to where the valid records end.
+            let call = log_file.append(GroupId(0), &[entry(3)], None);
+            log_file.wait(call);
+            log_file.close();
+            assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zeros_after_the_last_record_are_discarded() {
+        let dir = test_dir("zeros");
+        let len = write_entries(&dir);
+        let path = dir.join(LOG_FILE_NAME);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[0; 100])
+            .unwrap();
+        let (_log_file, records) = open(&dir).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_records_before_the_end_are_an_error() {
+        let dir = test_dir("damaged");
+        let len = write_entries(&dir);
+        let path = dir.join(LOG_FILE_NAME);
+        // The last byte of the first record.
+        let offset = HEADER_LEN + bincode::serialized_size(&(GroupId(0), entry(1))).unwrap() - 1;
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(io::SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+
+        let error = open(&dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // Nothing is discarded.
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_lengths_before_the_end_are_an_error() {
+        let dir = test_dir("damaged-length");
+        let len = write_entries(&dir);
+        let path = dir.join(LOG_FILE_NAME);
+        // The length of the first record now reaches past the end of the file.
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&[0xff, 0xff]).unwrap();
+        drop(file);
+
+        let error = open(&dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaced_records_are_dropped_by_rewrites() {
+        let dir = test_dir("rewrite");
+        let term_vote = |term| Record::TermVote {
+            term: Term(term),
+            voted_for: String::new(),
+        };
+        // The saved state is the last term.
+        let state = Arc::new(Mutex::new(0));
+        let snapshot = {
+            let state = state.clone();
+            Box::new(move || of_group_0([term_vote(*state.lock().unwrap())]))
+        };
+        let (log_file, _) =
+            LogFile::open_with(&dir, runtime().handle().clone(), 1024, snapshot).unwrap();
+        let mut calls = vec![];
+        for term in 1..=1000 {
+            let mut state = state.lock().unwrap();
+            *state = term;
+            calls.push(log_file.append(GroupId(0), &[term_vote(term)], None));
+        }
+        log_file.wait(calls.pop().unwrap());
+        log_file.close();
+
+        let path = dir.join(LOG_FILE_NAME);
+        assert!(fs::metadata(&path).unwrap().len() < 1024);
+        assert!(!dir.join(REWRITE_FILE_NAME).exists());
+        let (_log_file, records) = open(&dir).unwrap();
+        assert!(records.len() < 100);
+        assert_eq!(records.last(), Some(&(GroupId(0), term_vote(1000))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn groups_share_the_file() {
+        let dir = test_dir("groups");
+        let (log_file, _) = open(&dir).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for group in [GroupId(1), GroupId(2)] {
+            let sender = sender.clone();
+            let on_durable = Box::new(move || sender.send(group).unwrap());
+            log_file.append(group, &[entry(group.0 as Index)], Some(on_durable));
+        }
+        let mut durable: Vec<_> = receiver.iter().take(2).collect();
+        durable.sort();
+        assert_eq!(durable, vec![GroupId(1), GroupId(2)]);
+        log_file.close();
+
+        let (_log_file, records) = open(&dir).unwrap();
+        assert_eq!(
+            records,
+            vec![(GroupId(1), entry(1)), (GroupId(2), entry(2))]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
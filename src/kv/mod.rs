@@ -1 +1,5 @@
+pub mod http;
+mod log_file;
+pub mod server;
+pub mod sharded;
 pub mod storage;
@@ -0,0 +1,228 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{oneshot, watch};
+
+use crate::{
+    apply_command::ApplyCommandFnMut,
+    log_array::Index,
+    raft::Raft,
+    raft_state::{Peer, Term},
+    range::{Range, RangeResult},
+    state_machine::{
+        encode_command, now_millis, ApplyError, ApplyResult, Command, CommandKind, StateMachine,
+    },
+};
+
+const LEASE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+// How long a proposal may wait to be committed and applied
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A command proposed by this server, waiting to be applied.
+struct Proposal {
+    command: Vec<u8>,
+    sender: oneshot::Sender<Result<ApplyResult, ApplyError>>,
+}
+
+type Proposals = Arc<Mutex<HashMap<Index, Proposal>>>;
+
+/// Why a proposed command has no result.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProposeError {
+    /// This server is not the leader. Holds the leader, if it is known.
+    NotLeader(Option<Peer>),
+    /// Another command was committed at the index of the proposal, which is
+    /// never applied.
+    Lost,
+    /// The proposal was not applied in time. It may still be applied later.
+    Timeout,
+    Apply(ApplyError),
+}
+
+impl fmt::Display for ProposeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotLeader(Some(leader)) => write!(f, "not the leader, try {}", leader.0),
+            Self::NotLeader(None) => write!(f, "not the leader, leader unknown"),
+            Self::Lost => write!(f, "proposal lost to a leadership change"),
+            Self::Timeout => write!(f, "proposal not applied in time"),
+            Self::Apply(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ProposeError {}
+
+/// A key-value server backed by a Raft log.
+#[derive(Clone)]
+pub struct KVServer {
+    pub raft: Raft<Vec<u8>>,
+    pub state_machine: Arc<Mutex<StateMachine>>,
+    proposals: Proposals,
+    // Index of the last command applied to `state_machine`
+    applied: watch::Receiver<Index>,
+}
+
+impl KVServer {
+    /// Creates a server that serves `state_machine`.
+    ///
+    /// `create_raft` is given the function that applies committed commands to
+    /// the state machine, and must pass it to the Raft instance it creates.
+    pub fn new(
+        state_machine: StateMachine,
+        create_raft: impl FnOnce(Box<dyn ApplyCommandFnMut<Vec<u8>>>) -> Raft<Vec<u8>>,
+    ) -> Self {
+        let result = Self::try_new(state_machine, |apply_command| {
+            Ok::<_, Infallible>(create_raft(apply_command))
+        });
+        match result {
+            Ok(server) => server,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like `new()`, but `create_raft` may fail.
+    pub fn try_new<E>(
+        state_machine: StateMachine,
+        create_raft: impl FnOnce(Box<dyn ApplyCommandFnMut<Vec<u8>>>) -> Result<Raft<Vec<u8>>, E>,
+    ) -> Result<Self, E> {
+        let state_machine = Arc::new(Mutex::new(state_machine));
+        let proposals = Proposals::default();
+        let (applied_sender, applied) = watch::channel(0);
+        let apply_command =
+            Self::apply_command(state_machine.clone(), proposals.clone(), applied_sender);
+        let server = KVServer {
+            raft: create_raft(Box::new(apply_command))?,
+            state_machine,
+            proposals,
+            applied,
+        };
+        server.run_lease_expiry_daemon();
+        Ok(server)
+    }
+
+    /// Applies committed commands to `state_machine`, and hands the results
+    /// to the proposals waiting for them.
+    fn apply_command(
+        state_machine: Arc<Mutex<StateMachine>>,
+        proposals: Proposals,
+        applied: watch::Sender<Index>,
+    ) -> impl ApplyCommandFnMut<Vec<u8>> {
+        move |index, command: Vec<u8>| {
+            let result = state_machine.lock().unwrap().apply(index, &command);
+            applied.send_replace(index);
+            if let Err(e) = &result {
+                log::warn!("Command at {} is not applied: {}", index, e);
+            }
+            // A proposal whose entry was replaced by another leader is dropped,
+            // which tells the proposer it is lost.
+            if let Some(proposal) = proposals.lock().unwrap().remove(&index) {
+                if proposal.command == command {
+                    let _ = proposal.sender.send(result);
+                }
+            }
+        }
+    }
+
+    /// Replicates `command` and returns the result of applying it.
+    ///
+    /// Reads proposed this way are linearizable: they are ordered with all
+    /// writes by the log.
+    pub async fn propose(&self, command: Command) -> Result<ApplyResult, ProposeError> {
+        let command = encode_command(command);
+        let (sender, receiver) = oneshot::channel();
+        let index = {
+            // Holds the lock until the proposal is registered, in case the
+            // command is applied right away.
+            let mut proposals = self.proposals.lock().unwrap();
+            let index = match self.raft.start(command.clone()) {
+                Some(index_term) => index_term.index,
+                None => return Err(ProposeError::NotLeader(self.raft.leader())),
+            };
+            proposals.insert(index, Proposal { command, sender });
+            index
+        };
+
+        match tokio::time::timeout(PROPOSAL_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => result.map_err(ProposeError::Apply),
+            Ok(Err(_)) => Err(ProposeError::Lost),
+            Err(_) => {
+                self.proposals.lock().unwrap().remove(&index);
+                Err(ProposeError::Timeout)
+            }
+        }
+    }
+
+    /// Reads `range` from the local state machine, without a log entry.
+    ///
+    /// The read is linearizable like a proposed one: it is served by the
+    /// leader, once a quorum has confirmed its leadership and the commands
+    /// committed before the read are applied, see `Raft::read_index()`.
+    pub async fn range(&self, range: &Range) -> Result<RangeResult, ProposeError> {
+        let Some(read_index) = self.raft.read_index().await else {
+            return Err(ProposeError::NotLeader(self.raft.leader()));
+        };
+        let mut applied = self.applied.clone();
+        let wait = applied.wait_for(|applied| *applied >= read_index);
+        match tokio::time::timeout(PROPOSAL_TIMEOUT, wait).await {
+            Ok(Ok(_)) => {}
+            // The Raft instance is killed, nothing is applied anymore.
+            Ok(Err(_)) => return Err(ProposeError::NotLeader(None)),
+            Err(_) => return Err(ProposeError::Timeout),
+        }
+        Ok(self.state_machine.lock().unwrap().range(range))
+    }
+
+    /// Starts a task that revokes expired leases while this server is the
+    /// leader. The task is one of the daemons of the Raft instance, and stops
+    /// with it.
+    ///
+    /// Expiry is checked against the wall clock of the leader, and the result
+    /// goes through the log as an expire command, so that all replicas delete
+    /// the same keys at the same log index. The command only revokes the
+    /// lease if it has also expired in log time, i.e. if no keep-alive got
+    /// ahead of it. An expire command proposed by a leader that loses its
+    /// leadership may be lost. The next leader proposes it again.
+    ///
+    /// A lease is expired once per term: it stays expired until the command
+    /// is applied, which can take more than one check.
+    fn run_lease_expiry_daemon(&self) {
+        let this = self.clone();
+        self.raft.daemons.spawn(&self.raft.thread_pool, async move {
+            // Leases whose expiry is proposed, with the term it is proposed in
+            let mut expiring: HashSet<(u64, Term)> = HashSet::new();
+            loop {
+                tokio::time::sleep(LEASE_CHECK_INTERVAL).await;
+                let (term, is_leader) = this.raft.get_state();
+                if !is_leader {
+                    expiring.clear();
+                    continue;
+                }
+
+                let expired = this
+                    .state_machine
+                    .lock()
+                    .unwrap()
+                    .expired_leases(now_millis());
+                expiring
+                    .retain(|(lease, expire_term)| *expire_term == term && expired.contains(lease));
+                for lease in expired {
+                    if expiring.contains(&(lease, term)) {
+                        continue;
+                    }
+                    let command =
+                        Command::new(CommandKind::LeaseExpireCommand { lease }, vec![], None);
+                    if this.raft.start(encode_command(command)).is_none() {
+                        break;
+                    }
+                    expiring.insert((lease, term));
+                }
+            }
+        });
+    }
+}
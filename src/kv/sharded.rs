@@ -0,0 +1,489 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    kv::{
+        server::{KVServer, ProposeError},
+        storage::{HostStorage, KVStorage},
+    },
+    multi_raft::MultiRaftHost,
+    raft_builder::BuildError,
+    raft_config::RaftConfig,
+    raft_state::GroupId,
+    shard::{command_keys, RoutingTable, Shard, ShardEvent},
+    state_machine::{ApplyResult, Command, CommandKind, StateMachine},
+};
+
+// How many times a command is sent before the routing cache is given up on
+const MAX_ROUTING_ATTEMPTS: u32 = 10;
+// How long to wait before sending a command again, times the attempt
+const ROUTING_RETRY_DELAY: Duration = Duration::from_millis(50);
+// Two shards are merged only if they hold at most this many keys together.
+const MERGE_MAX_KEYS: usize = 10_000;
+
+/// Why a command cannot be sent to its shard, or a shard cannot be split or
+/// merged.
+#[derive(Debug)]
+pub enum ShardError {
+    /// The command does not work on keys, and must be sent to a group.
+    NoKey,
+    /// The group is not on this host.
+    UnknownGroup(GroupId),
+    /// No shard that holds the key was found, after refreshing the routing
+    /// cache a few times.
+    NoRoute(Vec<u8>),
+    /// The shard has less than two keys, and cannot be split.
+    TooSmall,
+    /// The shard is the last one, and has no right neighbour to merge.
+    NoNeighbour,
+    /// The two shards hold too many keys to be merged.
+    TooLarge {
+        keys: usize,
+    },
+    /// The shard did not split or merge, and answered this instead.
+    Rejected(ApplyResult),
+    Propose(ProposeError),
+}
+
+impl fmt::Display for ShardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoKey => write!(f, "command has no key to route by"),
+            Self::UnknownGroup(group) => write!(f, "group {} is not on this host", group.0),
+            Self::NoRoute(key) => write!(f, "no shard found for key {:?}", key),
+            Self::TooSmall => write!(f, "shard has less than two keys"),
+            Self::NoNeighbour => write!(f, "shard has no right neighbour"),
+            Self::TooLarge { keys } => write!(
+                f,
+                "shards hold {} keys, at most {} can be merged",
+                keys, MERGE_MAX_KEYS
+            ),
+            Self::Rejected(result) => write!(f, "rejected by the shard: {:?}", result),
+            Self::Propose(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ShardError {}
+
+impl From<ProposeError> for ShardError {
+    fn from(e: ProposeError) -> Self {
+        ShardError::Propose(e)
+    }
+}
+
+/// A key-value store whose keyspace is split into shards, each replicated by
+/// its own Raft group.
+///
+/// Every group has a replica on each host of the cluster. Splits and merges go
+/// through the log of the groups involved, and each host creates and removes
+/// its replicas as it applies them.
+#[derive(Clone)]
+pub struct ShardedKV {
+    host: MultiRaftHost,
+    peers: Arc<Vec<SocketAddr>>,
+    peer_index: usize,
+    config: RaftConfig,
+    // Where the state of the groups is kept, if not in memory
+    storage: Option<Arc<HostStorage>>,
+    servers: Arc<RwLock<HashMap<GroupId, KVServer>>>,
+    routing: Arc<Mutex<RoutingTable>>,
+    shard_events: mpsc::UnboundedSender<ShardEvent>,
+}
+
+impl ShardedKV {
+    /// Creates the replicas of this host, at `peer_index` of `peers`. The
+    /// cluster starts with one shard, which holds every key and is replicated
+    /// by `GroupId(0)`. The groups on this host share one log file in the
+    /// data dir of `config`, if any.
+    pub fn new(
+        host: MultiRaftHost,
+        peers: Vec<SocketAddr>,
+        peer_index: usize,
+        config: RaftConfig,
+    ) -> Result<Self, BuildError> {
+        let storage = match &config.data_dir {
+            Some(dir) => Some(Arc::new(
+                HostStorage::open(dir, host.runtime().clone()).map_err(BuildError::Storage)?,
+            )),
+            None => None,
+        };
+        let (shard_events, mut receiver) = mpsc::unbounded_channel();
+        let this = ShardedKV {
+            host,
+            peers: Arc::new(peers),
+            peer_index,
+            config,
+            storage,
+            servers: Arc::default(),
+            routing: Arc::default(),
+            shard_events,
+        };
+        this.add_group(StateMachine::default())?;
+
+        let handler = this.clone();
+        this.host.runtime().spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                match event {
+                    ShardEvent::Split(state_machine) => {
+                        let group = state_machine.shard.group;
+                        if let Err(e) = handler.add_group(*state_machine) {
+                            log::error!("Cannot create split off group {}: {}", group.0, e);
+                        }
+                    }
+                    ShardEvent::Merged(group) => handler.remove_group(group),
+                }
+            }
+        });
+        Ok(this)
+    }
+
+    fn add_group(&self, mut state_machine: StateMachine) -> Result<KVServer, BuildError> {
+        let group = state_machine.shard.group;
+        // The storage of the group is in use.
+        if self.host.group(group).is_some() {
+            return Err(BuildError::GroupExists(group));
+        }
+        let shard = state_machine.shard.clone();
+        state_machine.server = self.peer_index;
+        state_machine.shard_events = Some(self.shard_events.clone());
+        let storage = match &self.storage {
+            Some(storage) => storage.group(group),
+            None => KVStorage::default(),
+        };
+        let server = KVServer::try_new(state_machine, |apply_command| {
+            self.host.create_group(
+                group,
+                &self.peers,
+                self.peer_index,
+                storage,
+                self.config.clone(),
+                apply_command,
+            )
+        })?;
+        self.servers.write().unwrap().insert(group, server.clone());
+        self.routing.lock().unwrap().update(shard);
+        Ok(server)
+    }
+
+    fn remove_group(&self, group: GroupId) {
+        self.servers.write().unwrap().remove(&group);
+        let storage = self.storage.clone();
+        if let Some(join_handle) = self.host.remove_group(group) {
+            self.host.runtime().spawn_blocking(move || {
+                join_handle.join();
+                if let Some(storage) = storage {
+                    storage.remove_group(group);
+                }
+            });
+        }
+    }
+
+    /// The replica of `group` on this host.
+    pub fn server(&self, group: GroupId) -> Option<KVServer> {
+        self.servers.read().unwrap().get(&group).cloned()
+    }
+
+    /// The shards known to this host.
+    pub fn shards(&self) -> Vec<Shard> {
+        self.refresh_routing();
+        self.routing.lock().unwrap().shards().cloned().collect()
+    }
+
+    /// Adds the shards of the replicas on this host to the routing cache.
+    fn refresh_routing(&self) {
+        let servers = self.servers.read().unwrap();
+        let mut routing = self.routing.lock().unwrap();
+        for server in servers.values() {
+            routing.update(server.state_machine.lock().unwrap().shard.clone());
+        }
+    }
+
+    fn route(&self, key: &[u8]) -> Option<(Shard, KVServer)> {
+        let shard = self.routing.lock().unwrap().lookup(key).cloned()?;
+        let server = self.server(shard.group)?;
+        Some((shard, server))
+    }
+
+    /// Proposes `command` to the shard that holds its keys, see
+    /// `KVServer::propose()`. A transaction is sent to the shard of its first
+    /// key, and all its keys must be in that shard.
+    ///
+    /// The shard is looked up in a routing cache. If the cache is out of date
+    /// because of a split or a merge, it is refreshed and the command is sent
+    /// again, to the right shard. A command is also sent again while its shard
+    /// is frozen for a merge. Only commands without a session should be
+    /// proposed this way: each shard has its own sessions.
+    pub async fn propose(&self, command: Command) -> Result<ApplyResult, ShardError> {
+        let key = command_keys(&command)
+            .and_then(|keys| keys.first().map(|key| key.to_vec()))
+            .ok_or(ShardError::NoKey)?;
+        for attempt in 0..MAX_ROUTING_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(ROUTING_RETRY_DELAY * attempt).await;
+            }
+            let route = self.route(&key).or_else(|| {
+                self.refresh_routing();
+                self.route(&key)
+            });
+            let Some((routed, server)) = route else {
+                continue;
+            };
+
+            match server.propose(command.clone()).await? {
+                ApplyResult::WrongShard(shard) => {
+                    let keys = command_keys(&command).unwrap_or_default();
+                    let in_shard = keys.iter().all(|key| shard.contains(key));
+                    if shard == routed && !in_shard {
+                        // The cache is up to date, the keys are not all in
+                        // one shard.
+                        return Ok(ApplyResult::WrongShard(shard));
+                    }
+                    self.routing.lock().unwrap().update(shard);
+                    self.refresh_routing();
+                }
+                result => return Ok(result),
+            }
+        }
+        Err(ShardError::NoRoute(key))
+    }
+
+    /// Proposes `command` to `group`. Used for commands that do not work on
+    /// keys, e.g. to grant a lease in the shard of `group`.
+    pub async fn propose_to(
+        &self,
+        group: GroupId,
+        command: Command,
+    ) -> Result<ApplyResult, ShardError> {
+        let server = self.server(group).ok_or(ShardError::UnknownGroup(group))?;
+        Ok(server.propose(command).await?)
+    }
+
+    /// Splits the shard of `group` at `key`, or in the middle if `key` is
+    /// `None`. The keys from the split key on move to a new group, with
+    /// replicas on the same hosts. Returns the two halves.
+    ///
+    /// This host must be the leader of `group`.
+    pub async fn split(
+        &self,
+        group: GroupId,
+        key: Option<Vec<u8>>,
+    ) -> Result<(Shard, Shard), ShardError> {
+        let server = self.server(group).ok_or(ShardError::UnknownGroup(group))?;
+        let key = match key {
+            Some(key) => key,
+            None => server
+                .state_machine
+                .lock()
+                .unwrap()
+                .middle_key()
+                .ok_or(ShardError::TooSmall)?,
+        };
+        let new_group = loop {
+            let new_group = GroupId(rand::random());
+            if new_group.0 != 0 && self.server(new_group).is_none() {
+                break new_group;
+            }
+        };
+
+        let command = Command::new(
+            CommandKind::SplitCommand {
+                new_group: new_group.0,
+            },
+            key,
+            None,
+        );
+        match server.propose(command).await? {
+            ApplyResult::Split { left, right } => {
+                let mut routing = self.routing.lock().unwrap();
+                routing.update(left.clone());
+                routing.update(right.clone());
+                Ok((left, right))
+            }
+            result => Err(ShardError::Rejected(result)),
+        }
+    }
+
+    /// Splits every shard led by this host that holds more than `max_keys`
+    /// keys. Returns the errors of the splits that failed.
+    pub async fn split_large_shards(&self, max_keys: usize) -> Vec<ShardError> {
+        let large: Vec<GroupId> = self
+            .servers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, server)| server.raft.get_state().1)
+            .filter(|(_, server)| server.state_machine.lock().unwrap().db.len() > max_keys)
+            .map(|(group, _)| *group)
+            .collect();
+        let mut errors = vec![];
+        for group in large {
+            if let Err(e) = self.split(group, None).await {
+                errors.push(e);
+            }
+        }
+        errors
+    }
+
+    /// Merges the right neighbour of the shard of `group` into it. Returns
+    /// the merged shard.
+    ///
+    /// The neighbour is frozen first, then its data is replicated through the
+    /// log of `group`. The merge is sent again if it cannot be told whether
+    /// `group` applied it. The neighbour is unfrozen if the merge is rejected,
+    /// or was never sent. If `group` stops answering, the neighbour stays
+    /// frozen, since the merge may still be applied: calling this again
+    /// completes the merge.
+    ///
+    /// This host must be the leader of both groups.
+    pub async fn merge(&self, group: GroupId) -> Result<Shard, ShardError> {
+        let left = self.server(group).ok_or(ShardError::UnknownGroup(group))?;
+        let (end, left_keys) = {
+            let state_machine = left.state_machine.lock().unwrap();
+            let end = state_machine.shard.end.clone();
+            (end.ok_or(ShardError::NoNeighbour)?, state_machine.db.len())
+        };
+        let right = self
+            .servers
+            .read()
+            .unwrap()
+            .values()
+            .find(|server| server.state_machine.lock().unwrap().shard.start == end)
+            .cloned()
+            .ok_or(ShardError::NoNeighbour)?;
+        let keys = left_keys + right.state_machine.lock().unwrap().db.len();
+        if keys > MERGE_MAX_KEYS {
+            return Err(ShardError::TooLarge { keys });
+        }
+        for server in [&left, &right] {
+            if !server.raft.get_state().1 {
+                return Err(ProposeError::NotLeader(server.raft.leader()).into());
+            }
+        }
+
+        let prepare = Command::new(CommandKind::PrepareMergeCommand, vec![], None);
+        match right.propose(prepare).await {
+            Ok(ApplyResult::MergePrepared) => {}
+            // Not frozen.
+            Ok(result) => return Err(ShardError::Rejected(result)),
+            Err(e) => {
+                // Frozen or not, nothing can be merged yet.
+                Self::abort_merge(&right).await;
+                return Err(e.into());
+            }
+        }
+        // The prepare is applied here, and nothing is applied after it.
+        let (snapshot, right_shard) = {
+            let state_machine = right.state_machine.lock().unwrap();
+            (state_machine.snapshot(), state_machine.shard.clone())
+        };
+        let commit = Command::new(CommandKind::CommitMergeCommand, vec![], Some(snapshot));
+        // Replaced by the error of each attempt
+        let mut error = ProposeError::Timeout;
+        for attempt in 0..MAX_ROUTING_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(ROUTING_RETRY_DELAY * attempt).await;
+            }
+            match left.propose(commit.clone()).await {
+                Ok(ApplyResult::Merged(shard)) => {
+                    self.routing.lock().unwrap().update(shard.clone());
+                    return Ok(shard);
+                }
+                // Applied by an earlier attempt. Entries are applied in the
+                // order they are sent, so no earlier attempt can be applied
+                // after this one.
+                Ok(ApplyResult::WrongShard(shard))
+                    if shard.end == right_shard.end && shard.contains(&right_shard.start) =>
+                {
+                    self.routing.lock().unwrap().update(shard.clone());
+                    return Ok(shard);
+                }
+                Ok(result) => {
+                    Self::abort_merge(&right).await;
+                    return Err(ShardError::Rejected(result));
+                }
+                // This host no longer leads `group`, the new leader may still
+                // apply the merge.
+                Err(e @ ProposeError::NotLeader(_)) => return Err(e.into()),
+                Err(e) => error = e,
+            }
+        }
+        Err(error.into())
+    }
+
+    // Unfreezes the shard of `server`, whose merge cannot be applied.
+    async fn abort_merge(server: &KVServer) {
+        let abort = Command::new(CommandKind::AbortMergeCommand, vec![], None);
+        if let Err(e) = server.propose(abort).await {
+            let group = server.state_machine.lock().unwrap().shard.group;
+            log::warn!("Cannot unfreeze the shard of group {}: {}", group.0, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::wait_until;
+
+    // Waits for this host to lead `group`.
+    fn wait_for_group(kv: &ShardedKV, group: GroupId) {
+        let led = wait_until(Duration::from_secs(5), || {
+            kv.server(group)
+                .is_some_and(|server| server.raft.get_state().1)
+        });
+        assert!(led, "group {} has no leader", group.0);
+    }
+
+    fn set(key: &str) -> Command {
+        let key = key.as_bytes().to_vec();
+        Command::new(CommandKind::SetCommand, key.clone(), Some(key))
+    }
+
+    fn get(key: &str) -> Command {
+        Command::new(CommandKind::GetCommand, key.as_bytes().to_vec(), None)
+    }
+
+    #[test]
+    fn shards_are_split_and_merged() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let host = MultiRaftHost::new(runtime.handle().clone());
+        let peers = vec!["127.0.0.1:1".parse().unwrap()];
+        let kv = ShardedKV::new(host, peers, 0, RaftConfig::default()).unwrap();
+        wait_for_group(&kv, GroupId(0));
+        for key in ["a", "b", "c", "d"] {
+            let result = runtime.block_on(kv.propose(set(key))).unwrap();
+            assert_eq!(result, ApplyResult::Stored);
+        }
+
+        let (left, right) = runtime
+            .block_on(kv.split(GroupId(0), Some(b"c".to_vec())))
+            .unwrap();
+        assert_eq!(left.end, Some(b"c".to_vec()));
+        assert_eq!(right.start, b"c".to_vec());
+        wait_for_group(&kv, right.group);
+        // Keys from "c" on are served by the new group.
+        let result = runtime.block_on(kv.propose(set("x"))).unwrap();
+        assert_eq!(result, ApplyResult::Stored);
+        let server = kv.server(right.group).unwrap();
+        assert_eq!(server.state_machine.lock().unwrap().db.len(), 3);
+        assert_eq!(kv.shards(), vec![left, right.clone()]);
+
+        let merged = runtime.block_on(kv.merge(GroupId(0))).unwrap();
+        assert!(merged.start.is_empty() && merged.end.is_none());
+        let removed = wait_until(Duration::from_secs(5), || kv.server(right.group).is_none());
+        assert!(removed, "the merged group should be removed");
+        for key in ["a", "x"] {
+            let result = runtime.block_on(kv.propose(get(key))).unwrap();
+            assert!(matches!(result, ApplyResult::Found(_)), "{:?}", result);
+        }
+        assert_eq!(kv.shards(), vec![merged]);
+    }
+}
@@ -1,11 +1,122 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tokio::sync::oneshot;
 
-use crate::storage::{
-    RaftLogEntryRef, RaftStoragePersisterTrait, RaftStorageTrait, RaftStoredState,
+use crate::{
+    kv::log_file::{LogFile, Record},
+    log_array::Index,
+    raft_state::{GroupId, Term},
+    storage::{
+        RaftLogEntryRef, RaftStoragePersisterTrait, RaftStorageTrait, RaftStoredLogEntry,
+        RaftStoredState,
+    },
 };
 
+/// A storage that keeps everything in memory, and optionally in the log file
+/// of a `HostStorage`. State written through the persister is visible to
+/// `read_state()` of the same storage.
 #[derive(Default)]
-pub struct KVStorage;
+pub struct KVStorage {
+    persister: Arc<KVPersister>,
+}
+
+// The saved state of the groups on a host, by group
+type GroupStates = HashMap<GroupId, Arc<Mutex<RaftStoredState>>>;
+
+/// The storage of all Raft groups on a host, kept in one log file, so that
+/// the records of different groups are written with one sync.
+pub struct HostStorage {
+    log_file: Arc<LogFile>,
+    // Shared with the log file, which rewrites itself from it
+    groups: Arc<Mutex<GroupStates>>,
+}
+
+impl HostStorage {
+    /// Opens the storage kept in `dir`, creating it if needed. The state of
+    /// each group saved there before is read back. The file is written on
+    /// `runtime`.
+    pub fn open(dir: &Path, runtime: tokio::runtime::Handle) -> io::Result<Self> {
+        let groups: Arc<Mutex<GroupStates>> = Arc::default();
+        let snapshot = {
+            let groups = groups.clone();
+            move || {
+                let groups = groups.lock().unwrap();
+                let mut records = vec![];
+                for (group, state) in groups.iter() {
+                    let state = state.lock().unwrap();
+                    records.extend(state_records(&state).into_iter().map(|r| (*group, r)));
+                }
+                records
+            }
+        };
+        let (log_file, records) = LogFile::open(dir, runtime, snapshot)?;
+        let mut states: HashMap<GroupId, RaftStoredState> = HashMap::new();
+        for (group, record) in records {
+            let state = states.entry(group).or_insert_with(empty_state);
+            match record {
+                Record::TermVote { term, voted_for } => {
+                    state.current_term = term;
+                    state.voted_for = voted_for;
+                }
+                Record::Entry {
+                    index,
+                    term,
+                    command,
+                } => append_entry(
+                    &mut state.log,
+                    RaftStoredLogEntry {
+                        index,
+                        term,
+                        command,
+                    },
+                ),
+                Record::Membership(membership) => state.membership = membership,
+                Record::Removed => {
+                    states.remove(&group);
+                }
+            }
+        }
+        // Groups that are not opened again are still kept by rewrites.
+        *groups.lock().unwrap() = states
+            .into_iter()
+            .map(|(group, state)| (group, Arc::new(Mutex::new(state))))
+            .collect();
+        Ok(HostStorage {
+            log_file: Arc::new(log_file),
+            groups,
+        })
+    }
+
+    /// The storage of `group`, with the state saved before, if any. The
+    /// storage must not be used by more than one instance at a time.
+    pub fn group(&self, group: GroupId) -> KVStorage {
+        let state = self
+            .groups
+            .lock()
+            .unwrap()
+            .entry(group)
+            .or_insert_with(|| Arc::new(Mutex::new(empty_state())))
+            .clone();
+        KVStorage {
+            persister: Arc::new(KVPersister {
+                state,
+                log_file: Some((group, self.log_file.clone())),
+            }),
+        }
+    }
+
+    /// Drops the saved state of `group`, once the instance that used it is
+    /// killed.
+    pub fn remove_group(&self, group: GroupId) {
+        self.groups.lock().unwrap().remove(&group);
+        self.log_file.append(group, &[Record::Removed], None);
+    }
+}
 
 impl RaftStorageTrait for KVStorage {
     type RaftStoragePersister<LogEntry: RaftLogEntryRef> = KVPersister;
@@ -13,31 +124,209 @@ impl RaftStorageTrait for KVStorage {
     fn persister<LogEntry: RaftLogEntryRef>(
         self,
     ) -> std::sync::Arc<Self::RaftStoragePersister<LogEntry>> {
-        Arc::new(KVPersister)
+        self.persister
     }
 
     fn read_state(&self) -> std::io::Result<RaftStoredState> {
-        todo!()
+        Ok(self.persister.state.lock().unwrap().clone())
+    }
+}
+
+pub struct KVPersister {
+    // Shared with the log file, which rewrites itself from it
+    state: Arc<Mutex<RaftStoredState>>,
+    // Where the state is saved, and the group it is saved as, if not only in
+    // memory
+    log_file: Option<(GroupId, Arc<LogFile>)>,
+}
+
+impl Default for KVPersister {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(empty_state())),
+            log_file: None,
+        }
+    }
+}
+
+fn empty_state() -> RaftStoredState {
+    RaftStoredState {
+        current_term: Term(0),
+        voted_for: String::new(),
+        log: vec![],
+        membership: vec![],
+        snapshot_index: 0,
+        snapshot_term: Term(0),
+        snapshot: vec![],
+    }
+}
+
+// Entries are stored in index order. Entries from `entry` on are replaced.
+fn append_entry(log: &mut Vec<RaftStoredLogEntry>, entry: RaftStoredLogEntry) {
+    let kept = log.partition_point(|stored| stored.index < entry.index);
+    log.truncate(kept);
+    log.push(entry);
+}
+
+fn entry_record(entry: &RaftStoredLogEntry) -> Record {
+    Record::Entry {
+        index: entry.index,
+        term: entry.term,
+        command: entry.command.clone(),
     }
 }
 
-#[derive(Debug)]
-pub struct KVPersister;
+// The records that `state` is read back from
+fn state_records(state: &RaftStoredState) -> Vec<Record> {
+    let mut records = vec![
+        Record::TermVote {
+            term: state.current_term,
+            voted_for: state.voted_for.clone(),
+        },
+        Record::Membership(state.membership.clone()),
+    ];
+    records.extend(state.log.iter().map(entry_record));
+    records
+}
+
+impl KVPersister {
+    // Saves `entries`, and returns the `LogFile::append()` call that writes
+    // them, if any. `sender` gets the index of the last entry once they are
+    // durable.
+    fn save_entries<LogEntry: RaftLogEntryRef>(
+        &self,
+        entries: &[LogEntry],
+        sender: oneshot::Sender<Index>,
+    ) -> Option<u64> {
+        let index = entries.last().map_or(0, |entry| entry.index());
+        let mut state = self.state.lock().unwrap();
+        let mut records = vec![];
+        for entry in entries {
+            let stored = RaftStoredLogEntry {
+                index: entry.index(),
+                term: entry.term(),
+                command: entry.command_bytes(),
+            };
+            if self.log_file.is_some() {
+                records.push(entry_record(&stored));
+            }
+            append_entry(&mut state.log, stored);
+        }
+        match &self.log_file {
+            // Appended while `state` is locked, so that records are written in
+            // the order they are applied to `state`.
+            Some((group, log_file)) => {
+                let on_durable = Box::new(move || {
+                    let _ = sender.send(index);
+                });
+                Some(log_file.append(*group, &records, Some(on_durable)))
+            }
+            None => {
+                let _ = sender.send(index);
+                None
+            }
+        }
+    }
+
+    // Saves `record`, which is already applied to `state`, and waits for it
+    // to be durable.
+    fn save_record(&self, state: MutexGuard<RaftStoredState>, record: Record) {
+        if let Some((group, log_file)) = &self.log_file {
+            let call = log_file.append(*group, &[record], None);
+            drop(state);
+            log_file.wait(call);
+        }
+    }
+}
 
 impl<LogEntry: RaftLogEntryRef> RaftStoragePersisterTrait<LogEntry> for KVPersister {
-    fn save_term_vote(&self, term: crate::raft_state::Term, voted_for: String) {
-        todo!()
+    fn save_term_vote(&self, term: Term, voted_for: String) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        state.current_term = term;
+        state.voted_for = voted_for.clone();
+        match &self.log_file {
+            Some((group, log_file)) => {
+                let on_durable = Box::new(move || {
+                    let _ = sender.send(());
+                });
+                let record = Record::TermVote { term, voted_for };
+                log_file.append(*group, &[record], Some(on_durable));
+            }
+            None => {
+                let _ = sender.send(());
+            }
+        }
+        receiver
     }
 
     fn append_one_entry(&self, entry: &LogEntry) {
-        todo!()
+        let (sender, _) = oneshot::channel();
+        let call = self.save_entries(std::slice::from_ref(entry), sender);
+        if let (Some((_, log_file)), Some(call)) = (&self.log_file, call) {
+            log_file.wait(call);
+        }
+    }
+
+    fn append_entries(&self, entries: &[LogEntry]) -> oneshot::Receiver<Index> {
+        let (sender, receiver) = oneshot::channel();
+        self.save_entries(entries, sender);
+        receiver
+    }
+
+    fn save_membership(&self, membership: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.membership = membership.clone();
+        self.save_record(state, Record::Membership(membership));
     }
 
-    // fn append_entries<'a, LogEntryList>(&self, entries: LogEntryList)
-    // where
-    //     LogEntry: 'a,
-    //     LogEntryList: IntoIterator<Item = &'a LogEntry>,
-    // {
-    //     todo!()
-    // }
+    // The file is shared with the other groups, and closed once they are
+    // all dropped.
+    fn close(&self) {
+        if let Some((_, log_file)) = &self.log_file {
+            log_file.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log_array::LogEntry;
+
+    #[test]
+    fn groups_are_read_back_until_removed() {
+        let dir = std::env::temp_dir().join(format!("raft-host-storage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let open = || HostStorage::open(&dir, runtime.handle().clone()).unwrap();
+        let save_term = |storage: &HostStorage, group, term| {
+            let persister = storage.group(group).persister::<LogEntry<Vec<u8>>>();
+            let durable = RaftStoragePersisterTrait::<LogEntry<Vec<u8>>>::save_term_vote(
+                &*persister,
+                Term(term),
+                String::new(),
+            );
+            runtime.block_on(durable).unwrap();
+        };
+        let term =
+            |storage: &HostStorage, group| storage.group(group).read_state().unwrap().current_term;
+
+        let storage = open();
+        save_term(&storage, GroupId(1), 1);
+        save_term(&storage, GroupId(2), 2);
+        drop(storage);
+
+        let storage = open();
+        assert_eq!(term(&storage, GroupId(1)), Term(1));
+        assert_eq!(term(&storage, GroupId(2)), Term(2));
+        storage.remove_group(GroupId(1));
+        drop(storage);
+
+        let storage = open();
+        assert_eq!(term(&storage, GroupId(1)), Term(0));
+        assert_eq!(term(&storage, GroupId(2)), Term(2));
+        drop(storage);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,278 @@
+use std::{
+    fmt,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    messages::{TimeoutNowArgs, TimeoutNowReply},
+    raft::{Raft, ReplicableCommand},
+    raft_state::{LeadershipTransfer, Peer},
+    remote::remote_peer::RemotePeer,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransferLeadershipError {
+    /// This instance is not the leader.
+    NotLeader,
+    /// The target is this instance, or not a voter in the cluster.
+    InvalidTarget(Peer),
+    /// Leadership is already being transferred to the given peer.
+    InProgress(Peer),
+}
+
+impl fmt::Display for TransferLeadershipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotLeader => write!(f, "not the leader"),
+            Self::InvalidTarget(peer) => write!(f, "{:?} cannot take over leadership", peer),
+            Self::InProgress(peer) => {
+                write!(f, "leadership is already being transferred to {:?}", peer)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransferLeadershipError {}
+
+impl<Command: ReplicableCommand> Raft<Command> {
+    const CATCH_UP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Hands leadership over to `target`.
+    ///
+    /// New proposals are rejected while the transfer is in progress. Once the
+    /// target has every entry in our log, it is told to start an election
+    /// right away. If the target has not taken over after an election timeout,
+    /// the transfer is abandoned and proposals are accepted again.
+    ///
+    /// Returns after the transfer has been started. `get_state()` tells if
+    /// this instance is still the leader.
+    pub fn transfer_leadership(&self, target: Peer) -> Result<(), TransferLeadershipError> {
+        let remote = self
+            .remote_peers
+            .lock()
+            .unwrap()
+            .get(&target)
+            .cloned()
+            .ok_or(TransferLeadershipError::InvalidTarget(target))?;
+
+        let transfer = {
+            let mut rf = self.inner_state.lock().unwrap();
+            if !rf.is_leader() {
+                return Err(TransferLeadershipError::NotLeader);
+            }
+            // Learners have remote peers too, but cannot be elected.
+            if !rf.membership.is_voter(target) {
+                return Err(TransferLeadershipError::InvalidTarget(target));
+            }
+            if let Some(transfer) = rf.leadership_transfer {
+                return Err(TransferLeadershipError::InProgress(transfer.target));
+            }
+            let transfer = LeadershipTransfer {
+                target,
+                deadline: Instant::now() + self.election.max_election_timeout(),
+            };
+            rf.leadership_transfer = Some(transfer);
+            transfer
+        };
+        log::info!("{:?} transferring leadership to {:?}", self.peer, target);
+
+        self.sync_log_entries_daemon.trigger(Some(target));
+        let this = self.clone();
+        self.daemons.spawn(&self.thread_pool, async move {
+            this.run_leadership_transfer(remote, transfer).await
+        });
+        Ok(())
+    }
+
+    async fn run_leadership_transfer(
+        self,
+        target: RemotePeer<Command, Peer>,
+        transfer: LeadershipTransfer,
+    ) {
+        // Wait until the target has caught up with our log.
+        let args = loop {
+            {
+                let rf = self.inner_state.lock().unwrap();
+                if rf.leadership_transfer != Some(transfer) {
+                    return;
+                }
+                let match_index = rf
+                    .cluster
+                    .get(&target.unique_id)
+                    .map_or(0, |member| member.match_index);
+                if match_index == rf.log.last_index_term().index {
+                    break TimeoutNowArgs {
+                        term: rf.current_term,
+                        leader_id: self.peer,
+                    };
+                }
+            }
+            if Instant::now() >= transfer.deadline {
+                self.abort_leadership_transfer(transfer);
+                return;
+            }
+            self.sync_log_entries_daemon.trigger(Some(target.unique_id));
+            tokio::time::sleep(Self::CATCH_UP_POLL_INTERVAL).await;
+        };
+
+        if let Ok(reply) = target.timeout_now(args).await {
+            let mut rf = self.inner_state.lock().unwrap();
+            if reply.term > rf.current_term {
+                self.step_down(&mut rf, reply.term);
+            }
+        }
+
+        // The target should have won the election well before the deadline.
+        // Its first message of the new term makes us step down, which clears
+        // the transfer.
+        tokio::time::sleep_until(transfer.deadline.into()).await;
+        self.abort_leadership_transfer(transfer);
+    }
+
+    fn abort_leadership_transfer(&self, transfer: LeadershipTransfer) {
+        let mut rf = self.inner_state.lock().unwrap();
+        if rf.leadership_transfer == Some(transfer) {
+            log::warn!(
+                "{:?} failed to transfer leadership to {:?}",
+                self.peer,
+                transfer.target
+            );
+            rf.leadership_transfer = None;
+        }
+    }
+
+    pub fn process_timeout_now(&self, args: TimeoutNowArgs) -> TimeoutNowReply {
+        let mut rf = self.inner_state.lock().unwrap();
+        if !self.keep_running.load(Ordering::Relaxed) {
+            return TimeoutNowReply {
+                term: rf.current_term,
+            };
+        }
+        if args.term > rf.current_term {
+            self.step_down(&mut rf, args.term);
+        }
+        if args.term == rf.current_term && !rf.is_leader() {
+            // Skip the rest of the election timeout. Our peers will vote for
+            // us even though they have heard from the leader recently.
+            self.election.reset_election_timer();
+            self.start_election(&mut rf, true);
+        }
+
+        TimeoutNowReply {
+            term: rf.current_term,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        raft_state::Term,
+        test_utils::{wait_for_leader, wait_until, LocalNetwork},
+    };
+
+    #[test]
+    fn transfer_hands_leadership_to_the_target() {
+        let (_network, rafts) = LocalNetwork::cluster(3);
+        let leader = wait_for_leader(&rafts);
+        rafts[leader].start("command".to_owned()).unwrap();
+
+        let target = (leader + 1) % 3;
+        rafts[leader].transfer_leadership(Peer(target)).unwrap();
+        // The target starts an election right away, well before any election
+        // timer fires.
+        assert!(wait_until(Duration::from_millis(150), || rafts[target]
+            .get_state()
+            .1));
+        assert_eq!(wait_for_leader(&rafts), target);
+    }
+
+    #[test]
+    fn transfer_is_abandoned_after_an_election_timeout() {
+        let (network, rafts) = LocalNetwork::cluster(3);
+        let leader = wait_for_leader(&rafts);
+        let target = (leader + 1) % 3;
+        network.disconnect(Peer(target));
+
+        let started = Instant::now();
+        rafts[leader].transfer_leadership(Peer(target)).unwrap();
+        assert_eq!(rafts[leader].start("rejected".to_owned()), None);
+        assert_eq!(
+            rafts[leader].transfer_leadership(Peer(target)),
+            Err(TransferLeadershipError::InProgress(Peer(target)))
+        );
+
+        // Proposals are accepted again once the transfer is abandoned.
+        assert!(wait_until(Duration::from_secs(2), || rafts[leader]
+            .start("accepted".to_owned())
+            .is_some()));
+        assert!(started.elapsed() >= rafts[leader].config.max_election_timeout());
+        assert!(rafts[leader].get_state().1);
+    }
+
+    #[test]
+    fn transfer_is_aborted_when_the_leader_steps_down() {
+        let (network, rafts) = LocalNetwork::cluster(3);
+        let leader = wait_for_leader(&rafts);
+        let target = (leader + 1) % 3;
+        network.disconnect(Peer(target));
+        rafts[leader].transfer_leadership(Peer(target)).unwrap();
+
+        let term = rafts[leader].get_state().0;
+        {
+            let mut rf = rafts[leader].inner_state.lock().unwrap();
+            rafts[leader].step_down(&mut rf, Term(term.0 + 1));
+            assert_eq!(rf.leadership_transfer, None);
+        }
+        assert_eq!(
+            rafts[leader].transfer_leadership(Peer(target)),
+            Err(TransferLeadershipError::NotLeader)
+        );
+    }
+
+    #[test]
+    fn transfer_rejects_invalid_targets() {
+        let (_network, rafts) = LocalNetwork::cluster(3);
+        let leader = wait_for_leader(&rafts);
+        assert_eq!(
+            rafts[leader].transfer_leadership(Peer(leader)),
+            Err(TransferLeadershipError::InvalidTarget(Peer(leader)))
+        );
+        assert_eq!(
+            rafts[leader].transfer_leadership(Peer(7)),
+            Err(TransferLeadershipError::InvalidTarget(Peer(7)))
+        );
+        let follower = (leader + 1) % 3;
+        assert_eq!(
+            rafts[follower].transfer_leadership(Peer(leader)),
+            Err(TransferLeadershipError::NotLeader)
+        );
+    }
+
+    #[test]
+    fn transfer_rejects_learners() {
+        let (_network, rafts) = LocalNetwork::cluster(3);
+        let leader = wait_for_leader(&rafts);
+        // Membership changes wait for the first entry of the term to commit.
+        assert!(wait_until(Duration::from_secs(1), || rafts[leader]
+            .add_learner(Peer(3), "3".to_owned())
+            .is_ok()));
+        assert!(rafts[leader]
+            .remote_peers
+            .lock()
+            .unwrap()
+            .contains_key(&Peer(3)));
+        assert_eq!(
+            rafts[leader].transfer_leadership(Peer(3)),
+            Err(TransferLeadershipError::InvalidTarget(Peer(3)))
+        );
+        assert!(rafts[leader]
+            .inner_state
+            .lock()
+            .unwrap()
+            .leadership_transfer
+            .is_none());
+    }
+}
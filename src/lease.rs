@@ -0,0 +1,92 @@
+use std::collections::BTreeSet;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::state_machine::{ApplyResult, StateMachine};
+
+/// A lease keeps the keys attached to it alive until it expires.
+///
+/// Expiry is measured in log time, the largest proposal timestamp applied so
+/// far. Replicas never delete keys on their own: the leader proposes an expire
+/// command for each expired lease, see [`StateMachine::expired_leases`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Lease {
+    pub ttl_millis: u64,
+    pub expires_at: u64,
+    pub keys: BTreeSet<Vec<u8>>,
+}
+
+impl StateMachine {
+    /// Returns the leases that have expired at wall clock time `now_millis`.
+    pub fn expired_leases(&self, now_millis: u64) -> Vec<u64> {
+        let mut expired: Vec<u64> = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.expires_at <= now_millis)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.sort_unstable();
+        expired
+    }
+
+    /// Creates a lease whose ID is the revision of the command being applied.
+    pub(crate) fn grant_lease(&mut self, ttl_millis: u64) -> ApplyResult {
+        let lease = self.revision;
+        self.leases.insert(
+            lease,
+            Lease {
+                ttl_millis,
+                expires_at: self.log_time.saturating_add(ttl_millis),
+                keys: BTreeSet::new(),
+            },
+        );
+        ApplyResult::LeaseGranted { lease, ttl_millis }
+    }
+
+    /// Extends `lease` by its TTL, unless it has already expired.
+    pub(crate) fn keep_lease_alive(&mut self, lease: u64) -> ApplyResult {
+        let log_time = self.log_time;
+        match self.leases.get_mut(&lease) {
+            Some(l) if l.expires_at > log_time => {
+                l.expires_at = log_time.saturating_add(l.ttl_millis);
+                ApplyResult::LeaseKeptAlive {
+                    ttl_millis: l.ttl_millis,
+                }
+            }
+            _ => ApplyResult::LeaseNotFound(lease),
+        }
+    }
+
+    /// Revokes `lease` if it has expired. The leader may have seen it expire
+    /// on its own clock, while a keep-alive ahead of the expire command in the
+    /// log extended it.
+    pub(crate) fn expire_lease(&mut self, lease: u64) -> ApplyResult {
+        match self.leases.get(&lease) {
+            Some(l) if l.expires_at > self.log_time => ApplyResult::LeaseNotExpired,
+            _ => self.revoke_lease(lease),
+        }
+    }
+
+    pub(crate) fn revoke_lease(&mut self, lease: u64) -> ApplyResult {
+        let Some(l) = self.leases.remove(&lease) else {
+            return ApplyResult::LeaseNotFound(lease);
+        };
+        for key in l.keys {
+            self.delete(&key);
+        }
+        ApplyResult::LeaseRevoked
+    }
+
+    /// Moves `key` from lease `from` to lease `to`. Lease 0 means no lease.
+    pub(crate) fn attach_lease(&mut self, key: &[u8], from: u64, to: u64) {
+        if from == to {
+            return;
+        }
+        if let Some(lease) = self.leases.get_mut(&from) {
+            lease.keys.remove(key);
+        }
+        if let Some(lease) = self.leases.get_mut(&to) {
+            lease.keys.insert(key.to_vec());
+        }
+    }
+}
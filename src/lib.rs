@@ -0,0 +1,27 @@
+pub mod apply_command;
+mod daemons;
+pub mod durio;
+mod election;
+mod heartbeat;
+pub mod kv;
+pub mod leadership_transfer;
+mod lease;
+pub mod log_array;
+pub mod membership;
+pub mod messages;
+pub mod multi_raft;
+mod persist_log_entries;
+pub mod raft;
+pub mod raft_builder;
+pub mod raft_config;
+pub mod raft_state;
+pub mod range;
+mod read_index;
+pub mod remote;
+pub mod shard;
+pub mod state_machine;
+pub mod storage;
+mod sync_log_entries;
+#[cfg(test)]
+mod test_utils;
+pub mod watch;
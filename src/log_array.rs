@@ -1,12 +1,29 @@
-use crate::raft_state::Term;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{membership::Membership, raft_state::Term};
 
 pub type Index = usize;
 
-#[derive(Clone, Debug)]
-pub(crate) struct LogEntry<Command> {
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IndexTerm {
+    pub index: Index,
+    pub term: Term,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LogEntryEnum<Command> {
+    /// Appended by a new leader, to commit entries of previous terms.
+    Noop,
+    Command(Command),
+    /// Changes the servers in the cluster.
+    Membership(Membership),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogEntry<Command> {
     pub index: Index,
     pub term: Term,
-    command: Option<Command>,
+    pub(crate) command: LogEntryEnum<Command>,
 }
 
 #[derive(Clone, Debug)]
@@ -17,19 +34,156 @@ pub(crate) struct LogArray<C> {
 impl<C> LogArray<C> {
     /// Create the initial Raft log with no user-supplied commands.
     pub fn create() -> LogArray<C> {
-        let ret = LogArray {
+        LogArray {
             inner: vec![Self::build_first_entry(0, Term(0))],
-        };
-        ret
+        }
+    }
+}
+
+impl<C> LogArray<C> {
+    /// The index of the first entry in the log. The first entry never carries
+    /// a command, it only records the term of the previous entry.
+    pub fn start(&self) -> Index {
+        self.first_entry().index
+    }
+
+    /// One past the index of the last entry in the log.
+    pub fn end(&self) -> Index {
+        self.start() + self.inner.len()
+    }
+
+    pub fn last_index_term(&self) -> IndexTerm {
+        let last = self.last_entry();
+        IndexTerm {
+            index: last.index,
+            term: last.term,
+        }
+    }
+
+    /// The entry at `index`. Panics if `index` is out of the log.
+    pub fn at(&self, index: Index) -> &LogEntry<C> {
+        &self.inner[self.check_index(index)]
+    }
+
+    /// All entries starting at `index`, inclusive.
+    #[cfg(test)]
+    pub fn after(&self, index: Index) -> &[LogEntry<C>] {
+        &self.inner[self.check_range_index(index)..]
+    }
+
+    /// All entries in `[start, end)`.
+    pub fn between(&self, start: Index, end: Index) -> &[LogEntry<C>] {
+        &self.inner[self.check_range_index(start)..self.check_range_index(end)]
+    }
+
+    /// The index of the first entry of `term`, if the log has any. Entries of
+    /// the same term are next to each other, and the terms of entries only go
+    /// up.
+    pub fn first_index_of_term(&self, term: Term) -> Option<Index> {
+        let first = self.inner.partition_point(|entry| entry.term < term);
+        self.inner
+            .get(first)
+            .filter(|entry| entry.term == term)
+            .map(|entry| entry.index)
+    }
+
+    /// The index of the last entry of `term`, if the log has any.
+    pub fn last_index_of_term(&self, term: Term) -> Option<Index> {
+        let end = self.inner.partition_point(|entry| entry.term <= term);
+        self.inner[..end]
+            .last()
+            .filter(|entry| entry.term == term)
+            .map(|entry| entry.index)
+    }
+
+    /// Add a new command to the end of the log, returns the index of it.
+    pub fn add_command(&mut self, term: Term, command: C) -> Index {
+        self.add_entry(term, LogEntryEnum::Command(command))
+    }
+
+    /// Add an entry that carries no command, returns the index of it.
+    pub fn add_noop(&mut self, term: Term) -> Index {
+        self.add_entry(term, LogEntryEnum::Noop)
+    }
+
+    /// Add a configuration entry, returns the index of it.
+    pub fn add_membership(&mut self, term: Term, membership: Membership) -> Index {
+        self.add_entry(term, LogEntryEnum::Membership(membership))
+    }
+
+    /// The latest configuration entry in the log, if there is any.
+    pub fn last_membership(&self) -> Option<(Index, &Membership)> {
+        self.inner
+            .iter()
+            .rev()
+            .find_map(|entry| match &entry.command {
+                LogEntryEnum::Membership(membership) => Some((entry.index, membership)),
+                _ => None,
+            })
+    }
+
+    /// Append an entry received from the leader. The entry must be placed
+    /// right after the current last entry.
+    pub fn push(&mut self, entry: LogEntry<C>) {
+        assert_eq!(entry.index, self.end(), "Log entries must be continuous");
+        self.inner.push(entry);
+    }
+
+    /// Remove all entries at and after `index`. The first entry cannot be
+    /// removed.
+    pub fn truncate(&mut self, index: Index) {
+        assert!(index > self.start(), "The first entry cannot be truncated");
+        let index = self.check_range_index(index);
+        self.inner.truncate(index);
     }
 }
 
 impl<C> LogArray<C> {
+    fn first_entry(&self) -> &LogEntry<C> {
+        self.inner.first().expect("Log array should not be empty")
+    }
+
+    fn last_entry(&self) -> &LogEntry<C> {
+        self.inner.last().expect("Log array should not be empty")
+    }
+
+    fn check_index(&self, index: Index) -> usize {
+        assert!(
+            index >= self.start() && index < self.end(),
+            "Accessing index {} out of the log [{}, {})",
+            index,
+            self.start(),
+            self.end()
+        );
+        index - self.start()
+    }
+
+    fn check_range_index(&self, index: Index) -> usize {
+        assert!(
+            index >= self.start() && index <= self.end(),
+            "Accessing range index {} out of the log [{}, {}]",
+            index,
+            self.start(),
+            self.end()
+        );
+        index - self.start()
+    }
+
+    fn add_entry(&mut self, term: Term, command: LogEntryEnum<C>) -> Index {
+        let index = self.end();
+        self.inner.push(LogEntry {
+            index,
+            term,
+            command,
+        });
+        index
+    }
+
     fn build_first_entry(index: Index, term: Term) -> LogEntry<C> {
         LogEntry {
             index,
             term,
-            command: None,
+            command: LogEntryEnum::Noop,
         }
     }
 }
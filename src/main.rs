@@ -1,40 +1,128 @@
 mod config;
-mod durio;
-mod election;
-mod heartbeat;
-mod kv;
-mod log_array;
-mod raft;
-mod raft_state;
-mod remote;
-mod state_machine;
-mod storage;
-
-use crate::{raft::Raft, state_machine::StateMachine};
-use durio::LazyRaftServiceClient;
-use kv::storage::KVStorage;
-use std::{collections::HashMap, net::SocketAddr};
-
-const IP: [u8; 4] = [127, 0, 0, 1];
+
+use config::Config;
+use raft::{
+    kv::{
+        http::HttpServer,
+        server::KVServer,
+        sharded::ShardedKV,
+        storage::{HostStorage, KVStorage},
+    },
+    multi_raft::MultiRaftHost,
+    raft_state::{GroupId, Peer},
+    state_machine::StateMachine,
+};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    process,
+    time::Duration,
+};
+use tokio::net::TcpListener;
+
+// How often the shards led by this node are checked for being too large
+const SPLIT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+fn resolve(address: &str) -> SocketAddr {
+    match address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        _ => {
+            eprintln!("Cannot resolve address: {}", address);
+            process::exit(1);
+        }
+    }
+}
 
 fn main() {
-    let raft_addr: Vec<SocketAddr> = vec![(IP, 9001).into(), (IP, 9002).into(), (IP, 9003).into()];
-    let servers = raft_addr
-        .into_iter()
-        .map(|addr| LazyRaftServiceClient { socket_addr: addr })
-        .collect();
+    let config = Config::new();
 
-    // let config = Config::new();
+    let servers: Vec<SocketAddr> = config
+        .cluster
+        .iter()
+        .map(|member| resolve(&member.address))
+        .collect();
 
-    // let state_machine = StateMachine {
-    //     db: HashMap::new(),
-    //     server: config.index,
-    // };
+    // Followers redirect clients to the leader if its HTTP address is known,
+    // and otherwise answer with a leader hint.
+    let http_peers: HashMap<Peer, String> = config
+        .cluster_http
+        .iter()
+        .map(|(id, http)| (Peer(*id as usize), http.clone()))
+        .collect();
 
-    let storage = KVStorage::default();
+    let runtime = tokio::runtime::Runtime::new().expect("Creating runtime should not fail");
+    let (rpc_listener, http_listener) = runtime.block_on(async {
+        let rpc_listener = TcpListener::bind(resolve(&config.address)).await;
+        let http_listener = TcpListener::bind(resolve(&config.http)).await;
+        (rpc_listener, http_listener)
+    });
+    let (rpc_listener, http_listener) = match (rpc_listener, http_listener) {
+        (Ok(rpc_listener), Ok(http_listener)) => (rpc_listener, http_listener),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!(
+                "Cannot listen on {} and {}: {}",
+                config.address, config.http, e
+            );
+            process::exit(1);
+        }
+    };
 
-    let raft: Raft<String> = Raft::new(servers, 0, storage);
+    // Raft runs on the runtime of the servers, instead of on its own. This
+    // node hosts one group, or one per shard.
+    let host = MultiRaftHost::new(runtime.handle().clone());
+    let http = match config.shard_max_keys {
+        Some(max_keys) => {
+            let kv = ShardedKV::new(host.clone(), servers, config.index, config.raft.clone())
+                .unwrap_or_else(|e| {
+                    eprintln!("Cannot create node {}: {}", config.id, e);
+                    process::exit(1);
+                });
+            let splitter = kv.clone();
+            runtime.spawn(async move {
+                let mut interval = tokio::time::interval(SPLIT_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    for e in splitter.split_large_shards(max_keys).await {
+                        log::warn!("Cannot split shard: {}", e);
+                    }
+                }
+            });
+            HttpServer::sharded(kv, http_peers)
+        }
+        None => {
+            let storage = match &config.raft.data_dir {
+                Some(data_dir) => HostStorage::open(data_dir, runtime.handle().clone())
+                    .unwrap_or_else(|e| {
+                        eprintln!("Cannot open {}: {}", data_dir.display(), e);
+                        process::exit(1);
+                    })
+                    .group(GroupId(0)),
+                None => KVStorage::default(),
+            };
+            let kv = KVServer::new(StateMachine::default(), |apply_command| {
+                host.create_group(
+                    GroupId(0),
+                    &servers,
+                    config.index,
+                    storage,
+                    config.raft.clone(),
+                    apply_command,
+                )
+                .unwrap_or_else(|e| {
+                    eprintln!("Cannot create node {}: {}", config.id, e);
+                    process::exit(1);
+                })
+            });
+            HttpServer::new(kv, http_peers)
+        }
+    };
 
-    loop {}
-    // println!("final state: {:?}", server);
+    let result = runtime.block_on(futures_util::future::try_join(
+        host.run(rpc_listener),
+        http.run(http_listener),
+    ));
+    if let Err(e) = result {
+        eprintln!("Node {} stopped: {}", config.id, e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,588 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    log_array::{Index, IndexTerm},
+    raft::{ClusterMember, Raft, ReplicableCommand, ReplicationState},
+    raft_state::{Peer, RaftState, State},
+    remote::remote_peer::RemotePeer,
+    storage::encode_membership,
+};
+
+/// The servers that make up the cluster, mapped to their RPC addresses.
+///
+/// Servers are added and removed by configuration entries in the log. A
+/// configuration takes effect as soon as it is appended to the log, without
+/// waiting for it to be committed.
+///
+/// A joint configuration (C_old,new) is used to replace several servers in one
+/// step. While it is in effect, elections and commits need a majority of both
+/// the old and the new voters. Once it is committed, the leader appends the
+/// new configuration (C_new) by itself.
+///
+/// Servers that are not voters are learners. They receive log entries but are
+/// left out of elections and commit quorums, until they are promoted.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Membership {
+    // All servers that receive log entries, old and new
+    pub servers: BTreeMap<Peer, String>,
+    // Servers that vote, i.e. the new voters in a joint configuration
+    pub voters: BTreeSet<Peer>,
+    // Servers that vote in the old configuration, if this one is joint
+    pub old_voters: Option<BTreeSet<Peer>>,
+}
+
+impl Membership {
+    /// A configuration in which every server is a voter.
+    pub fn new(servers: BTreeMap<Peer, String>) -> Self {
+        Membership {
+            voters: servers.keys().copied().collect(),
+            servers,
+            old_voters: None,
+        }
+    }
+
+    pub fn contains(&self, peer: Peer) -> bool {
+        self.servers.contains_key(&peer)
+    }
+
+    pub fn is_voter(&self, peer: Peer) -> bool {
+        self.voters.contains(&peer)
+            || self
+                .old_voters
+                .as_ref()
+                .is_some_and(|old_voters| old_voters.contains(&peer))
+    }
+
+    pub fn is_learner(&self, peer: Peer) -> bool {
+        self.contains(peer) && !self.is_voter(peer)
+    }
+
+    pub fn learners(&self) -> impl Iterator<Item = Peer> + '_ {
+        self.peers().filter(|peer| self.is_learner(*peer))
+    }
+
+    pub fn is_joint(&self) -> bool {
+        self.old_voters.is_some()
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = Peer> + '_ {
+        self.servers.keys().copied()
+    }
+
+    /// Returns true if `votes` contains a majority of the voters, and of the
+    /// old voters if this configuration is joint.
+    pub fn has_quorum(&self, votes: &HashSet<Peer>) -> bool {
+        Self::has_majority(&self.voters, votes)
+            && self
+                .old_voters
+                .as_ref()
+                .is_none_or(|old_voters| Self::has_majority(old_voters, votes))
+    }
+
+    /// The highest index that is matched by a quorum of the voters, given the
+    /// index matched by each server.
+    pub fn quorum_index(&self, matched: impl Fn(Peer) -> Index) -> Index {
+        let index = Self::majority_index(&self.voters, &matched);
+        match &self.old_voters {
+            Some(old_voters) => index.min(Self::majority_index(old_voters, &matched)),
+            None => index,
+        }
+    }
+
+    fn has_majority(voters: &BTreeSet<Peer>, votes: &HashSet<Peer>) -> bool {
+        let granted = voters.iter().filter(|peer| votes.contains(peer)).count();
+        granted > voters.len() / 2
+    }
+
+    fn majority_index(voters: &BTreeSet<Peer>, matched: impl Fn(Peer) -> Index) -> Index {
+        let mut indexes: Vec<Index> = voters.iter().copied().map(matched).collect();
+        if indexes.is_empty() {
+            return 0;
+        }
+        indexes.sort_unstable();
+        indexes[(indexes.len() - 1) / 2]
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MembershipChangeError {
+    /// This instance is not the leader.
+    NotLeader,
+    /// A previous change is not committed yet, or leadership is being
+    /// transferred.
+    ChangeInProgress,
+    /// The server to add is already in the cluster.
+    AlreadyMember(Peer),
+    /// The server to remove is not in the cluster.
+    NotMember(Peer),
+    /// The last server of the cluster cannot be removed.
+    LastMember(Peer),
+    /// The new configuration has no server.
+    EmptyMembership,
+    /// The server to promote is not a learner.
+    NotLearner(Peer),
+    /// The learner to promote is too far behind the leader.
+    LearnerBehind(Peer),
+}
+
+impl fmt::Display for MembershipChangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotLeader => write!(f, "not the leader"),
+            Self::ChangeInProgress => write!(f, "another change is in progress"),
+            Self::AlreadyMember(peer) => write!(f, "{:?} is already in the cluster", peer),
+            Self::NotMember(peer) => write!(f, "{:?} is not in the cluster", peer),
+            Self::LastMember(peer) => write!(f, "{:?} is the last server in the cluster", peer),
+            Self::EmptyMembership => write!(f, "the cluster cannot be empty"),
+            Self::NotLearner(peer) => write!(f, "{:?} is not a learner", peer),
+            Self::LearnerBehind(peer) => write!(f, "{:?} has not caught up yet", peer),
+        }
+    }
+}
+
+impl std::error::Error for MembershipChangeError {}
+
+impl<Command: ReplicableCommand> Raft<Command> {
+    // A learner can be promoted once it is missing at most this many entries.
+    const PROMOTION_MAX_LAG: Index = 64;
+
+    /// Adds the server `peer` listening at `address` to the cluster.
+    ///
+    /// Returns the index and term of the configuration entry. Only one change
+    /// can be pending at a time: a new change is rejected until the entry of
+    /// the previous one is committed.
+    pub fn add_server(
+        &self,
+        peer: Peer,
+        address: String,
+    ) -> Result<IndexTerm, MembershipChangeError> {
+        let mut rf = self.inner_state.lock().unwrap();
+        self.check_membership_change(&rf)?;
+        if rf.membership.contains(peer) {
+            return Err(MembershipChangeError::AlreadyMember(peer));
+        }
+
+        let mut membership = rf.membership.clone();
+        membership.servers.insert(peer, address);
+        membership.voters.insert(peer);
+        Ok(self.append_membership(&mut rf, membership))
+    }
+
+    /// Adds the server `peer` listening at `address` to the cluster as a
+    /// learner. The learner receives log entries, but does not vote and does
+    /// not count towards commit quorums.
+    pub fn add_learner(
+        &self,
+        peer: Peer,
+        address: String,
+    ) -> Result<IndexTerm, MembershipChangeError> {
+        let mut rf = self.inner_state.lock().unwrap();
+        self.check_membership_change(&rf)?;
+        if rf.membership.contains(peer) {
+            return Err(MembershipChangeError::AlreadyMember(peer));
+        }
+
+        let mut membership = rf.membership.clone();
+        membership.servers.insert(peer, address);
+        Ok(self.append_membership(&mut rf, membership))
+    }
+
+    /// Turns the learner `peer` into a voter. The learner must have caught up
+    /// with the log of the leader, so that the new quorum can commit entries
+    /// right away.
+    pub fn promote_learner(&self, peer: Peer) -> Result<IndexTerm, MembershipChangeError> {
+        let mut rf = self.inner_state.lock().unwrap();
+        self.check_membership_change(&rf)?;
+        if !rf.membership.is_learner(peer) {
+            return Err(MembershipChangeError::NotLearner(peer));
+        }
+        let match_index = rf.cluster.get(&peer).map_or(0, |member| member.match_index);
+        if match_index + Self::PROMOTION_MAX_LAG < rf.log.last_index_term().index {
+            return Err(MembershipChangeError::LearnerBehind(peer));
+        }
+
+        let mut membership = rf.membership.clone();
+        membership.voters.insert(peer);
+        Ok(self.append_membership(&mut rf, membership))
+    }
+
+    /// Removes the server `peer`, voter or learner, from the cluster.
+    ///
+    /// If the leader removes itself, it keeps serving until the configuration
+    /// entry is committed, then steps down.
+    pub fn remove_server(&self, peer: Peer) -> Result<IndexTerm, MembershipChangeError> {
+        let mut rf = self.inner_state.lock().unwrap();
+        self.check_membership_change(&rf)?;
+        if !rf.membership.contains(peer) {
+            return Err(MembershipChangeError::NotMember(peer));
+        }
+        if rf.membership.voters.len() == 1 && rf.membership.voters.contains(&peer) {
+            return Err(MembershipChangeError::LastMember(peer));
+        }
+
+        let mut membership = rf.membership.clone();
+        membership.servers.remove(&peer);
+        membership.voters.remove(&peer);
+        Ok(self.append_membership(&mut rf, membership))
+    }
+
+    /// Replaces the servers in the cluster with `servers`, mapped to their RPC
+    /// addresses. Any number of servers can be added and removed at once.
+    /// All servers in `servers` become voters, including learners.
+    ///
+    /// A joint configuration of the current and the new servers is appended
+    /// first. The new configuration is appended when the joint one is
+    /// committed. Returns the index and term of the joint configuration entry.
+    pub fn change_membership(
+        &self,
+        servers: BTreeMap<Peer, String>,
+    ) -> Result<IndexTerm, MembershipChangeError> {
+        if servers.is_empty() {
+            return Err(MembershipChangeError::EmptyMembership);
+        }
+
+        let mut rf = self.inner_state.lock().unwrap();
+        self.check_membership_change(&rf)?;
+
+        let mut membership = rf.membership.clone();
+        membership.old_voters = Some(membership.voters);
+        membership.voters = servers.keys().copied().collect();
+        for (peer, address) in servers {
+            membership.servers.entry(peer).or_insert(address);
+        }
+        Ok(self.append_membership(&mut rf, membership))
+    }
+
+    /// Returns the servers currently in the cluster.
+    pub fn membership(&self) -> Membership {
+        self.inner_state.lock().unwrap().membership.clone()
+    }
+
+    fn check_membership_change(
+        &self,
+        rf: &RaftState<Command>,
+    ) -> Result<(), MembershipChangeError> {
+        if !rf.is_leader() {
+            return Err(MembershipChangeError::NotLeader);
+        }
+        // The no-op entry of the current term must be committed first, so that
+        // changes started by previous leaders are never lost.
+        if rf.leadership_transfer.is_some()
+            || rf.membership.is_joint()
+            || rf.membership_index > rf.commit_index
+            || rf.log.at(rf.commit_index).term != rf.current_term
+        {
+            return Err(MembershipChangeError::ChangeInProgress);
+        }
+        Ok(())
+    }
+
+    /// Leaves the joint configuration once it is committed, by appending the
+    /// new configuration. Servers that are not in the new configuration stop
+    /// receiving log entries.
+    pub(crate) fn leave_joint_membership(&self, rf: &mut RaftState<Command>) {
+        if !rf.is_leader() || !rf.membership.is_joint() || rf.membership_index > rf.commit_index {
+            return;
+        }
+
+        let voters = rf.membership.voters.clone();
+        let mut membership = rf.membership.clone();
+        membership.servers.retain(|peer, _| voters.contains(peer));
+        membership.old_voters = None;
+        self.append_membership(rf, membership);
+    }
+
+    fn append_membership(&self, rf: &mut RaftState<Command>, membership: Membership) -> IndexTerm {
+        let term = rf.current_term;
+        let index = rf.log.add_membership(term, membership.clone());
+        self.persist_log_signal.notify_one();
+        log::info!("{:?} changing membership to {:?}", self.peer, membership);
+        self.set_membership(rf, membership, index);
+        self.update_commit_index(rf);
+
+        self.sync_log_entries_daemon.trigger(None);
+        IndexTerm { index, term }
+    }
+
+    /// Switches to `membership`, introduced by the log entry at `index`.
+    ///
+    /// Replication progress is tracked for new servers, and RPC clients and
+    /// daemons are started for them. Servers that are no longer in the cluster
+    /// are forgotten.
+    pub(crate) fn set_membership(
+        &self,
+        rf: &mut RaftState<Command>,
+        membership: Membership,
+        index: Index,
+    ) {
+        let log_end = rf.log.end();
+        rf.cluster.retain(|peer, _| membership.contains(*peer));
+        for (peer, address) in membership.servers.iter() {
+            rf.cluster.entry(*peer).or_insert_with(|| ClusterMember {
+                id: peer.0 as u64,
+                address: address.clone(),
+                match_index: 0,
+                next_index: log_end,
+                replication: ReplicationState::Probe,
+                inflight_appends: 0,
+                inflight_bytes: 0,
+                sent_appends: 0,
+                probe_since: 0,
+            });
+        }
+        rf.membership = membership;
+        rf.membership_index = index;
+        if matches!(rf.state, State::Follower | State::Learner) {
+            rf.state = self.follower_state(rf);
+        }
+
+        self.update_remote_peers(&rf.membership);
+    }
+
+    /// Switches to the latest membership in the log, or the membership before
+    /// the first log entry if there is no configuration entry in the log.
+    pub(crate) fn restore_membership(&self, rf: &mut RaftState<Command>) {
+        let (membership, index) = match rf.log.last_membership() {
+            Some((index, membership)) => (membership.clone(), index),
+            None => (rf.base_membership.clone(), rf.log.start()),
+        };
+        self.set_membership(rf, membership, index);
+    }
+
+    /// Persists the membership that is in effect before the first log entry.
+    pub(crate) fn persist_base_membership(&self, rf: &RaftState<Command>) {
+        self.persister
+            .save_membership(encode_membership(&rf.base_membership));
+    }
+
+    fn update_remote_peers(&self, membership: &Membership) {
+        let mut remote_peers = self.remote_peers.lock().unwrap();
+        remote_peers.retain(|peer, remote_peer| {
+            let keep = membership.contains(*peer);
+            if !keep {
+                remote_peer.stop();
+                self.sync_log_entries_daemon.trigger(Some(*peer));
+            }
+            keep
+        });
+
+        for (peer, address) in membership.servers.iter() {
+            if *peer == self.peer || remote_peers.contains_key(peer) {
+                continue;
+            }
+            // Servers of the initial membership have no address, their clients
+            // are passed in when this instance is created.
+            let remote_raft = match self.initial_peers.get(peer) {
+                Some(remote_raft) if address.is_empty() => Ok(remote_raft.clone()),
+                _ => self.connector.connect(address),
+            };
+            match remote_raft {
+                Ok(remote_raft) => {
+                    let remote_peer = RemotePeer::create(*peer, remote_raft);
+                    self.schedule_heartbeats(remote_peer.clone());
+                    self.schedule_sync_log_entries(remote_peer.clone());
+                    remote_peers.insert(*peer, remote_peer);
+                }
+                Err(e) => log::error!("Cannot connect to {:?} at {}: {}", peer, address, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::{
+        kv::storage::KVStorage,
+        raft_config::RaftConfig,
+        test_utils::{wait_for_leader, wait_until, Command, LocalNetwork},
+    };
+
+    fn join(network: &Arc<LocalNetwork>, peer: Peer) -> Raft<Command> {
+        let raft = Raft::join(
+            network.clients(peer, 3),
+            peer,
+            KVStorage::default(),
+            network.connector(peer),
+            RaftConfig::default(),
+            |_, _: Command| {},
+        );
+        network.add(peer, raft.clone());
+        raft
+    }
+
+    // Whether the last configuration of `raft` is committed and not joint.
+    fn settled(raft: &Raft<Command>) -> bool {
+        let rf = raft.inner_state.lock().unwrap();
+        rf.membership_index <= rf.commit_index
+            && !rf.membership.is_joint()
+            && rf.log.at(rf.commit_index).term == rf.current_term
+    }
+
+    #[test]
+    fn servers_are_added_and_removed_one_at_a_time() {
+        let (network, mut rafts) = LocalNetwork::cluster(3);
+        let leader = wait_for_leader(&rafts);
+        assert!(wait_until(Duration::from_secs(1), || settled(
+            &rafts[leader]
+        )));
+        rafts.push(join(&network, Peer(3)));
+
+        // The followers are cut off for a moment, so that the change cannot
+        // be committed before the next one is tried.
+        let followers: Vec<_> = (0..3).filter(|index| *index != leader).collect();
+        for index in &followers {
+            network.disconnect(Peer(*index));
+        }
+        rafts[leader].add_server(Peer(3), "3".to_owned()).unwrap();
+        assert_eq!(
+            rafts[leader].add_server(Peer(4), "4".to_owned()),
+            Err(MembershipChangeError::ChangeInProgress)
+        );
+        for index in &followers {
+            network.reconnect(Peer(*index));
+        }
+        assert!(wait_until(Duration::from_secs(1), || settled(
+            &rafts[leader]
+        )));
+        assert!(wait_until(Duration::from_secs(1), || rafts[3]
+            .membership()
+            .is_voter(Peer(3))));
+        assert_eq!(
+            rafts[leader].add_server(Peer(3), "3".to_owned()),
+            Err(MembershipChangeError::AlreadyMember(Peer(3)))
+        );
+
+        // The leader removes itself, and steps down once that is committed.
+        rafts[leader].remove_server(Peer(leader)).unwrap();
+        assert!(wait_until(Duration::from_secs(2), || !rafts[leader]
+            .get_state()
+            .1));
+        network.disconnect(Peer(leader));
+        let remaining: Vec<_> = (0..4)
+            .filter(|index| *index != leader)
+            .map(|index| rafts[index].clone())
+            .collect();
+        let new_leader = wait_for_leader(&remaining);
+        assert!(!remaining[new_leader].membership().contains(Peer(leader)));
+
+        for raft in rafts {
+            raft.kill().join();
+        }
+    }
+
+    fn membership(
+        voters: &[usize],
+        old_voters: Option<&[usize]>,
+        learners: &[usize],
+    ) -> Membership {
+        let servers = voters
+            .iter()
+            .chain(old_voters.unwrap_or_default())
+            .chain(learners)
+            .map(|index| (Peer(*index), index.to_string()))
+            .collect();
+        let peers = |indexes: &[usize]| indexes.iter().map(|index| Peer(*index)).collect();
+        Membership {
+            servers,
+            voters: peers(voters),
+            old_voters: old_voters.map(peers),
+        }
+    }
+
+    fn votes(indexes: &[usize]) -> HashSet<Peer> {
+        indexes.iter().map(|index| Peer(*index)).collect()
+    }
+
+    #[test]
+    fn learners_do_not_count_towards_quorums() {
+        let membership = membership(&[0, 1, 2], None, &[3, 4]);
+        assert!(membership.is_learner(Peer(3)));
+        assert!(!membership.has_quorum(&votes(&[0, 3, 4])));
+        assert!(membership.has_quorum(&votes(&[0, 1])));
+
+        let matched = |peer: Peer| [5, 3, 1, 9, 9][peer.0];
+        assert_eq!(membership.quorum_index(matched), 3);
+    }
+
+    #[test]
+    fn joint_quorums_need_both_majorities() {
+        let membership = membership(&[2, 3, 4], Some(&[0, 1, 2]), &[]);
+        assert!(membership.is_voter(Peer(0)));
+        assert!(membership.is_voter(Peer(4)));
+        // A majority of the new voters only, then of the old voters only.
+        assert!(!membership.has_quorum(&votes(&[2, 3, 4])));
+        assert!(!membership.has_quorum(&votes(&[0, 1, 2])));
+        assert!(membership.has_quorum(&votes(&[1, 2, 3])));
+        assert!(membership.has_quorum(&votes(&[0, 1, 3, 4])));
+
+        // The old voters are behind.
+        let matched = |peer: Peer| [1, 2, 8, 9, 9][peer.0];
+        assert_eq!(membership.quorum_index(matched), 2);
+        // The new voters are behind.
+        let matched = |peer: Peer| [9, 9, 8, 1, 2][peer.0];
+        assert_eq!(membership.quorum_index(matched), 2);
+        let matched = |peer: Peer| [7, 1, 6, 5, 1][peer.0];
+        assert_eq!(membership.quorum_index(matched), 5);
+    }
+
+    #[test]
+    fn membership_changes_leave_the_joint_configuration() {
+        let (network, mut rafts) = LocalNetwork::cluster(3);
+        let leader = wait_for_leader(&rafts);
+        assert!(wait_until(Duration::from_secs(1), || settled(
+            &rafts[leader]
+        )));
+        rafts.push(join(&network, Peer(3)));
+        rafts.push(join(&network, Peer(4)));
+
+        // Replace both followers at once.
+        let servers: BTreeMap<_, _> = [leader, 3, 4]
+            .into_iter()
+            .map(|index| (Peer(index), index.to_string()))
+            .collect();
+        let followers: Vec<_> = (0..3).filter(|index| *index != leader).collect();
+        for index in &followers {
+            network.disconnect(Peer(*index));
+        }
+        rafts[leader].change_membership(servers.clone()).unwrap();
+        assert!(rafts[leader].membership().is_joint());
+        assert_eq!(
+            rafts[leader].change_membership(servers.clone()),
+            Err(MembershipChangeError::ChangeInProgress)
+        );
+        // The joint configuration needs a majority of the old servers too.
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(rafts[leader].membership().is_joint());
+        for index in &followers {
+            network.reconnect(Peer(*index));
+        }
+        assert!(wait_until(Duration::from_secs(2), || settled(
+            &rafts[leader]
+        )));
+        let membership = rafts[leader].membership();
+        assert_eq!(membership.voters, servers.keys().copied().collect());
+        assert!(membership.servers.keys().eq(servers.keys()));
+
+        // The removed servers are not needed for a quorum any more.
+        for index in &followers {
+            network.disconnect(Peer(*index));
+        }
+        let index = rafts[leader].start("after".to_owned()).unwrap().index;
+        assert!(wait_until(Duration::from_secs(1), || {
+            let rf = rafts[leader].inner_state.lock().unwrap();
+            rf.commit_index >= index
+        }));
+
+        for raft in rafts {
+            raft.kill().join();
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    log_array::{Index, LogEntry},
+    raft_state::{Peer, Term},
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequestVoteArgs {
+    pub term: Term,
+    pub candidate_id: Peer,
+    pub last_log_index: Index,
+    pub last_log_term: Term,
+    // The election was started at the request of the leader, see TimeoutNowArgs
+    pub leadership_transfer: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequestVoteReply {
+    pub term: Term,
+    pub vote_granted: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppendEntriesArgs<Command> {
+    pub term: Term,
+    pub leader_id: Peer,
+    pub prev_log_index: Index,
+    pub prev_log_term: Term,
+    pub entries: Vec<LogEntry<Command>>,
+    pub leader_commit: Index,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppendEntriesReply {
+    pub term: Term,
+    pub success: bool,
+    /// Set when the request is rejected because the log of the receiver does
+    /// not have the entry at `prev_log_index`.
+    pub conflict: Option<ConflictHint>,
+}
+
+/// Tells the leader where the log of a follower diverges from its own, so
+/// that it can skip the entries of a whole term at a time, instead of going
+/// back one entry per request.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ConflictHint {
+    /// Term of the entry of the follower at `prev_log_index`, or `None` if the
+    /// log of the follower ends before that.
+    pub term: Option<Term>,
+    /// First index of the entries of `term` in the log of the follower, or
+    /// the end of its log if `term` is `None`.
+    pub index: Index,
+}
+
+/// Sent by a leader that is handing over leadership. The receiver starts an
+/// election right away, without waiting for its election timer to fire.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimeoutNowArgs {
+    pub term: Term,
+    pub leader_id: Peer,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimeoutNowReply {
+    pub term: Term,
+}
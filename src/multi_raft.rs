@@ -0,0 +1,129 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use tokio::net::TcpListener;
+
+use crate::{
+    apply_command::ApplyCommandFnMut,
+    durio::{HostClients, LazyRaftServiceConnector, RaftService},
+    raft::{Raft, RaftJoinHandle},
+    raft_builder::{BuildError, RaftBuilder},
+    raft_config::RaftConfig,
+    raft_state::GroupId,
+    storage::RaftStorageTrait,
+};
+
+/// Hosts many Raft groups in one process.
+///
+/// All groups run on one runtime. Each remote host gets one connection, shared
+/// by the RPCs of all groups, and heartbeats of different groups to the same
+/// host are sent in one frame. Incoming RPCs are dispatched to the group they
+/// are tagged with.
+#[derive(Clone)]
+pub struct MultiRaftHost {
+    runtime: tokio::runtime::Handle,
+    hosts: Arc<HostClients>,
+    service: RaftService,
+}
+
+impl MultiRaftHost {
+    /// Creates a host whose groups run on `runtime`.
+    pub fn new(runtime: tokio::runtime::Handle) -> Self {
+        MultiRaftHost {
+            runtime,
+            hosts: Arc::new(HostClients::default()),
+            service: RaftService::default(),
+        }
+    }
+
+    /// Creates the instance of `group` on this host, and serves it. Fails
+    /// with `BuildError::GroupExists` if the group is already on this host.
+    ///
+    /// `peers` are the hosts of the initial servers of the group, this host
+    /// at `peer_index` included. A host may be the peer of many groups.
+    pub fn create_group(
+        &self,
+        group: GroupId,
+        peers: &[SocketAddr],
+        peer_index: usize,
+        storage: impl RaftStorageTrait,
+        config: RaftConfig,
+        apply_command: impl ApplyCommandFnMut<Vec<u8>>,
+    ) -> Result<Raft<Vec<u8>>, BuildError> {
+        if self.service.group(group).is_some() {
+            return Err(BuildError::GroupExists(group));
+        }
+        let peers = peers
+            .iter()
+            .map(|socket_addr| self.hosts.client(group, *socket_addr))
+            .collect();
+        let connector = LazyRaftServiceConnector::new(group, self.hosts.clone());
+        let raft = RaftBuilder::new(peers, peer_index, storage, connector)
+            .group(group)
+            .config(config)
+            .runtime(self.runtime.clone())
+            .build(apply_command)?;
+        if let Err(e) = self.service.add_group(raft.clone()) {
+            // Created at the same time by another caller.
+            drop(raft.kill());
+            return Err(e);
+        }
+        Ok(raft)
+    }
+
+    pub fn runtime(&self) -> &tokio::runtime::Handle {
+        &self.runtime
+    }
+
+    pub fn group(&self, group: GroupId) -> Option<Raft<Vec<u8>>> {
+        self.service.group(group)
+    }
+
+    /// Stops serving `group` and kills its instance. Returns `None` if the
+    /// group is not on this host.
+    pub fn remove_group(&self, group: GroupId) -> Option<RaftJoinHandle> {
+        self.service.remove_group(group).map(Raft::kill)
+    }
+
+    /// Serves the RPCs of all groups on the connections accepted by
+    /// `listener`, until accepting fails.
+    pub async fn run(&self, listener: TcpListener) -> io::Result<()> {
+        self.service.clone().run(listener).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::storage::KVStorage;
+
+    #[test]
+    fn groups_are_created_once() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let host = MultiRaftHost::new(runtime.handle().clone());
+        let peers = ["127.0.0.1:1".parse().unwrap()];
+        let create = |group| {
+            host.create_group(
+                group,
+                &peers,
+                0,
+                KVStorage::default(),
+                RaftConfig::default(),
+                |_, _: Vec<u8>| {},
+            )
+        };
+
+        create(GroupId(1)).unwrap();
+        assert!(matches!(
+            create(GroupId(1)),
+            Err(BuildError::GroupExists(GroupId(1)))
+        ));
+        create(GroupId(2)).unwrap();
+
+        // The group can be created again once removed.
+        host.remove_group(GroupId(1)).unwrap().join();
+        create(GroupId(1)).unwrap();
+        for group in [GroupId(1), GroupId(2)] {
+            host.remove_group(group).unwrap().join();
+        }
+    }
+}
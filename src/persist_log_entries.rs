@@ -0,0 +1,120 @@
+use tokio::sync::oneshot;
+
+use crate::{
+    log_array::Index,
+    raft::{Raft, ReplicableCommand},
+    raft_state::RaftState,
+};
+
+impl<Command: ReplicableCommand> Raft<Command> {
+    /// Starts a task that hands the entries added by the leader to storage.
+    ///
+    /// The leader sends new entries to followers while they are being
+    /// written, and counts itself towards the quorum of an entry only once it
+    /// is written, see `update_commit_index()`. The task is woken up each
+    /// time the leader adds entries.
+    pub(crate) fn run_persist_log_entries_daemon(&self) {
+        let this = self.clone();
+        self.daemons.spawn(&self.thread_pool, async move {
+            loop {
+                let pending = {
+                    let mut rf = this.inner_state.lock().unwrap();
+                    if Self::has_pending_entries(&rf) {
+                        let truncations = rf.truncations;
+                        let entries = rf.log.between(rf.saved_index + 1, rf.log.end()).to_vec();
+                        rf.saved_index = rf.log.last_index_term().index;
+                        // Taken before `inner_state` is released, so that
+                        // stepping down waits for these entries to be handed
+                        // to storage.
+                        let persist_log_guard = this.persist_log_lock.lock().unwrap();
+                        drop(rf);
+
+                        let durable = this.persister.append_entries(&entries);
+                        drop(persist_log_guard);
+                        Some((durable, truncations))
+                    } else {
+                        None
+                    }
+                };
+                match pending {
+                    Some((durable, truncations)) => {
+                        this.watch_durable_index(durable, truncations);
+                    }
+                    None => this.persist_log_signal.notified().await,
+                }
+            }
+        })
+    }
+
+    fn has_pending_entries(rf: &RaftState<Command>) -> bool {
+        rf.is_leader() && rf.saved_index < rf.log.last_index_term().index
+    }
+
+    /// Hands the entries the leader has added to storage, if the daemon has
+    /// not. Called before we stop leading, after which entries of another
+    /// leader can replace ours.
+    pub(crate) fn persist_pending_entries(&self, rf: &mut RaftState<Command>) {
+        let last_log_index = rf.log.last_index_term().index;
+        if rf.saved_index >= last_log_index {
+            // The daemon may still be handing them over.
+            drop(self.persist_log_lock.lock().unwrap());
+            return;
+        }
+
+        let _persist_log_guard = self.persist_log_lock.lock().unwrap();
+        let durable = self
+            .persister
+            .append_entries(rf.log.between(rf.saved_index + 1, rf.log.end()));
+        rf.saved_index = last_log_index;
+        self.watch_durable_index(durable, rf.truncations);
+    }
+
+    /// Hands the entries of `rf.log` after `rf.saved_index` to storage.
+    /// Returns when they are reported written, see `watch_durable_index()`.
+    pub(crate) fn save_log_entries(&self, rf: &mut RaftState<Command>) -> oneshot::Receiver<bool> {
+        let durable = self
+            .persister
+            .append_entries(rf.log.between(rf.saved_index + 1, rf.log.end()));
+        rf.saved_index = rf.log.last_index_term().index;
+        self.watch_durable_index(durable, rf.truncations)
+    }
+
+    /// Wakes up the replies waiting for entries to be written.
+    pub(crate) fn signal_durable(&self, rf: &RaftState<Command>) {
+        self.durable_signal
+            .send_replace((rf.truncations, rf.durable_index));
+    }
+
+    /// Moves `durable_index` forward once storage reports entries written,
+    /// and commits the entries that are then written by a quorum.
+    ///
+    /// The receiver returned gets true once the entries count as written, or
+    /// false if the log was truncated after they were handed to storage, i.e.
+    /// `truncations` is out of date. It is dropped if the entries cannot be
+    /// written.
+    pub(crate) fn watch_durable_index(
+        &self,
+        durable: oneshot::Receiver<Index>,
+        truncations: u64,
+    ) -> oneshot::Receiver<bool> {
+        let (sender, receiver) = oneshot::channel();
+        let this = self.clone();
+        self.daemons.spawn(&self.thread_pool, async move {
+            let Ok(index) = durable.await else {
+                log::error!("Cannot write log entries of {:?}", this.peer);
+                return;
+            };
+            let mut rf = this.inner_state.lock().unwrap();
+            let moved = rf.truncations == truncations;
+            if moved && index > rf.durable_index {
+                rf.durable_index = index;
+                this.signal_durable(&rf);
+                if rf.is_leader() {
+                    this.update_commit_index(&mut rf);
+                }
+            }
+            let _ = sender.send(moved);
+        });
+        receiver
+    }
+}
@@ -1,16 +1,36 @@
 use crate::{
+    apply_command::ApplyCommandFnMut,
+    daemons::Daemons,
     election::ElectionState,
-    heartbeat::{HeartbeatsDaemon, HEARTBEAT_INTERVAL},
-    log_array::{Index, LogEntry},
-    raft_state::{Peer, RaftState},
-    remote::{remote_peer::RemotePeer, remote_raft::RemoteRaft},
-    state_machine::StateMachine,
-    storage::{RaftStoragePersisterTrait, RaftStorageTrait},
+    heartbeat::HeartbeatsDaemon,
+    log_array::{Index, IndexTerm, LogEntry},
+    raft_builder::RaftBuilder,
+    raft_config::RaftConfig,
+    raft_state::{GroupId, Peer, RaftState, State, Term},
+    remote::{
+        remote_peer::RemotePeer,
+        remote_raft::{RemoteRaft, RemoteRaftConnector},
+    },
+    storage::{encode_voted_for, RaftStoragePersisterTrait, RaftStorageTrait},
+    sync_log_entries::SyncLogEntriesDaemon,
 };
+use futures_util::{future::BoxFuture, FutureExt};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::Serialize;
 use std::{
-    sync::{atomic::AtomicBool, Arc, Mutex},
-    time::Duration,
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
+use tokio::sync::{watch, Notify};
+
+/// Everything a command needs to be replicated and persisted by Raft.
+pub trait ReplicableCommand: 'static + Clone + Send + Serialize + DeserializeOwned {}
+
+impl<C> ReplicableCommand for C where C: 'static + Clone + Send + Serialize + DeserializeOwned {}
 
 #[derive(Debug, Default, Clone)]
 pub struct ClusterMember {
@@ -22,9 +42,89 @@ pub struct ClusterMember {
 
     // Index of the next log entry to send
     pub next_index: Index,
+
+    // How entries are sent to the peer, see `ReplicationState`
+    pub replication: ReplicationState,
+
+    // `AppendEntries` requests sent to the peer without a reply, and the
+    // size of the entries they carry
+    pub inflight_appends: usize,
+    pub inflight_bytes: usize,
+
+    // Sequence number of the last `AppendEntries` request sent to the peer
+    pub sent_appends: u64,
+
+    // `sent_appends` when the peer last moved to `Probe`
+    pub probe_since: u64,
+}
+
+impl ClusterMember {
+    /// Returns true if no more `AppendEntries` can be sent to the peer until
+    /// replies arrive: a probe is in flight, or the limits of `config` on
+    /// requests in flight are reached. Heartbeats are still sent.
+    pub fn is_paused(&self, config: &RaftConfig) -> bool {
+        match self.replication {
+            ReplicationState::Probe => self.inflight_appends > 0,
+            ReplicationState::Replicate => {
+                self.inflight_appends >= config.max_inflight_appends
+                    || self.inflight_bytes >= config.max_inflight_bytes
+            }
+        }
+    }
+
+    /// Moves the peer to `Probe`. Replies to requests sent before cannot
+    /// move it back to `Replicate`, see `record_reply()`.
+    pub fn probe(&mut self) {
+        self.replication = ReplicationState::Probe;
+        self.probe_since = self.sent_appends;
+    }
+
+    /// Records the reply to the request with the sequence number `sequence`.
+    /// The peer moves to `Replicate` if the request is accepted, and to
+    /// `Probe` if it is rejected.
+    ///
+    /// Replies can arrive out of order. Those to requests sent before the
+    /// peer last moved to `Probe` are out of date, and leave the state as it
+    /// is.
+    pub fn record_reply(&mut self, sequence: u64, success: bool) {
+        if sequence <= self.probe_since {
+            return;
+        }
+        if success {
+            self.replication = ReplicationState::Replicate;
+        } else {
+            self.probe();
+        }
+    }
+}
+
+/// How the leader sends entries to a peer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicationState {
+    /// It is not known where our log and the log of the peer match, e.g.
+    /// after a rejected or failed `AppendEntries`. Requests are sent one at a
+    /// time.
+    #[default]
+    Probe,
+    /// The peer accepted our last request. Requests are sent without waiting
+    /// for replies, up to the limits of the config.
+    Replicate,
+}
+
+/// The state of a Raft instance, as reported by `Raft::status()`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RaftStatus {
+    pub group: GroupId,
+    pub peer: Peer,
+    pub term: Term,
+    pub role: &'static str,
+    pub leader: Option<Peer>,
+    pub last_log_index: Index,
+    pub commit_index: Index,
+    pub last_applied: Index,
 }
 
-#[allow(dead_code)]
 pub struct Raft<Command> {
     pub(crate) inner_state: Arc<Mutex<RaftState<Command>>>,
     // ----------- PERSISTENT STATE -----------
@@ -71,80 +171,228 @@ pub struct Raft<Command> {
     // Servers in the cluster, including this one
     // The index of current server
     pub(crate) election: Arc<ElectionState>,
+    // Wakes up the apply command daemon when the commit index moves forward
+    pub(crate) apply_command_signal: Arc<Notify>,
+    // Wakes up the persist log entries daemon when the leader adds entries
+    pub(crate) persist_log_signal: Arc<Notify>,
+    // Held while the leader writes entries without holding `inner_state`
+    pub(crate) persist_log_lock: Arc<Mutex<()>>,
+    // `truncations` and `durable_index` of `inner_state`, watched by replies
+    // that wait for entries to be written
+    pub(crate) durable_signal: Arc<watch::Sender<(u64, Index)>>,
+    // `term_vote_saves` of `inner_state` that are written
+    pub(crate) term_vote_signal: Arc<watch::Sender<u64>>,
     pub(crate) persister: Arc<dyn RaftStoragePersisterTrait<LogEntry<Command>>>,
-    pub(crate) peers: Vec<Peer>,
+    pub(crate) remote_peers: Arc<Mutex<HashMap<Peer, RemotePeer<Command, Peer>>>>,
+    pub(crate) initial_peers: Arc<HashMap<Peer, Arc<dyn RemoteRaft<Command>>>>,
+    pub(crate) connector: Arc<dyn RemoteRaftConnector<Command>>,
     pub(crate) peer: Peer,
+    pub(crate) group: GroupId,
     pub(crate) heartbeats_daemon: HeartbeatsDaemon,
+    pub(crate) sync_log_entries_daemon: SyncLogEntriesDaemon,
     pub(crate) thread_pool: tokio::runtime::Handle,
+    // Tasks of this instance, waited for by `RaftJoinHandle`
+    pub(crate) daemons: Daemons,
+    pub(crate) config: Arc<RaftConfig>,
     pub(crate) keep_running: Arc<AtomicBool>,
-    // join_handle: Arc<Mutex<Option<RaftJoinHandle>>>,
+    pub(crate) join_handle: Arc<Mutex<Option<RaftJoinHandle>>>,
 }
 
-impl<Command> Raft<Command> {
+impl<Command: ReplicableCommand> Raft<Command> {
+    /// Creates an instance that is one of the initial servers `peers` of a
+    /// cluster.
+    ///
+    /// Panics if the instance cannot be created, see `RaftBuilder` for the
+    /// errors and more options.
     pub fn new(
         peers: Vec<impl RemoteRaft<Command>>,
         peer_index: usize,
         storage: impl RaftStorageTrait,
+        connector: impl RemoteRaftConnector<Command>,
+        config: RaftConfig,
+        apply_command: impl ApplyCommandFnMut<Command>,
     ) -> Self {
-        let peer_size = peers.len();
-        assert!(
-            peer_size > peer_index,
-            "Peer Index should be smaller than number of peers"
-        );
+        RaftBuilder::new(peers, peer_index, storage, connector)
+            .config(config)
+            .build(apply_command)
+            .unwrap_or_else(|e| panic!("Creating Raft instance should not fail: {}", e))
+    }
 
-        let mut raft_state = RaftState::create();
-        // if let Ok(stored_state) = storage.read_state() {
-        //     // TODO: Yet to be developed
-        // }
-
-        let inner_state = Arc::new(Mutex::new(raft_state));
-        let election = Arc::new(ElectionState::create());
-        election.reset_election_timer();
-
-        let persister = storage.persister();
-
-        // let remote_peers = peers
-        //     .into_iter()
-        //     .enumerate()
-        //     .map(|(index, remote_raft)| RemotePeer::create(Peer(index)))
-        //     .collect();
-
-        let peers = (0..peer_size)
-            .filter(|p| *p != peer_index)
-            .map(Peer)
-            .collect();
-
-        println!("peers: {:?}", peers);
-
-        let thread_pool = tokio::runtime::Builder::new_multi_thread()
-            .enable_io()
-            .enable_time()
-            .thread_name(format!("raft-{}", peer_index))
-            .worker_threads(peer_size)
-            .build()
-            .expect("Creating thread pool should not fail");
-
-        let this = Raft {
-            peers,
-            peer: Peer(peer_index),
-            inner_state,
-            election,
-            persister,
-            heartbeats_daemon: HeartbeatsDaemon::create(),
-            thread_pool: thread_pool.handle().clone(),
-            keep_running: Arc::new(AtomicBool::new(true)),
-            // join_handle: Arc::new(Mutex::new(None)),
-        };
+    /// Creates an instance that joins an existing cluster as `peer`. `peers`
+    /// are the initial servers of the cluster, as passed to `new()` when the
+    /// cluster was created.
+    ///
+    /// The instance is not part of the cluster and never starts an election
+    /// by itself. It learns the servers in the cluster from the log, after the
+    /// leader adds it with `add_server()`.
+    ///
+    /// Panics if the instance cannot be created, like `new()`.
+    pub fn join(
+        peers: Vec<impl RemoteRaft<Command>>,
+        peer: Peer,
+        storage: impl RaftStorageTrait,
+        connector: impl RemoteRaftConnector<Command>,
+        config: RaftConfig,
+        apply_command: impl ApplyCommandFnMut<Command>,
+    ) -> Self {
+        RaftBuilder::join(peers, peer, storage, connector)
+            .config(config)
+            .build(apply_command)
+            .unwrap_or_else(|e| panic!("Creating Raft instance should not fail: {}", e))
+    }
 
-        this.schedule_heartbeats(HEARTBEAT_INTERVAL);
-        // this.join_handle
-        //     .lock()
-        //     .unwrap()
-        //     .replace(RaftJoinHandle { thread_pool });
+    /// Proposes a new command to be replicated. Returns the index and term of
+    /// the new log entry, or `None` if this instance is not the leader or is
+    /// transferring leadership to another peer.
+    pub fn start(&self, command: Command) -> Option<IndexTerm> {
+        let mut rf = self.inner_state.lock().unwrap();
+        if !rf.is_leader() || rf.leadership_transfer.is_some() {
+            return None;
+        }
+
+        let term = rf.current_term;
+        let index = rf.log.add_command(term, command);
+        self.persist_log_signal.notify_one();
+
+        self.sync_log_entries_daemon
+            .add_to_batch(&self.daemons, &self.thread_pool);
+        Some(IndexTerm { index, term })
+    }
+
+    /// Stops this instance and all its clones. No election is started, no
+    /// heartbeat or log entry is sent, and no command is applied afterwards.
+    ///
+    /// Returns a handle to wait for the daemons of this instance to finish.
+    /// Only the first call gets the daemons, later calls get an empty handle.
+    pub fn kill(self) -> RaftJoinHandle {
+        self.keep_running.store(false, Ordering::Release);
+        for remote_peer in self.remote_peers.lock().unwrap().values() {
+            remote_peer.stop();
+        }
+        // Cancels the tasks.
+        self.daemons.stop();
+        self.election.stop_election_timer();
+        self.heartbeats_daemon.trigger(true);
+        self.sync_log_entries_daemon.trigger(None);
+
+        self.join_handle.lock().unwrap().take().unwrap_or_default()
+    }
 
-        // this.thread_pool.block_on(async {});
+    /// Returns the current term and whether this instance is the leader.
+    pub fn get_state(&self) -> (Term, bool) {
+        let rf = self.inner_state.lock().unwrap();
+        (rf.current_term, rf.is_leader())
+    }
 
-        this
+    /// Returns the group this instance belongs to.
+    pub fn group(&self) -> GroupId {
+        self.group
+    }
+
+    /// Returns the leader this instance last heard from, or itself if it is
+    /// the leader.
+    pub fn leader(&self) -> Option<Peer> {
+        self.inner_state.lock().unwrap().leader_id
+    }
+
+    /// Returns how far each peer is replicated, if this instance is the
+    /// leader. Peers that lag behind show up as paused, or in probe state.
+    pub fn replication(&self) -> Option<BTreeMap<Peer, ClusterMember>> {
+        let rf = self.inner_state.lock().unwrap();
+        if !rf.is_leader() {
+            return None;
+        }
+        let mut cluster = rf.cluster.clone();
+        cluster.remove(&self.peer);
+        Some(cluster)
+    }
+
+    /// Returns what this instance knows about itself and the cluster.
+    pub fn status(&self) -> RaftStatus {
+        let rf = self.inner_state.lock().unwrap();
+        RaftStatus {
+            group: self.group,
+            peer: self.peer,
+            term: rf.current_term,
+            role: match rf.state {
+                State::Leader => "leader",
+                State::Follower => "follower",
+                State::Candidate => "candidate",
+                State::Learner => "learner",
+            },
+            leader: rf.leader_id,
+            last_log_index: rf.log.last_index_term().index,
+            commit_index: rf.commit_index,
+            last_applied: rf.last_applied,
+        }
+    }
+
+    /// Moves to `term` as a follower. Any vote cast in a previous term and
+    /// any leadership transfer in progress are dropped.
+    pub(crate) fn step_down(&self, rf: &mut RaftState<Command>, term: Term) {
+        // Entries of another leader may replace ours from now on.
+        self.persist_pending_entries(rf);
+        if term > rf.current_term {
+            rf.current_term = term;
+            rf.voted_for = None;
+            rf.leader_id = None;
+            rf.leader_contact = None;
+            self.persist_term_vote(rf);
+        }
+        rf.state = self.follower_state(rf);
+        rf.leadership_transfer = None;
+        self.election.reset_election_timer();
+    }
+
+    /// The state to be in when not leading and not running for election:
+    /// learner if we are a non-voting member of the cluster, follower
+    /// otherwise.
+    pub(crate) fn follower_state(&self, rf: &RaftState<Command>) -> State {
+        if rf.membership.is_learner(self.peer) {
+            State::Learner
+        } else {
+            State::Follower
+        }
+    }
+
+    /// Hands the term and vote to storage without waiting for them to be
+    /// written. Replies that depend on them wait, see `wait_term_vote()`.
+    pub(crate) fn persist_term_vote(&self, rf: &mut RaftState<Command>) {
+        rf.term_vote_saves += 1;
+        let saves = rf.term_vote_saves;
+        let durable = self
+            .persister
+            .save_term_vote(rf.current_term, encode_voted_for(&rf.voted_for));
+        let this = self.clone();
+        self.daemons.spawn(&self.thread_pool, async move {
+            if durable.await.is_err() {
+                log::error!("Cannot write the term and vote of {:?}", this.peer);
+                return;
+            }
+            this.term_vote_signal.send_if_modified(|durable| {
+                let moved = *durable < saves;
+                *durable = saves.max(*durable);
+                moved
+            });
+        });
+    }
+
+    /// Returns a future that is ready once the term and vote in `rf` are
+    /// written, or `None` if they already are. Nothing that depends on them
+    /// can be sent to other servers before: a vote would be forgotten in a
+    /// crash, and could be given twice.
+    pub(crate) fn wait_term_vote(&self, rf: &RaftState<Command>) -> Option<BoxFuture<'static, ()>> {
+        let saves = rf.term_vote_saves;
+        if *self.term_vote_signal.borrow() >= saves {
+            return None;
+        }
+        let mut durable = self.term_vote_signal.subscribe();
+        Some(
+            async move {
+                let _ = durable.wait_for(|durable| *durable >= saves).await;
+            }
+            .boxed(),
+        )
     }
 
     // pub fn start(&mut self) {}
@@ -234,17 +482,194 @@ impl<Command> Raft<Command> {
     // }
 }
 
-// #[must_use]
-// #[derive(Debug)]
-// pub struct RaftJoinHandle {
-//     thread_pool: tokio::runtime::Runtime,
-// }
+impl<Command> Clone for Raft<Command> {
+    fn clone(&self) -> Self {
+        Raft {
+            inner_state: self.inner_state.clone(),
+            election: self.election.clone(),
+            apply_command_signal: self.apply_command_signal.clone(),
+            persist_log_signal: self.persist_log_signal.clone(),
+            persist_log_lock: self.persist_log_lock.clone(),
+            durable_signal: self.durable_signal.clone(),
+            term_vote_signal: self.term_vote_signal.clone(),
+            persister: self.persister.clone(),
+            remote_peers: self.remote_peers.clone(),
+            initial_peers: self.initial_peers.clone(),
+            connector: self.connector.clone(),
+            peer: self.peer,
+            group: self.group,
+            heartbeats_daemon: self.heartbeats_daemon.clone(),
+            sync_log_entries_daemon: self.sync_log_entries_daemon.clone(),
+            thread_pool: self.thread_pool.clone(),
+            daemons: self.daemons.clone(),
+            config: self.config.clone(),
+            keep_running: self.keep_running.clone(),
+            join_handle: self.join_handle.clone(),
+        }
+    }
+}
+
+/// Waits for the daemons of a killed Raft instance to finish, see
+/// `Raft::kill()`.
+#[must_use]
+#[derive(Default)]
+pub struct RaftJoinHandle {
+    // `None` if the instance runs on a runtime given to `RaftBuilder`
+    pub(crate) thread_pool: Option<tokio::runtime::Runtime>,
+    // `None` in the handle of an instance killed before
+    pub(crate) daemons: Option<Daemons>,
+    pub(crate) close: Option<Box<dyn FnOnce() + Send>>,
+    pub(crate) timeout: Duration,
+}
+
+impl RaftJoinHandle {
+    /// Waits for the tasks of the instance to exit, then closes the
+    /// persister, which waits for what it saved to be durable. Tasks are
+    /// waited for on any runtime, including one given to `RaftBuilder`.
+    /// Daemons that do not exit within the timeout are left behind, with a
+    /// warning.
+    ///
+    /// Must not be called from an async context, since it blocks.
+    pub fn join(mut self) {
+        let deadline = Instant::now() + self.timeout;
+        if let Some(daemons) = self.daemons.take() {
+            let running = daemons.wait(deadline);
+            if running > 0 {
+                log::warn!("{} Raft daemons did not exit in time", running);
+            }
+        }
+        if let Some(thread_pool) = self.thread_pool.take() {
+            thread_pool.shutdown_timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        if let Some(close) = self.close.take() {
+            close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::storage::KVStorage,
+        messages::{AppendEntriesArgs, RequestVoteArgs},
+        test_utils::{wait_for_leader, LocalNetwork},
+    };
+
+    #[test]
+    fn paused_peers_wait_for_replies() {
+        let config = RaftConfig {
+            max_inflight_appends: 2,
+            max_inflight_bytes: 100,
+            ..Default::default()
+        };
+        let mut member = ClusterMember::default();
+        assert!(!member.is_paused(&config));
+        // Probes are sent one at a time.
+        member.inflight_appends = 1;
+        assert!(member.is_paused(&config));
+
+        member.replication = ReplicationState::Replicate;
+        assert!(!member.is_paused(&config));
+        member.inflight_appends = 2;
+        assert!(member.is_paused(&config));
+        member.inflight_appends = 1;
+        member.inflight_bytes = 100;
+        assert!(member.is_paused(&config));
+    }
 
-// impl RaftJoinHandle {
-//     const SHUTDOWN_TIMEOUT: std::time::Duration =
-//         Duration::from_millis(HEARTBEAT_INTERVAL.as_millis() as u64 * 2);
+    #[test]
+    fn out_of_date_replies_leave_the_replication_state() {
+        let mut member = ClusterMember {
+            sent_appends: 3,
+            ..Default::default()
+        };
+        member.record_reply(1, true);
+        assert_eq!(member.replication, ReplicationState::Replicate);
+
+        // The second request is rejected, the reply to the third arrives
+        // after.
+        member.record_reply(2, false);
+        assert_eq!(member.replication, ReplicationState::Probe);
+        member.record_reply(3, true);
+        assert_eq!(member.replication, ReplicationState::Probe);
+
+        member.sent_appends += 1;
+        member.record_reply(4, true);
+        assert_eq!(member.replication, ReplicationState::Replicate);
+        // Nor can they move the peer back to `Probe`.
+        member.record_reply(3, false);
+        assert_eq!(member.replication, ReplicationState::Replicate);
+    }
 
-//     pub fn join(self) {
-//         self.thread_pool.shutdown_timeout(Self::SHUTDOWN_TIMEOUT);
-//     }
-// }
+    #[test]
+    fn join_waits_for_all_daemons() {
+        let (_network, rafts) = LocalNetwork::cluster(3);
+        let leader = wait_for_leader(&rafts);
+        rafts[leader].start("command".to_owned()).unwrap();
+
+        let daemons: Vec<_> = rafts.iter().map(|raft| raft.daemons.clone()).collect();
+        for raft in rafts.iter() {
+            raft.clone().kill().join();
+        }
+        for daemons in daemons {
+            assert_eq!(daemons.wait(Instant::now()), 0);
+        }
+    }
+
+    #[test]
+    fn join_waits_for_tasks_on_a_given_runtime() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let network = Arc::new(LocalNetwork::default());
+        let raft = RaftBuilder::new(
+            network.clients(Peer(0), 1),
+            0,
+            KVStorage::default(),
+            network.connector(Peer(0)),
+        )
+        .runtime(runtime.handle().clone())
+        .build(|_, _: String| {})
+        .unwrap();
+        wait_for_leader(std::slice::from_ref(&raft));
+
+        let daemons = raft.daemons.clone();
+        raft.kill().join();
+        assert_eq!(daemons.wait(Instant::now()), 0);
+        // The runtime is still usable.
+        assert_eq!(runtime.block_on(async { 1 }), 1);
+    }
+
+    #[test]
+    fn killed_instance_rejects_rpcs() {
+        let (_network, rafts) = LocalNetwork::cluster(3);
+        let leader = wait_for_leader(&rafts);
+        let follower = rafts[(leader + 1) % 3].clone();
+        follower.clone().kill().join();
+        let term = follower.get_state().0;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let reply = runtime.block_on(follower.process_request_vote(RequestVoteArgs {
+            term: Term(term.0 + 1),
+            candidate_id: Peer(leader),
+            last_log_index: 100,
+            last_log_term: Term(term.0 + 1),
+            leadership_transfer: true,
+        }));
+        assert!(!reply.vote_granted);
+
+        let reply = runtime.block_on(
+            follower
+                .process_append_entries(AppendEntriesArgs {
+                    term,
+                    leader_id: Peer(leader),
+                    prev_log_index: 0,
+                    prev_log_term: Term(0),
+                    entries: vec![],
+                    leader_commit: 0,
+                })
+                .wait(),
+        );
+        assert!(!reply.success);
+        assert_eq!(follower.get_state().0, term);
+    }
+}
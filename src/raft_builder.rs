@@ -0,0 +1,446 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
+
+use tokio::sync::{watch, Notify};
+
+use crate::{
+    apply_command::ApplyCommandFnMut,
+    daemons::Daemons,
+    election::ElectionState,
+    heartbeat::HeartbeatsDaemon,
+    membership::Membership,
+    raft::{Raft, RaftJoinHandle, ReplicableCommand},
+    raft_config::{RaftConfig, RaftConfigError},
+    raft_state::{GroupId, Peer, RaftState},
+    remote::remote_raft::{RemoteRaft, RemoteRaftConnector},
+    storage::{decode_log_entry, RaftStorageTrait},
+    sync_log_entries::SyncLogEntriesDaemon,
+};
+
+/// Why a Raft instance cannot be created.
+#[derive(Debug)]
+pub enum BuildError {
+    /// The index of the instance is not the index of one of the peers.
+    PeerIndexOutOfRange {
+        peer_index: usize,
+        peer_count: usize,
+    },
+    NoPeers,
+    InvalidConfig(RaftConfigError),
+    /// The state saved before cannot be read from storage.
+    Storage(io::Error),
+    /// The state saved before is read, but cannot be decoded.
+    CorruptStorage(String),
+    /// The runtime of the instance cannot be created.
+    Runtime(io::Error),
+    /// An instance of the group is already served on this host.
+    GroupExists(GroupId),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PeerIndexOutOfRange {
+                peer_index,
+                peer_count,
+            } => write!(
+                f,
+                "peer index {} is out of range, there are {} peers",
+                peer_index, peer_count
+            ),
+            Self::NoPeers => write!(f, "no peers given"),
+            Self::InvalidConfig(e) => e.fmt(f),
+            Self::Storage(e) => write!(f, "cannot read saved state: {}", e),
+            Self::CorruptStorage(e) => write!(f, "saved state is corrupt: {}", e),
+            Self::Runtime(e) => write!(f, "cannot create runtime: {}", e),
+            Self::GroupExists(group) => write!(f, "group {} is already on this host", group.0),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Creates a Raft instance.
+///
+/// ```ignore
+/// let raft = RaftBuilder::new(peers, peer_index, storage, connector)
+///     .config(config)
+///     .runtime(runtime.handle().clone())
+///     .build(apply_command)?;
+/// ```
+pub struct RaftBuilder<Command, Storage> {
+    initial_peers: HashMap<Peer, Arc<dyn RemoteRaft<Command>>>,
+    peer_count: usize,
+    // `None` if the instance joins an existing cluster
+    peer_index: Option<usize>,
+    peer: Peer,
+    group: GroupId,
+    base_membership: Membership,
+    storage: Storage,
+    connector: Arc<dyn RemoteRaftConnector<Command>>,
+    config: RaftConfig,
+    runtime: Option<tokio::runtime::Handle>,
+}
+
+impl<Command: ReplicableCommand, Storage: RaftStorageTrait> RaftBuilder<Command, Storage> {
+    /// Starts building an instance that is one of the initial servers `peers`
+    /// of a cluster, at `peer_index`.
+    pub fn new(
+        peers: Vec<impl RemoteRaft<Command>>,
+        peer_index: usize,
+        storage: Storage,
+        connector: impl RemoteRaftConnector<Command>,
+    ) -> Self {
+        // The initial servers are given by `peers`, unless a membership has
+        // been persisted before.
+        let base_membership = Membership::new(
+            (0..peers.len())
+                .map(|index| (Peer(index), String::new()))
+                .collect(),
+        );
+        let mut builder =
+            Self::create(peers, Peer(peer_index), base_membership, storage, connector);
+        builder.peer_index = Some(peer_index);
+        builder
+    }
+
+    /// Starts building an instance that joins an existing cluster as `peer`,
+    /// see `Raft::join()`.
+    pub fn join(
+        peers: Vec<impl RemoteRaft<Command>>,
+        peer: Peer,
+        storage: Storage,
+        connector: impl RemoteRaftConnector<Command>,
+    ) -> Self {
+        Self::create(peers, peer, Membership::default(), storage, connector)
+    }
+
+    fn create(
+        peers: Vec<impl RemoteRaft<Command>>,
+        peer: Peer,
+        base_membership: Membership,
+        storage: Storage,
+        connector: impl RemoteRaftConnector<Command>,
+    ) -> Self {
+        let peer_count = peers.len();
+        let initial_peers = peers
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| *index != peer.0)
+            .map(|(index, remote_raft)| {
+                let remote_raft: Arc<dyn RemoteRaft<Command>> = Arc::new(remote_raft);
+                (Peer(index), remote_raft)
+            })
+            .collect();
+        RaftBuilder {
+            initial_peers,
+            peer_count,
+            peer_index: None,
+            peer,
+            group: GroupId::default(),
+            base_membership,
+            storage,
+            connector: Arc::new(connector),
+            config: RaftConfig::default(),
+            runtime: None,
+        }
+    }
+
+    /// Sets the group of the instance. Defaults to `GroupId(0)`.
+    pub fn group(mut self, group: GroupId) -> Self {
+        self.group = group;
+        self
+    }
+
+    /// Sets the tunables of the instance.
+    pub fn config(mut self, config: RaftConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Runs the tasks of the instance on `runtime`, instead of on a runtime
+    /// created for the instance.
+    ///
+    /// The tasks are cancelled when the instance is killed, and
+    /// `RaftJoinHandle::join()` waits for them like for those on a runtime
+    /// of the instance. The runtime itself is not shut down.
+    pub fn runtime(mut self, runtime: tokio::runtime::Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Creates the instance and starts its daemons. Committed commands are
+    /// passed to `apply_command`.
+    pub fn build(
+        self,
+        apply_command: impl ApplyCommandFnMut<Command>,
+    ) -> Result<Raft<Command>, BuildError> {
+        let RaftBuilder {
+            initial_peers,
+            peer_count,
+            peer_index,
+            peer,
+            group,
+            base_membership,
+            storage,
+            connector,
+            config,
+            runtime,
+        } = self;
+        if peer_count == 0 {
+            return Err(BuildError::NoPeers);
+        }
+        if let Some(peer_index) = peer_index.filter(|index| *index >= peer_count) {
+            return Err(BuildError::PeerIndexOutOfRange {
+                peer_index,
+                peer_count,
+            });
+        }
+        config.validate().map_err(BuildError::InvalidConfig)?;
+
+        let peer_size = base_membership.servers.len().max(1);
+        let mut raft_state = RaftState::create();
+        raft_state.base_membership = base_membership;
+        let mut has_stored_membership = false;
+        match storage.read_state() {
+            Ok(stored_state) => {
+                let corrupt = |e: &dyn fmt::Display| BuildError::CorruptStorage(e.to_string());
+                raft_state.current_term = stored_state.current_term();
+                raft_state.voted_for = stored_state.voted_for().map_err(|e| corrupt(&e))?;
+                if let Some(membership) = stored_state.membership().map_err(|e| corrupt(&e))? {
+                    raft_state.base_membership = membership;
+                    has_stored_membership = true;
+                }
+                for stored_entry in stored_state.log.iter() {
+                    let entry = decode_log_entry(stored_entry).map_err(|e| corrupt(&e))?;
+                    raft_state.log.push(entry);
+                }
+            }
+            // Nothing has been saved yet.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(BuildError::Storage(e)),
+        }
+        raft_state.durable_index = raft_state.log.last_index_term().index;
+        raft_state.saved_index = raft_state.durable_index;
+
+        let durable_signal = watch::channel((0, raft_state.durable_index)).0;
+        let inner_state = Arc::new(Mutex::new(raft_state));
+        let election = Arc::new(ElectionState::create(&config));
+        election.reset_election_timer();
+
+        let persister = storage.persister();
+
+        // Only a runtime created here is owned, and shut down, by the
+        // instance.
+        let (thread_pool, owned_thread_pool) = match runtime {
+            Some(runtime) => (runtime, None),
+            None => {
+                let thread_pool = tokio::runtime::Builder::new_multi_thread()
+                    .enable_io()
+                    .enable_time()
+                    .thread_name(format!("raft-{}", peer.0))
+                    .worker_threads(peer_size)
+                    .build()
+                    .map_err(BuildError::Runtime)?;
+                (thread_pool.handle().clone(), Some(thread_pool))
+            }
+        };
+
+        let this = Raft {
+            remote_peers: Arc::new(Mutex::new(HashMap::new())),
+            initial_peers: Arc::new(initial_peers),
+            connector,
+            peer,
+            group,
+            inner_state,
+            election,
+            apply_command_signal: Arc::new(Notify::new()),
+            persist_log_signal: Arc::new(Notify::new()),
+            persist_log_lock: Arc::new(Mutex::new(())),
+            durable_signal: Arc::new(durable_signal),
+            term_vote_signal: Arc::new(watch::channel(0).0),
+            persister,
+            heartbeats_daemon: HeartbeatsDaemon::create(&config),
+            sync_log_entries_daemon: SyncLogEntriesDaemon::create(peer_size, &config),
+            thread_pool,
+            daemons: Daemons::create(),
+            config: Arc::new(config),
+            keep_running: Arc::new(AtomicBool::new(true)),
+            join_handle: Arc::new(Mutex::new(None)),
+        };
+
+        {
+            let mut rf = this.inner_state.lock().unwrap();
+            if !has_stored_membership {
+                this.persist_base_membership(&rf);
+            }
+            // Starts the daemons of all peers in the cluster.
+            this.restore_membership(&mut rf);
+        }
+        this.run_election_timer();
+        this.run_apply_command_daemon(apply_command);
+        this.run_persist_log_entries_daemon();
+        let persister = this.persister.clone();
+        this.join_handle.lock().unwrap().replace(RaftJoinHandle {
+            thread_pool: owned_thread_pool,
+            daemons: Some(this.daemons.clone()),
+            close: Some(Box::new(move || persister.close())),
+            timeout: this.config.heartbeat_interval() * 2,
+        });
+
+        Ok(this)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        kv::storage::{KVPersister, KVStorage},
+        raft_state::Term,
+        storage::{RaftLogEntryRef, RaftStoredState},
+        test_utils::{wait_until, Command, LocalNetwork},
+    };
+
+    // Storage that returns the given state, or fails to read it
+    struct SavedStorage(Result<RaftStoredState, io::ErrorKind>);
+
+    impl RaftStorageTrait for SavedStorage {
+        type RaftStoragePersister<LogEntry: RaftLogEntryRef> = KVPersister;
+
+        fn persister<LogEntry: RaftLogEntryRef>(self) -> Arc<KVPersister> {
+            Arc::new(KVPersister::default())
+        }
+
+        fn read_state(&self) -> io::Result<RaftStoredState> {
+            self.0.clone().map_err(io::Error::from)
+        }
+    }
+
+    fn saved_state(voted_for: &str, membership: Vec<u8>) -> RaftStoredState {
+        RaftStoredState {
+            current_term: Term(1),
+            voted_for: voted_for.to_owned(),
+            log: vec![],
+            membership,
+            snapshot_index: 0,
+            snapshot_term: Term(0),
+            snapshot: vec![],
+        }
+    }
+
+    fn builder<Storage: RaftStorageTrait>(
+        peer_count: usize,
+        peer_index: usize,
+        storage: Storage,
+    ) -> RaftBuilder<Command, Storage> {
+        let network = Arc::new(LocalNetwork::default());
+        RaftBuilder::new(
+            network.clients(Peer(peer_index), peer_count),
+            peer_index,
+            storage,
+            network.connector(Peer(peer_index)),
+        )
+    }
+
+    fn build<Storage: RaftStorageTrait>(builder: RaftBuilder<Command, Storage>) -> BuildError {
+        match builder.build(|_, _: Command| {}) {
+            Ok(raft) => {
+                raft.kill().join();
+                panic!("the instance should not be built");
+            }
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn peers_must_include_the_instance() {
+        let error = build(builder(0, 0, KVStorage::default()));
+        assert!(matches!(error, BuildError::NoPeers));
+        let error = build(builder(3, 3, KVStorage::default()));
+        assert!(matches!(
+            error,
+            BuildError::PeerIndexOutOfRange {
+                peer_index: 3,
+                peer_count: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let config = RaftConfig {
+            election_timeout_min_millis: 500,
+            election_timeout_max_millis: 400,
+            ..Default::default()
+        };
+        let error = build(builder(1, 0, KVStorage::default()).config(config));
+        assert!(matches!(error, BuildError::InvalidConfig(_)));
+    }
+
+    #[test]
+    fn unreadable_storage_is_rejected() {
+        let storage = SavedStorage(Err(io::ErrorKind::PermissionDenied));
+        let error = build(builder(1, 0, storage));
+        assert!(
+            matches!(error, BuildError::Storage(e) if e.kind() == io::ErrorKind::PermissionDenied)
+        );
+
+        let storage = SavedStorage(Ok(saved_state("", vec![0xff; 3])));
+        let error = build(builder(1, 0, storage));
+        assert!(matches!(error, BuildError::CorruptStorage(_)));
+
+        let storage = SavedStorage(Ok(saved_state("not a peer", vec![])));
+        let error = build(builder(1, 0, storage));
+        assert!(matches!(error, BuildError::CorruptStorage(_)));
+    }
+
+    #[test]
+    fn missing_state_is_a_new_instance() {
+        let storage = SavedStorage(Err(io::ErrorKind::NotFound));
+        let raft = builder(1, 0, storage).build(|_, _: Command| {}).unwrap();
+        assert_eq!(raft.get_state().0, Term(0));
+        raft.kill().join();
+
+        let storage = SavedStorage(Ok(saved_state("0", vec![])));
+        let raft = builder(1, 0, storage).build(|_, _: Command| {}).unwrap();
+        assert_eq!(raft.inner_state.lock().unwrap().voted_for, Some(Peer(0)));
+        raft.kill().join();
+    }
+
+    #[test]
+    fn instances_run_on_the_runtime_of_the_caller() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let applied = Arc::new(AtomicUsize::new(0));
+        let counter = applied.clone();
+        // Built from a task, where creating a runtime of its own would panic
+        // once that runtime is dropped.
+        let raft = runtime.block_on(async {
+            builder(1, 0, KVStorage::default())
+                .runtime(tokio::runtime::Handle::current())
+                .build(move |_, _: Command| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap()
+        });
+        assert!(wait_until(Duration::from_secs(2), || raft.get_state().1));
+        raft.start("command".to_owned()).unwrap();
+        assert!(wait_until(Duration::from_secs(1), || applied
+            .load(Ordering::SeqCst)
+            == 1));
+
+        let daemons = raft.daemons.clone();
+        raft.kill().join();
+        assert_eq!(daemons.wait(Instant::now()), 0);
+        // The runtime belongs to the caller, and keeps running.
+        assert_eq!(runtime.block_on(runtime.spawn(async { 7 })).unwrap(), 7);
+    }
+}
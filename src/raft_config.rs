@@ -0,0 +1,237 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Tunables of a Raft instance.
+///
+/// A config can be loaded from a TOML or JSON file. Fields missing from the
+/// file keep their default value.
+///
+/// There is no snapshot threshold yet: the log is never compacted, and a
+/// threshold would have no effect. It is left for the change that adds log
+/// compaction, until then `snapshot_threshold` is an unknown field.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RaftConfig {
+    /// How often the leader sends heartbeats to each peer.
+    pub heartbeat_interval_millis: u64,
+    /// Heartbeats sent early, e.g. to commit new entries sooner, are sent at
+    /// most this often.
+    pub heartbeat_max_delay_millis: u64,
+    /// A follower that does not hear from the leader starts an election after
+    /// a random time between the min (inclusive) and the max (exclusive).
+    pub election_timeout_min_millis: u64,
+    pub election_timeout_max_millis: u64,
+    /// The most log entries sent to a peer in one `AppendEntries` request.
+    pub max_entries_per_append: usize,
+    /// The most `AppendEntries` requests sent to a peer without a reply.
+    pub max_inflight_appends: usize,
+    /// No more `AppendEntries` requests are sent to a peer once the entries
+    /// sent to it without a reply take this many bytes, so that a slow peer
+    /// does not make us hold on to every entry it is sent. One request is
+    /// always allowed, however large its entries.
+    pub max_inflight_bytes: usize,
+    /// Proposals are sent to peers in batches. A batch is sent this long
+    /// after its first proposal, or once it fills an `AppendEntries` request.
+    /// Rounded up to the resolution of the timer of the runtime, usually a
+    /// millisecond.
+    ///
+    /// 0 sends each proposal right away. Proposals made while earlier entries
+    /// are in flight are still sent together. A delay saves requests when
+    /// many small proposals arrive over a slow network, at the cost of
+    /// latency.
+    pub proposal_batch_delay_micros: u64,
+    /// Where the persisted state is kept. `None` keeps it in memory.
+    pub data_dir: Option<PathBuf>,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            heartbeat_interval_millis: 50,
+            heartbeat_max_delay_millis: 30,
+            election_timeout_min_millis: 200,
+            election_timeout_max_millis: 400,
+            max_entries_per_append: 1024,
+            max_inflight_appends: 4,
+            max_inflight_bytes: 8 * 1024 * 1024,
+            proposal_batch_delay_micros: 0,
+            data_dir: None,
+        }
+    }
+}
+
+/// Why a config cannot be loaded or used.
+#[derive(Debug)]
+pub enum RaftConfigError {
+    Read(std::io::Error),
+    /// The file is not valid TOML or JSON, or has unknown fields.
+    Parse(String),
+    /// The file name does not end with `.toml` or `.json`.
+    UnknownFormat(PathBuf),
+    /// The config is well-formed, but not safe to run with.
+    Invalid(String),
+}
+
+impl fmt::Display for RaftConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(e) => write!(f, "cannot read config file: {}", e),
+            Self::Parse(e) => write!(f, "malformed config file: {}", e),
+            Self::UnknownFormat(path) => write!(
+                f,
+                "config file {} should end with .toml or .json",
+                path.display()
+            ),
+            Self::Invalid(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RaftConfigError {}
+
+impl RaftConfig {
+    // Heartbeats must be well below the election timeout, otherwise followers
+    // start elections while the leader is still alive.
+    const MIN_HEARTBEATS_PER_ELECTION_TIMEOUT: u64 = 3;
+
+    /// Reads a config from a TOML or JSON file, chosen by the file extension.
+    /// The config is not validated.
+    pub fn load(path: &Path) -> Result<Self, RaftConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(RaftConfigError::Read)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => {
+                toml::from_str(&contents).map_err(|e| RaftConfigError::Parse(e.to_string()))
+            }
+            Some("json") => {
+                serde_json::from_str(&contents).map_err(|e| RaftConfigError::Parse(e.to_string()))
+            }
+            _ => Err(RaftConfigError::UnknownFormat(path.to_owned())),
+        }
+    }
+
+    /// Checks that a cluster can elect and keep a leader with this config.
+    pub fn validate(&self) -> Result<(), RaftConfigError> {
+        let invalid = |message: String| Err(RaftConfigError::Invalid(message));
+        if self.heartbeat_interval_millis == 0 {
+            return invalid("heartbeat_interval_millis must be positive".to_owned());
+        }
+        if self.election_timeout_min_millis >= self.election_timeout_max_millis {
+            return invalid(format!(
+                "election_timeout_min_millis ({}) must be below election_timeout_max_millis ({})",
+                self.election_timeout_min_millis, self.election_timeout_max_millis
+            ));
+        }
+        if self
+            .heartbeat_interval_millis
+            .saturating_mul(Self::MIN_HEARTBEATS_PER_ELECTION_TIMEOUT)
+            > self.election_timeout_min_millis
+        {
+            return invalid(format!(
+                "heartbeat_interval_millis ({}) must be at most 1/{} of election_timeout_min_millis ({})",
+                self.heartbeat_interval_millis,
+                Self::MIN_HEARTBEATS_PER_ELECTION_TIMEOUT,
+                self.election_timeout_min_millis
+            ));
+        }
+        // Heartbeats sent late must still reach followers before they time
+        // out.
+        if self
+            .heartbeat_interval_millis
+            .saturating_add(self.heartbeat_max_delay_millis)
+            >= self.election_timeout_min_millis
+        {
+            return invalid(format!(
+                "heartbeat_interval_millis ({}) plus heartbeat_max_delay_millis ({}) must be below election_timeout_min_millis ({})",
+                self.heartbeat_interval_millis,
+                self.heartbeat_max_delay_millis,
+                self.election_timeout_min_millis
+            ));
+        }
+        if self.max_entries_per_append == 0 {
+            return invalid("max_entries_per_append must be positive".to_owned());
+        }
+        if self.max_inflight_appends == 0 {
+            return invalid("max_inflight_appends must be positive".to_owned());
+        }
+        if self.max_inflight_bytes == 0 {
+            return invalid("max_inflight_bytes must be positive".to_owned());
+        }
+        if self
+            .data_dir
+            .as_ref()
+            .is_some_and(|data_dir| data_dir.as_os_str().is_empty())
+        {
+            return invalid("data_dir must not be empty".to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn proposal_batch_delay(&self) -> Duration {
+        Duration::from_micros(self.proposal_batch_delay_micros)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_millis)
+    }
+
+    pub fn min_election_timeout(&self) -> Duration {
+        Duration::from_millis(self.election_timeout_min_millis)
+    }
+
+    pub fn max_election_timeout(&self) -> Duration {
+        Duration::from_millis(self.election_timeout_max_millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        RaftConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_heartbeats_too_slow_for_elections() {
+        for heartbeat_interval_millis in [100, u64::MAX / 2, u64::MAX] {
+            let config = RaftConfig {
+                heartbeat_interval_millis,
+                ..Default::default()
+            };
+            assert!(matches!(
+                config.validate(),
+                Err(RaftConfigError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn validate_rejects_heartbeat_delays_too_long_for_elections() {
+        for heartbeat_max_delay_millis in [150, 1000, u64::MAX] {
+            let config = RaftConfig {
+                heartbeat_max_delay_millis,
+                ..Default::default()
+            };
+            assert!(matches!(
+                config.validate(),
+                Err(RaftConfigError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn load_rejects_unknown_fields() {
+        let path = std::env::temp_dir().join(format!("raft-config-{}.toml", std::process::id()));
+        // Misspelled `heartbeat_interval_millis`.
+        std::fs::write(&path, "heartbeat_interval_ms = 10\n").unwrap();
+        let result = RaftConfig::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(RaftConfigError::Parse(_))));
+    }
+}
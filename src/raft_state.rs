@@ -1,16 +1,43 @@
-use crate::log_array::{Index, LogArray};
+use std::{collections::BTreeMap, time::Instant};
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    log_array::{Index, LogArray},
+    membership::Membership,
+    raft::ClusterMember,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct Peer(pub usize);
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+/// Identifies a Raft group among the groups that share a process, see
+/// `MultiRaftHost`. A process that runs one group uses `GroupId(0)`.
+#[derive(
+    Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize,
+)]
+pub struct GroupId(pub u64);
+
+#[derive(
+    Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize,
+)]
 pub struct Term(pub usize);
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub(crate) enum State {
     Leader,
     Follower,
     Candidate,
+    // Receives log entries from the leader, but does not vote
+    Learner,
+}
+
+/// An ongoing attempt of the leader to hand leadership over to `target`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct LeadershipTransfer {
+    pub target: Peer,
+    // The transfer is abandoned if it is not done by this time
+    pub deadline: Instant,
 }
 
 #[derive(Debug)]
@@ -18,7 +45,7 @@ pub(crate) struct RaftState<Command> {
     pub current_term: Term,
     pub log: LogArray<Command>,
     // Who was voted for in the most recent term
-    pub voted_for: Option<Index>,
+    pub voted_for: Option<Peer>,
 
     // Index of highest log entry known to be committed
     pub commit_index: Index,
@@ -26,8 +53,45 @@ pub(crate) struct RaftState<Command> {
     // Index of highest log entry applied to state machine
     pub last_applied: Index,
 
+    // Index of highest log entry known to be written to storage. Storage
+    // reports entries written in the background, after they are added to
+    // the log.
+    pub durable_index: Index,
+
+    // Index of highest log entry handed to storage
+    pub saved_index: Index,
+
+    // Number of times entries were removed from the end of the log. Entries
+    // reported written after they were handed to storage may have been
+    // replaced in between, if this has changed.
+    pub truncations: u64,
+
+    // Number of times the term and vote were handed to storage
+    pub term_vote_saves: u64,
+
     // Candidate, follower, or leader
     pub state: State,
+
+    // Leader of the current term, if known
+    pub leader_id: Option<Peer>,
+
+    // Last time we heard from the leader of the current term
+    pub leader_contact: Option<Instant>,
+
+    // Servers in the cluster, including this one
+    pub cluster: BTreeMap<Peer, ClusterMember>,
+
+    // Servers in the cluster, as of the latest configuration entry
+    pub membership: Membership,
+
+    // Index of the entry that introduced `membership`
+    pub membership_index: Index,
+
+    // Servers in the cluster before the first log entry
+    pub base_membership: Membership,
+
+    // Set while proposals are paused to transfer leadership
+    pub leadership_transfer: Option<LeadershipTransfer>,
 }
 
 impl<Command> RaftState<Command> {
@@ -38,7 +102,22 @@ impl<Command> RaftState<Command> {
             log: LogArray::create(),
             commit_index: 0,
             last_applied: 0,
+            durable_index: 0,
+            saved_index: 0,
+            truncations: 0,
+            term_vote_saves: 0,
             state: State::Follower,
+            leader_id: None,
+            leader_contact: None,
+            cluster: BTreeMap::new(),
+            membership: Membership::default(),
+            membership_index: 0,
+            base_membership: Membership::default(),
+            leadership_transfer: None,
         }
     }
+
+    pub fn is_leader(&self) -> bool {
+        self.state == State::Leader
+    }
 }
@@ -0,0 +1,103 @@
+use std::ops::Bound;
+
+use serde_derive::Serialize;
+
+use crate::state_machine::{KeyValue, StateMachine};
+
+/// A read of the keys in `[start, end)`, in key order.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Range {
+    pub start: Vec<u8>,
+    /// Exclusive. `None` reads every key from `start` on.
+    pub end: Option<Vec<u8>>,
+    /// Maximum number of keys returned, 0 for no limit.
+    pub limit: usize,
+    /// Returns the keys with empty values.
+    pub keys_only: bool,
+}
+
+impl Range {
+    /// Reads every key that starts with `prefix`.
+    pub fn prefix(prefix: &[u8]) -> Self {
+        Range {
+            start: prefix.to_vec(),
+            end: prefix_end(prefix),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_limit(self, limit: usize) -> Self {
+        Range { limit, ..self }
+    }
+
+    pub fn keys_only(self) -> Self {
+        Range {
+            keys_only: true,
+            ..self
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct RangeResult {
+    pub kvs: Vec<(Vec<u8>, KeyValue)>,
+    /// Whether more keys are in the range but not returned due to the limit.
+    pub more: bool,
+    /// Revision of the store when the range was read.
+    pub revision: u64,
+}
+
+/// Returns the smallest key that is larger than every key starting with
+/// `prefix`, or `None` if there is no such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+impl StateMachine {
+    /// Reads `range` from the latest revision. Reads do not go through the
+    /// log, so they see the commands applied on this server so far.
+    pub fn range(&self, range: &Range) -> RangeResult {
+        let end = match &range.end {
+            Some(end) if *end <= range.start => {
+                return RangeResult {
+                    revision: self.revision,
+                    ..Default::default()
+                }
+            }
+            Some(end) => Bound::Excluded(end.as_slice()),
+            None => Bound::Unbounded,
+        };
+        let bounds = (Bound::Included(range.start.as_slice()), end);
+
+        let limit = if range.limit == 0 {
+            usize::MAX
+        } else {
+            range.limit
+        };
+        let mut iter = self.db.range::<[u8], _>(bounds);
+        let kvs = iter
+            .by_ref()
+            .take(limit)
+            .map(|(key, kv)| {
+                let mut kv = kv.clone();
+                if range.keys_only {
+                    kv.value.clear();
+                }
+                (key.clone(), kv)
+            })
+            .collect();
+
+        RangeResult {
+            kvs,
+            more: iter.next().is_some(),
+            revision: self.revision,
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashSet;
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+
+use crate::{
+    log_array::Index,
+    messages::AppendEntriesArgs,
+    raft::{Raft, ReplicableCommand},
+};
+
+impl<Command: ReplicableCommand> Raft<Command> {
+    /// Confirms that this instance is still the leader, and returns the
+    /// commit index as of this call. A read served once the entries up to
+    /// the index are applied is linearizable, without going through the log.
+    ///
+    /// Leadership is confirmed by a round of heartbeats, answered by a quorum
+    /// in the current term. Returns `None` if this instance is not the
+    /// leader, has not committed an entry of its term yet, or is not
+    /// confirmed by a quorum.
+    pub async fn read_index(&self) -> Option<Index> {
+        let (args, membership, peers) = {
+            let rf = self.inner_state.lock().unwrap();
+            // The commit index of a new leader is only known once the no-op
+            // entry of its term is committed.
+            if !rf.is_leader() || rf.log.at(rf.commit_index).term != rf.current_term {
+                return None;
+            }
+            let last_log = rf.log.last_index_term();
+            let args = AppendEntriesArgs {
+                term: rf.current_term,
+                leader_id: self.peer,
+                prev_log_index: last_log.index,
+                prev_log_term: last_log.term,
+                entries: vec![],
+                leader_commit: rf.commit_index,
+            };
+            let peers: Vec<_> = self
+                .remote_peers
+                .lock()
+                .unwrap()
+                .values()
+                .cloned()
+                .collect();
+            (args, rf.membership.clone(), peers)
+        };
+        let term = args.term;
+        let read_index = args.leader_commit;
+
+        let mut acks = HashSet::from([self.peer]);
+        let mut replies: FuturesUnordered<_> = peers
+            .into_iter()
+            .map(|peer| {
+                let args = args.clone();
+                async move { (peer.unique_id, peer.heartbeat(args, true).await) }
+            })
+            .collect();
+        while !membership.has_quorum(&acks) {
+            let (peer, reply) = replies.next().await?;
+            let Ok(reply) = reply else {
+                continue;
+            };
+            if reply.term > term {
+                let mut rf = self.inner_state.lock().unwrap();
+                if reply.term > rf.current_term {
+                    self.step_down(&mut rf, reply.term);
+                }
+                return None;
+            }
+            // A rejection in our term still recognizes us as the leader.
+            acks.insert(peer);
+        }
+        Some(read_index)
+    }
+}
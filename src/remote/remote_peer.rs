@@ -1,9 +1,84 @@
-pub(crate) struct RemotePeer<UniqueID> {
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    messages::{
+        AppendEntriesArgs, AppendEntriesReply, RequestVoteArgs, RequestVoteReply, TimeoutNowArgs,
+        TimeoutNowReply,
+    },
+    remote::remote_raft::RemoteRaft,
+};
+
+/// A remote Raft instance, identified by `unique_id`. All RPCs sent through a
+/// remote peer are bounded by `RPC_DEADLINE`.
+pub(crate) struct RemotePeer<Command, UniqueID> {
     pub unique_id: UniqueID,
+    raft: Arc<dyn RemoteRaft<Command>>,
+    // Set when the peer leaves the cluster, daemons of the peer exit
+    stopped: Arc<AtomicBool>,
+}
+
+impl<Command, UniqueID: Clone> Clone for RemotePeer<Command, UniqueID> {
+    fn clone(&self) -> Self {
+        RemotePeer {
+            unique_id: self.unique_id.clone(),
+            raft: self.raft.clone(),
+            stopped: self.stopped.clone(),
+        }
+    }
 }
 
-impl<UniqueID> RemotePeer<UniqueID> {
-    pub fn create(unique_id: UniqueID) -> Self {
-        RemotePeer { unique_id }
+impl<Command: Send + 'static, UniqueID> RemotePeer<Command, UniqueID> {
+    const RPC_DEADLINE: Duration = Duration::from_millis(100);
+
+    pub fn create(unique_id: UniqueID, raft: Arc<dyn RemoteRaft<Command>>) -> Self {
+        RemotePeer {
+            unique_id,
+            raft,
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    pub async fn request_vote(&self, args: RequestVoteArgs) -> std::io::Result<RequestVoteReply> {
+        Self::with_deadline(self.raft.request_vote(args)).await
+    }
+
+    pub async fn append_entries(
+        &self,
+        args: AppendEntriesArgs<Command>,
+    ) -> std::io::Result<AppendEntriesReply> {
+        Self::with_deadline(self.raft.append_entries(args)).await
+    }
+
+    pub async fn heartbeat(
+        &self,
+        args: AppendEntriesArgs<Command>,
+        urgent: bool,
+    ) -> std::io::Result<AppendEntriesReply> {
+        Self::with_deadline(self.raft.heartbeat(args, urgent)).await
+    }
+
+    pub async fn timeout_now(&self, args: TimeoutNowArgs) -> std::io::Result<TimeoutNowReply> {
+        Self::with_deadline(self.raft.timeout_now(args)).await
+    }
+
+    async fn with_deadline<T>(rpc: impl Future<Output = std::io::Result<T>>) -> std::io::Result<T> {
+        tokio::time::timeout(Self::RPC_DEADLINE, rpc)
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
     }
 }
@@ -1,3 +1,38 @@
-pub trait RemoteRaft<Command> {
-    async fn request_vote(&self) -> std::io::Result<()>;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::messages::{
+    AppendEntriesArgs, AppendEntriesReply, RequestVoteArgs, RequestVoteReply, TimeoutNowArgs,
+    TimeoutNowReply,
+};
+
+#[async_trait]
+pub trait RemoteRaft<Command: Send + 'static>: Send + Sync + 'static {
+    async fn request_vote(&self, args: RequestVoteArgs) -> std::io::Result<RequestVoteReply>;
+
+    async fn append_entries(
+        &self,
+        args: AppendEntriesArgs<Command>,
+    ) -> std::io::Result<AppendEntriesReply>;
+
+    async fn timeout_now(&self, args: TimeoutNowArgs) -> std::io::Result<TimeoutNowReply>;
+
+    /// Sends a heartbeat, an `AppendEntries` without entries. A heartbeat
+    /// that is not `urgent` may be delayed a little, to be sent together
+    /// with others.
+    async fn heartbeat(
+        &self,
+        args: AppendEntriesArgs<Command>,
+        urgent: bool,
+    ) -> std::io::Result<AppendEntriesReply> {
+        let _ = urgent;
+        self.append_entries(args).await
+    }
+}
+
+/// Creates clients of Raft instances that join the cluster after this instance
+/// has started.
+pub trait RemoteRaftConnector<Command: Send + 'static>: Send + Sync + 'static {
+    fn connect(&self, address: &str) -> std::io::Result<Arc<dyn RemoteRaft<Command>>>;
 }
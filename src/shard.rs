@@ -0,0 +1,428 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    ops::Bound,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    lease::Lease,
+    raft_state::GroupId,
+    state_machine::{ApplyResult, Command, CommandKind, StateMachine, TxnOp},
+};
+
+/// The keys in `[start, end)`, replicated by one Raft group.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Shard {
+    pub group: GroupId,
+    pub start: Vec<u8>,
+    /// Exclusive. `None` if the shard holds every key from `start` on.
+    pub end: Option<Vec<u8>>,
+    /// Incremented by every split and merge. Of two overlapping shards, the
+    /// one with the higher generation is the more recent.
+    pub generation: u64,
+}
+
+impl Shard {
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key >= self.start.as_slice() && self.end.as_ref().is_none_or(|end| key < end.as_slice())
+    }
+
+    fn overlaps(&self, other: &Shard) -> bool {
+        self.end.as_ref().is_none_or(|end| other.start < *end)
+            && other.end.as_ref().is_none_or(|end| self.start < *end)
+    }
+}
+
+/// Sent by a state machine when its shard is split or merged, so that the host
+/// can create or remove the groups involved.
+#[derive(Debug)]
+pub enum ShardEvent {
+    /// A new shard was split off. Holds its state machine.
+    Split(Box<StateMachine>),
+    /// The shard of this group was merged into its left neighbour.
+    Merged(GroupId),
+}
+
+/// Maps keys to the shards that hold them.
+///
+/// The table is a cache, and may be out of date. A command sent to a shard
+/// that no longer holds its key returns `ApplyResult::WrongShard` with the
+/// current shard, which is added to the table.
+#[derive(Clone, Debug, Default)]
+pub struct RoutingTable {
+    // By start key
+    shards: BTreeMap<Vec<u8>, Shard>,
+}
+
+impl RoutingTable {
+    pub fn lookup(&self, key: &[u8]) -> Option<&Shard> {
+        self.shards
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .map(|(_, shard)| shard)
+            .filter(|shard| shard.contains(key))
+    }
+
+    /// Adds `shard` and removes the older shards that overlap it. Returns
+    /// false if a shard overlapping it is at least as recent.
+    pub fn update(&mut self, shard: Shard) -> bool {
+        let overlapping: Vec<Vec<u8>> = self
+            .shards
+            .values()
+            .filter(|s| s.overlaps(&shard))
+            .map(|s| s.start.clone())
+            .collect();
+        if overlapping
+            .iter()
+            .any(|start| self.shards[start].generation >= shard.generation)
+        {
+            return false;
+        }
+        for start in overlapping {
+            self.shards.remove(&start);
+        }
+        self.shards.insert(shard.start.clone(), shard);
+        true
+    }
+
+    pub fn shards(&self) -> impl Iterator<Item = &Shard> {
+        self.shards.values()
+    }
+}
+
+/// Returns the keys read or written by `c`, or `None` if the command does not
+/// work on keys.
+pub(crate) fn command_keys(c: &Command) -> Option<Vec<&[u8]>> {
+    match &c.kind {
+        CommandKind::GetCommand
+        | CommandKind::SetCommand
+        | CommandKind::DeleteCommand
+        | CommandKind::CompareAndSwapCommand { .. }
+        | CommandKind::AppendCommand
+        | CommandKind::IncrementCommand { .. } => Some(vec![c.key.as_slice()]),
+        CommandKind::TxnCommand {
+            compares,
+            success,
+            failure,
+        } => {
+            let op_keys = success.iter().chain(failure).map(|op| match op {
+                TxnOp::Put { key, .. } | TxnOp::Delete { key } => key.as_slice(),
+            });
+            Some(
+                compares
+                    .iter()
+                    .map(|compare| compare.key.as_slice())
+                    .chain(op_keys)
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+impl StateMachine {
+    /// Returns the key that splits the shard in two halves of the same number
+    /// of keys, or `None` if the shard has less than two keys.
+    pub fn middle_key(&self) -> Option<Vec<u8>> {
+        if self.db.len() < 2 {
+            return None;
+        }
+        self.db.keys().nth(self.db.len() / 2).cloned()
+    }
+
+    /// Returns the result of a command that must not be applied to this
+    /// shard: a key of the command is not in the shard, or the shard is
+    /// frozen.
+    pub(crate) fn check_shard(&self, c: &Command) -> Option<ApplyResult> {
+        let allowed = match &c.kind {
+            CommandKind::PrepareMergeCommand | CommandKind::AbortMergeCommand => true,
+            _ if self.frozen => false,
+            _ => command_keys(c).is_none_or(|keys| keys.iter().all(|key| self.shard.contains(key))),
+        };
+        (!allowed).then(|| ApplyResult::WrongShard(self.shard.clone()))
+    }
+
+    /// Moves the keys from `key` on to a new shard, replicated by `new_group`.
+    ///
+    /// The new shard gets the leases of this one, each with the keys that
+    /// move, and a copy of the client sessions. Its revisions continue from
+    /// the revision of the split. Neither shard can read revisions before the
+    /// split.
+    pub(crate) fn split(&mut self, key: &[u8], new_group: u64) -> ApplyResult {
+        if key <= self.shard.start.as_slice() || !self.shard.contains(key) {
+            return ApplyResult::WrongShard(self.shard.clone());
+        }
+
+        let generation = self.shard.generation + 1;
+        let right_shard = Shard {
+            group: GroupId(new_group),
+            start: key.to_vec(),
+            end: self.shard.end.replace(key.to_vec()),
+            generation,
+        };
+        self.shard.generation = generation;
+
+        let db = self.db.split_off(key);
+        let mut leases = HashMap::new();
+        for (id, lease) in self.leases.iter_mut() {
+            let keys = lease.keys.split_off(key);
+            leases.insert(
+                *id,
+                Lease {
+                    ttl_millis: lease.ttl_millis,
+                    expires_at: lease.expires_at,
+                    keys,
+                },
+            );
+        }
+        self.history.clear();
+        self.history_start = self.revision;
+
+        let right = StateMachine {
+            db,
+            server: self.server,
+            sessions: self.sessions.clone(),
+            log_time: self.log_time,
+            revision: self.revision,
+            leases,
+            shard: right_shard.clone(),
+            revision_offset: self.revision,
+            history_start: self.revision,
+            ..Default::default()
+        };
+        if let Some(shard_events) = &self.shard_events {
+            let _ = shard_events.send(ShardEvent::Split(Box::new(right)));
+        }
+        ApplyResult::Split {
+            left: self.shard.clone(),
+            right: right_shard,
+        }
+    }
+
+    /// Freezes the shard. Its state stays as it is until it is merged.
+    pub(crate) fn prepare_merge(&mut self) -> ApplyResult {
+        self.frozen = true;
+        ApplyResult::MergePrepared
+    }
+
+    /// Unfreezes the shard. Only proposed once the merge it was frozen for
+    /// is rejected by the left neighbour, or was never proposed to it.
+    pub(crate) fn abort_merge(&mut self) -> ApplyResult {
+        self.frozen = false;
+        ApplyResult::MergeAborted
+    }
+
+    /// Merges the frozen shard in `snapshot`, which must start where this
+    /// shard ends, into this shard.
+    ///
+    /// The client sessions of the merged shard are dropped. A lease known to
+    /// both shards keeps the keys of both, and expires at the later of the two
+    /// expiry times. Revisions continue from the larger revision of the two
+    /// shards. Revisions before the merge cannot be read.
+    pub(crate) fn commit_merge(&mut self, snapshot: &[u8]) -> ApplyResult {
+        let right: StateMachine = match bincode::deserialize(snapshot) {
+            Ok(right) => right,
+            Err(e) => {
+                log::warn!("Cannot decode the shard to merge: {}", e);
+                return ApplyResult::WrongShard(self.shard.clone());
+            }
+        };
+        if !right.frozen || self.shard.end.as_ref() != Some(&right.shard.start) {
+            return ApplyResult::WrongShard(self.shard.clone());
+        }
+
+        if right.revision > self.revision {
+            self.revision_offset += right.revision - self.revision;
+            self.revision = right.revision;
+        }
+        self.db.extend(right.db);
+        for (id, lease) in right.leases {
+            match self.leases.entry(id) {
+                Entry::Occupied(entry) => {
+                    let existing = entry.into_mut();
+                    existing.keys.extend(lease.keys);
+                    existing.expires_at = existing.expires_at.max(lease.expires_at);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(lease);
+                }
+            }
+        }
+        self.history.clear();
+        self.history_start = self.revision;
+
+        self.shard.end = right.shard.end;
+        self.shard.generation = self.shard.generation.max(right.shard.generation) + 1;
+        if let Some(shard_events) = &self.shard_events {
+            let _ = shard_events.send(ShardEvent::Merged(right.shard.group));
+        }
+        ApplyResult::Merged(self.shard.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{log_array::Index, state_machine::encode_command};
+
+    fn shard(group: u64, start: &str, end: Option<&str>, generation: u64) -> Shard {
+        Shard {
+            group: GroupId(group),
+            start: start.as_bytes().to_vec(),
+            end: end.map(|end| end.as_bytes().to_vec()),
+            generation,
+        }
+    }
+
+    fn apply(
+        state_machine: &mut StateMachine,
+        index: Index,
+        kind: CommandKind,
+        key: &str,
+        value: Option<Vec<u8>>,
+    ) -> ApplyResult {
+        let command = Command::new(kind, key.as_bytes().to_vec(), value);
+        state_machine
+            .apply(index, &encode_command(command))
+            .unwrap()
+    }
+
+    fn set(state_machine: &mut StateMachine, index: Index, key: &str) -> ApplyResult {
+        let value = Some(key.as_bytes().to_vec());
+        apply(state_machine, index, CommandKind::SetCommand, key, value)
+    }
+
+    // A shard of all keys holding "a" to "d", split at "c" into group 7.
+    // Returns both halves, and the events of the left one.
+    fn split_shard() -> (
+        StateMachine,
+        StateMachine,
+        mpsc::UnboundedReceiver<ShardEvent>,
+    ) {
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let mut left = StateMachine {
+            shard_events: Some(sender),
+            ..Default::default()
+        };
+        for (index, key) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            set(&mut left, index + 1, key);
+        }
+        let split = CommandKind::SplitCommand { new_group: 7 };
+        assert_eq!(
+            apply(&mut left, 5, split, "c", None),
+            ApplyResult::Split {
+                left: shard(0, "", Some("c"), 1),
+                right: shard(7, "c", None, 1),
+            }
+        );
+        let Ok(ShardEvent::Split(right)) = events.try_recv() else {
+            panic!("the split should create a shard");
+        };
+        (left, *right, events)
+    }
+
+    #[test]
+    fn routing_table_keeps_the_latest_shards() {
+        let mut routing = RoutingTable::default();
+        assert!(routing.update(shard(0, "", None, 0)));
+        assert_eq!(routing.lookup(b"x"), Some(&shard(0, "", None, 0)));
+
+        // A split replaces the shard it was split from.
+        assert!(routing.update(shard(1, "m", None, 1)));
+        assert_eq!(routing.lookup(b"a"), None);
+        assert_eq!(routing.lookup(b"x"), Some(&shard(1, "m", None, 1)));
+        assert!(routing.update(shard(0, "", Some("m"), 1)));
+        assert_eq!(routing.lookup(b"a"), Some(&shard(0, "", Some("m"), 1)));
+
+        // Shards older than those they overlap are ignored.
+        assert!(!routing.update(shard(0, "", None, 0)));
+        assert!(!routing.update(shard(2, "p", None, 1)));
+        assert_eq!(routing.shards().count(), 2);
+
+        // A merge replaces both halves.
+        assert!(routing.update(shard(0, "", None, 2)));
+        assert_eq!(
+            routing.shards().collect::<Vec<_>>(),
+            vec![&shard(0, "", None, 2)]
+        );
+    }
+
+    #[test]
+    fn splits_move_the_keys_from_the_split_key_on() {
+        let (mut left, mut right, _events) = split_shard();
+        assert_eq!(left.db.keys().collect::<Vec<_>>(), vec![b"a", b"b"]);
+        assert_eq!(right.db.keys().collect::<Vec<_>>(), vec![b"c", b"d"]);
+        assert_eq!(right.shard, shard(7, "c", None, 1));
+
+        assert_eq!(
+            set(&mut left, 6, "x"),
+            ApplyResult::WrongShard(shard(0, "", Some("c"), 1))
+        );
+        assert_eq!(set(&mut right, 6, "x"), ApplyResult::Stored);
+        // The split key must be inside the shard, after its start.
+        let split = CommandKind::SplitCommand { new_group: 8 };
+        assert!(matches!(
+            apply(&mut left, 7, split, "", None),
+            ApplyResult::WrongShard(_)
+        ));
+    }
+
+    #[test]
+    fn merges_take_the_keys_of_the_frozen_neighbour() {
+        let (mut left, mut right, mut events) = split_shard();
+        let commit = |right: &StateMachine| Some(right.snapshot());
+
+        // Only a frozen shard can be merged.
+        let result = apply(
+            &mut left,
+            6,
+            CommandKind::CommitMergeCommand,
+            "",
+            commit(&right),
+        );
+        assert!(matches!(result, ApplyResult::WrongShard(_)));
+
+        let result = apply(&mut right, 6, CommandKind::PrepareMergeCommand, "", None);
+        assert_eq!(result, ApplyResult::MergePrepared);
+        assert!(matches!(
+            set(&mut right, 7, "x"),
+            ApplyResult::WrongShard(_)
+        ));
+
+        let result = apply(
+            &mut left,
+            7,
+            CommandKind::CommitMergeCommand,
+            "",
+            commit(&right),
+        );
+        assert_eq!(result, ApplyResult::Merged(shard(0, "", None, 2)));
+        assert_eq!(left.db.len(), 4);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ShardEvent::Merged(GroupId(7)))
+        ));
+
+        // Sent again, the merge is rejected with the merged shard.
+        let result = apply(
+            &mut left,
+            8,
+            CommandKind::CommitMergeCommand,
+            "",
+            commit(&right),
+        );
+        assert_eq!(result, ApplyResult::WrongShard(shard(0, "", None, 2)));
+    }
+
+    #[test]
+    fn aborted_merges_unfreeze_the_shard() {
+        let (_left, mut right, _events) = split_shard();
+        apply(&mut right, 6, CommandKind::PrepareMergeCommand, "", None);
+        let result = apply(&mut right, 7, CommandKind::AbortMergeCommand, "", None);
+        assert_eq!(result, ApplyResult::MergeAborted);
+        assert_eq!(set(&mut right, 8, "x"), ApplyResult::Stored);
+    }
+}
@@ -1,9 +1,106 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-#[derive(Debug)]
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    lease::Lease,
+    log_array::Index,
+    shard::{Shard, ShardEvent},
+    watch::{WatchEvent, Watcher},
+};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum CommandKind {
     GetCommand,
     SetCommand,
+    RegisterSessionCommand,
+    DeleteCommand,
+    /// Sets the value if the current value is `expected`. `None` means the
+    /// key must not exist.
+    CompareAndSwapCommand {
+        expected: Option<Vec<u8>>,
+    },
+    AppendCommand,
+    /// Adds `delta` to the integer stored at the key, wrapping around on
+    /// overflow. A missing key counts as zero.
+    IncrementCommand {
+        delta: i64,
+    },
+    /// Runs the `success` operations if all `compares` hold, the `failure`
+    /// operations otherwise. The key of the command is not used.
+    TxnCommand {
+        compares: Vec<Compare>,
+        success: Vec<TxnOp>,
+        failure: Vec<TxnOp>,
+    },
+    /// Creates a lease that expires `ttl_millis` after the last keep-alive.
+    /// The ID of the lease is the revision of this command.
+    LeaseGrantCommand {
+        ttl_millis: u64,
+    },
+    LeaseKeepAliveCommand {
+        lease: u64,
+    },
+    /// Deletes the lease and all keys attached to it.
+    LeaseRevokeCommand {
+        lease: u64,
+    },
+    /// Revokes the lease if it has expired in log time. Proposed by the
+    /// leader when the lease expires on its clock, which a keep-alive applied
+    /// first may have made stale.
+    LeaseExpireCommand {
+        lease: u64,
+    },
+    /// Splits the shard at the key of the command. Keys from the split key on
+    /// move to a new shard, replicated by `new_group`.
+    SplitCommand {
+        new_group: u64,
+    },
+    /// Freezes the shard, so that it can be merged into its left neighbour.
+    /// A frozen shard applies no command other than this one and
+    /// AbortMerge.
+    PrepareMergeCommand,
+    /// Merges the frozen right neighbour of the shard, whose snapshot is the
+    /// value of the command, into the shard.
+    CommitMergeCommand,
+    /// Unfreezes the shard, once its merge can no longer be committed.
+    AbortMergeCommand,
+}
+
+/// A condition on a key, checked by a transaction.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Compare {
+    pub key: Vec<u8>,
+    pub target: CompareTarget,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CompareTarget {
+    /// The key exists and holds this value.
+    Value(Vec<u8>),
+    /// The key exists, or does not exist if false.
+    Exists(bool),
+    /// The key has been written this many times since it was created. A key
+    /// that does not exist has version 0.
+    Version(u64),
+    /// The key was last written at this revision. A key that does not exist
+    /// has revision 0.
+    ModRevision(u64),
+    /// The key was created at this revision. A key that does not exist has
+    /// revision 0.
+    CreateRevision(u64),
+}
+
+/// A write done by a transaction.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TxnOp {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
 }
 
 impl CommandKind {
@@ -11,116 +108,1540 @@ impl CommandKind {
         match self {
             CommandKind::GetCommand => 0,
             CommandKind::SetCommand => 1,
+            CommandKind::RegisterSessionCommand => 2,
+            CommandKind::DeleteCommand => 3,
+            CommandKind::CompareAndSwapCommand { .. } => 4,
+            CommandKind::AppendCommand => 5,
+            CommandKind::IncrementCommand { .. } => 6,
+            CommandKind::TxnCommand { .. } => 7,
+            CommandKind::LeaseGrantCommand { .. } => 8,
+            CommandKind::LeaseKeepAliveCommand { .. } => 9,
+            CommandKind::LeaseRevokeCommand { .. } => 10,
+            CommandKind::SplitCommand { .. } => 11,
+            CommandKind::PrepareMergeCommand => 12,
+            CommandKind::CommitMergeCommand => 13,
+            CommandKind::AbortMergeCommand => 14,
+            CommandKind::LeaseExpireCommand { .. } => 15,
         }
     }
 
+    /// Returns the kind of tag `value`. The arguments of the kind, if any, are
+    /// filled in by the decoder.
     fn from_u8(value: u8) -> Option<Self> {
         match value {
             0 => Some(CommandKind::GetCommand),
             1 => Some(CommandKind::SetCommand),
+            2 => Some(CommandKind::RegisterSessionCommand),
+            3 => Some(CommandKind::DeleteCommand),
+            4 => Some(CommandKind::CompareAndSwapCommand { expected: None }),
+            5 => Some(CommandKind::AppendCommand),
+            6 => Some(CommandKind::IncrementCommand { delta: 0 }),
+            7 => Some(CommandKind::TxnCommand {
+                compares: vec![],
+                success: vec![],
+                failure: vec![],
+            }),
+            8 => Some(CommandKind::LeaseGrantCommand { ttl_millis: 0 }),
+            9 => Some(CommandKind::LeaseKeepAliveCommand { lease: 0 }),
+            10 => Some(CommandKind::LeaseRevokeCommand { lease: 0 }),
+            11 => Some(CommandKind::SplitCommand { new_group: 0 }),
+            12 => Some(CommandKind::PrepareMergeCommand),
+            13 => Some(CommandKind::CommitMergeCommand),
+            14 => Some(CommandKind::AbortMergeCommand),
+            15 => Some(CommandKind::LeaseExpireCommand { lease: 0 }),
             _ => None,
         }
     }
+
+    fn has_value(&self) -> bool {
+        matches!(
+            self,
+            CommandKind::SetCommand
+                | CommandKind::CompareAndSwapCommand { .. }
+                | CommandKind::AppendCommand
+                | CommandKind::CommitMergeCommand
+        )
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Command {
     pub kind: CommandKind,
-    pub key: String,
-    pub value: String,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    // Session of the client that sent the command, 0 if there is none
+    pub client_id: u64,
+    // Position of the command among the commands of the session
+    pub sequence: u64,
+    // Wall clock time of the server that proposed the command, in millis
+    pub timestamp: u64,
+    // Lease the key of a Set command is attached to, 0 if there is none
+    pub lease: u64,
 }
-/// Our state machine will have two operations: get a value from a key, and set a key to a value
+/// The operations of our state machine. All of them work on a single key.
 impl Command {
-    pub fn new(kind: CommandKind, key: String, value: Option<String>) -> Self {
+    pub fn new(kind: CommandKind, key: Vec<u8>, value: Option<Vec<u8>>) -> Self {
         Command {
             kind,
             key,
-            value: if let Some(val) = value {
-                val
-            } else {
-                String::from("")
-            },
+            value: value.unwrap_or_default(),
+            client_id: 0,
+            sequence: 0,
+            timestamp: now_millis(),
+            lease: 0,
         }
     }
+
+    /// Opens a new client session. The ID of the session is the revision of
+    /// this command.
+    pub fn register_session() -> Self {
+        Self::new(CommandKind::RegisterSessionCommand, vec![], None)
+    }
+
+    /// Marks the command as the `sequence`-th command of session `client_id`.
+    /// Sequence numbers start at 1.
+    pub fn with_session(self, client_id: u64, sequence: u64) -> Self {
+        Command {
+            client_id,
+            sequence,
+            ..self
+        }
+    }
+
+    /// Attaches the key of a Set command to `lease`. The key is deleted when
+    /// the lease expires or is revoked.
+    pub fn with_lease(self, lease: u64) -> Self {
+        Command { lease, ..self }
+    }
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
 }
 
+// Version of the encoding written by `encode_command()`
+const FORMAT_VERSION: u8 = 1;
+
+// Format version, kind, client ID, sequence and timestamp
+const HEADER_LEN: usize = 1 + 1 + 8 + 8 + 8;
+
+/// Encodes `c` as: the format version, the kind tag, the client ID, sequence
+/// and timestamp, the key, then the arguments of the kind:
+/// - Set: the value, then the lease as u64 in little endian.
+/// - Append and CommitMerge: the value.
+/// - CompareAndSwap: the new value, a byte that tells if an expected value
+///   follows, and the expected value.
+/// - Increment: the delta, as i64 in little endian.
+/// - LeaseGrant: the TTL. LeaseKeepAlive, LeaseRevoke and LeaseExpire: the
+///   lease. Split: the new group. All as u64 in little endian.
+/// - Txn: the compares, the success operations and the failure operations.
+///   Each list is written as its length followed by its items. A compare is
+///   the key, a tag byte and the target: a string for Value (0), a byte for
+///   Exists (1), or an u64 for Version (2), ModRevision (3) and
+///   CreateRevision (4). An operation
+///   is a tag byte, the key and, for Put (0) only, the value. Delete is
+///   tagged 1.
+///
+/// Keys and values are written as their length (as u64 in little endian)
+/// followed by their bytes.
 pub fn encode_command(c: Command) -> Vec<u8> {
     let mut msg = Vec::new();
 
-    // Write the kind
+    // Write the version and the kind
+    msg.push(FORMAT_VERSION);
     msg.push(c.kind.index());
 
-    // Write the length of the key (as u64 in little endian)
-    let key_len = (c.key.len() as u64).to_le_bytes();
-    msg.extend_from_slice(&key_len);
+    // Write the session and the time of the command (as u64 in little endian)
+    msg.extend_from_slice(&c.client_id.to_le_bytes());
+    msg.extend_from_slice(&c.sequence.to_le_bytes());
+    msg.extend_from_slice(&c.timestamp.to_le_bytes());
 
-    // Write the key itself
-    msg.extend_from_slice(c.key.as_bytes());
+    // Write the key
+    write_bytes(&mut msg, &c.key);
 
-    // Write the length of the value (as u64 in little endian)
-    let value_len = (c.value.len() as u64).to_le_bytes();
-    msg.extend_from_slice(&value_len);
-
-    // Write the value itself
-    msg.extend_from_slice(c.value.as_bytes());
+    // Write the arguments
+    if c.kind.has_value() {
+        write_bytes(&mut msg, &c.value);
+    }
+    match &c.kind {
+        CommandKind::CompareAndSwapCommand { expected } => match expected {
+            Some(expected) => {
+                msg.push(1);
+                write_bytes(&mut msg, expected);
+            }
+            None => msg.push(0),
+        },
+        CommandKind::SetCommand => msg.extend_from_slice(&c.lease.to_le_bytes()),
+        CommandKind::IncrementCommand { delta } => msg.extend_from_slice(&delta.to_le_bytes()),
+        CommandKind::LeaseGrantCommand { ttl_millis } => {
+            msg.extend_from_slice(&ttl_millis.to_le_bytes())
+        }
+        CommandKind::LeaseKeepAliveCommand { lease }
+        | CommandKind::LeaseRevokeCommand { lease }
+        | CommandKind::LeaseExpireCommand { lease } => msg.extend_from_slice(&lease.to_le_bytes()),
+        CommandKind::SplitCommand { new_group } => msg.extend_from_slice(&new_group.to_le_bytes()),
+        CommandKind::TxnCommand {
+            compares,
+            success,
+            failure,
+        } => {
+            msg.extend_from_slice(&(compares.len() as u64).to_le_bytes());
+            for compare in compares {
+                write_compare(&mut msg, compare);
+            }
+            write_txn_ops(&mut msg, success);
+            write_txn_ops(&mut msg, failure);
+        }
+        _ => {}
+    }
 
     // msg
     msg
 }
 
-fn decode_command(msg: &[u8]) -> Option<Command> {
-    if msg.len() < 9 {
-        return None;
+fn write_bytes(msg: &mut Vec<u8>, value: &[u8]) {
+    msg.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    msg.extend_from_slice(value);
+}
+
+fn write_compare(msg: &mut Vec<u8>, compare: &Compare) {
+    write_bytes(msg, &compare.key);
+    match &compare.target {
+        CompareTarget::Value(value) => {
+            msg.push(0);
+            write_bytes(msg, value);
+        }
+        CompareTarget::Exists(exists) => {
+            msg.push(1);
+            msg.push(*exists as u8);
+        }
+        CompareTarget::Version(version) => {
+            msg.push(2);
+            msg.extend_from_slice(&version.to_le_bytes());
+        }
+        CompareTarget::ModRevision(revision) => {
+            msg.push(3);
+            msg.extend_from_slice(&revision.to_le_bytes());
+        }
+        CompareTarget::CreateRevision(revision) => {
+            msg.push(4);
+            msg.extend_from_slice(&revision.to_le_bytes());
+        }
     }
+}
 
-    let kind = CommandKind::from_u8(msg[0])?;
+fn write_txn_ops(msg: &mut Vec<u8>, ops: &[TxnOp]) {
+    msg.extend_from_slice(&(ops.len() as u64).to_le_bytes());
+    for op in ops {
+        match op {
+            TxnOp::Put { key, value } => {
+                msg.push(0);
+                write_bytes(msg, key);
+                write_bytes(msg, value);
+            }
+            TxnOp::Delete { key } => {
+                msg.push(1);
+                write_bytes(msg, key);
+            }
+        }
+    }
+}
 
-    let key_len = u64::from_le_bytes(msg[1..9].try_into().unwrap()) as usize;
-    if msg.len() < 9 + key_len {
-        return None;
+/// Why a command cannot be decoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The command is written in a format version this server does not know.
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    /// A compare, a transaction operation or a flag has an unknown tag.
+    UnknownTag(u8),
+    /// The command ends in the middle of a field.
+    Truncated,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            Self::UnknownKind(kind) => write!(f, "unknown command kind {}", kind),
+            Self::UnknownTag(tag) => write!(f, "unknown tag {}", tag),
+            Self::Truncated => write!(f, "command is truncated"),
+        }
     }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn read_u8(msg: &[u8], offset: usize) -> Result<u8, DecodeError> {
+    msg.get(offset).copied().ok_or(DecodeError::Truncated)
+}
 
-    let key = String::from_utf8(msg[9..9 + key_len].to_vec()).unwrap();
+fn read_u64(msg: &[u8], offset: usize) -> Result<u64, DecodeError> {
+    let bytes = msg
+        .get(offset..offset.checked_add(8).ok_or(DecodeError::Truncated)?)
+        .ok_or(DecodeError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads the bytes at `offset`, returns them and the offset right after them.
+fn read_bytes(msg: &[u8], offset: usize) -> Result<(Vec<u8>, usize), DecodeError> {
+    let len = read_u64(msg, offset)?;
+    let start = offset + 8;
+    let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| start.checked_add(len))
+        .ok_or(DecodeError::Truncated)?;
+    let bytes = msg.get(start..end).ok_or(DecodeError::Truncated)?;
+    Ok((bytes.to_vec(), end))
+}
 
-    let mut value = String::new();
-    if let CommandKind::SetCommand = kind {
-        if msg.len() < 9 + key_len + 8 {
-            return None;
+/// Reads the compare at `offset`, returns it and the offset right after it.
+fn read_compare(msg: &[u8], offset: usize) -> Result<(Compare, usize), DecodeError> {
+    let (key, offset) = read_bytes(msg, offset)?;
+    let (target, offset) = match read_u8(msg, offset)? {
+        0 => {
+            let (value, offset) = read_bytes(msg, offset + 1)?;
+            (CompareTarget::Value(value), offset)
         }
-        let value_len =
-            u64::from_le_bytes(msg[9 + key_len..9 + key_len + 8].try_into().unwrap()) as usize;
-        if msg.len() < 9 + key_len + 8 + value_len {
-            return None;
+        1 => {
+            let exists = match read_u8(msg, offset + 1)? {
+                0 => false,
+                1 => true,
+                flag => return Err(DecodeError::UnknownTag(flag)),
+            };
+            (CompareTarget::Exists(exists), offset + 2)
         }
-        value =
-            String::from_utf8(msg[9 + key_len + 8..9 + key_len + 8 + value_len].to_vec()).unwrap();
+        2 => (
+            CompareTarget::Version(read_u64(msg, offset + 1)?),
+            offset + 9,
+        ),
+        3 => (
+            CompareTarget::ModRevision(read_u64(msg, offset + 1)?),
+            offset + 9,
+        ),
+        4 => (
+            CompareTarget::CreateRevision(read_u64(msg, offset + 1)?),
+            offset + 9,
+        ),
+        tag => return Err(DecodeError::UnknownTag(tag)),
+    };
+    Ok((Compare { key, target }, offset))
+}
+
+/// Reads the list of operations at `offset`, returns it and the offset right
+/// after it.
+fn read_txn_ops(msg: &[u8], mut offset: usize) -> Result<(Vec<TxnOp>, usize), DecodeError> {
+    let len = read_u64(msg, offset)?;
+    offset += 8;
+    let mut ops = vec![];
+    for _ in 0..len {
+        let tag = read_u8(msg, offset)?;
+        let (key, next) = read_bytes(msg, offset + 1)?;
+        offset = next;
+        let op = match tag {
+            0 => {
+                let (value, next) = read_bytes(msg, offset)?;
+                offset = next;
+                TxnOp::Put { key, value }
+            }
+            1 => TxnOp::Delete { key },
+            tag => return Err(DecodeError::UnknownTag(tag)),
+        };
+        ops.push(op);
     }
+    Ok((ops, offset))
+}
 
-    Some(Command { kind, key, value })
+fn decode_command(msg: &[u8]) -> Result<Command, DecodeError> {
+    let version = read_u8(msg, 0)?;
+    if version != FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    if msg.len() < HEADER_LEN {
+        return Err(DecodeError::Truncated);
+    }
+
+    let mut kind = CommandKind::from_u8(msg[1]).ok_or(DecodeError::UnknownKind(msg[1]))?;
+    let client_id = read_u64(msg, 2)?;
+    let sequence = read_u64(msg, 10)?;
+    let timestamp = read_u64(msg, 18)?;
+
+    let (key, mut offset) = read_bytes(msg, HEADER_LEN)?;
+
+    let mut value = vec![];
+    if kind.has_value() {
+        (value, offset) = read_bytes(msg, offset)?;
+    }
+    let mut lease = 0;
+    match &mut kind {
+        CommandKind::SetCommand => lease = read_u64(msg, offset)?,
+        CommandKind::CompareAndSwapCommand { expected } => {
+            *expected = match read_u8(msg, offset)? {
+                0 => None,
+                1 => Some(read_bytes(msg, offset + 1)?.0),
+                flag => return Err(DecodeError::UnknownTag(flag)),
+            }
+        }
+        CommandKind::IncrementCommand { delta } => *delta = read_u64(msg, offset)? as i64,
+        CommandKind::LeaseGrantCommand { ttl_millis } => *ttl_millis = read_u64(msg, offset)?,
+        CommandKind::LeaseKeepAliveCommand { lease }
+        | CommandKind::LeaseRevokeCommand { lease }
+        | CommandKind::LeaseExpireCommand { lease } => *lease = read_u64(msg, offset)?,
+        CommandKind::SplitCommand { new_group } => *new_group = read_u64(msg, offset)?,
+        CommandKind::TxnCommand {
+            compares,
+            success,
+            failure,
+        } => {
+            let len = read_u64(msg, offset)?;
+            offset += 8;
+            for _ in 0..len {
+                let (compare, next) = read_compare(msg, offset)?;
+                compares.push(compare);
+                offset = next;
+            }
+            (*success, offset) = read_txn_ops(msg, offset)?;
+            (*failure, _) = read_txn_ops(msg, offset)?;
+        }
+        _ => {}
+    }
+
+    Ok(Command {
+        kind,
+        key,
+        value,
+        client_id,
+        sequence,
+        timestamp,
+        lease,
+    })
+}
+
+/// The outcome of applying a command.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ApplyResult {
+    /// A new session is opened with this client ID.
+    SessionRegistered(u64),
+    /// The value of the key, for Get. The removed value, for Delete.
+    Found(KeyValue),
+    /// The key does not exist.
+    NotFound,
+    /// The value is written, for Set and Append.
+    Stored,
+    /// CompareAndSwap found the expected value and replaced it.
+    Swapped,
+    /// CompareAndSwap did not find the expected value, nothing is written.
+    Conflict { current: Option<Vec<u8>> },
+    /// The value of the counter after Increment.
+    Counter(i64),
+    /// Increment found a value that is not an integer, nothing is written.
+    NotACounter { current: Vec<u8> },
+    /// Whether the compares of a transaction held, i.e. which operations
+    /// were run.
+    Txn { succeeded: bool },
+    /// A lease is created with this ID.
+    LeaseGranted { lease: u64, ttl_millis: u64 },
+    /// The lease is extended by its TTL.
+    LeaseKeptAlive { ttl_millis: u64 },
+    /// The lease and its keys are deleted.
+    LeaseRevoked,
+    /// The lease has expired or never existed, nothing is written.
+    LeaseNotFound(u64),
+    /// LeaseExpire found the lease alive, nothing is written.
+    LeaseNotExpired,
+    /// A key of the command is not in the shard, or the shard is frozen for a
+    /// merge. Nothing is written. Holds the shard as it is now.
+    WrongShard(Shard),
+    /// The shard is split in two.
+    Split { left: Shard, right: Shard },
+    /// The shard is frozen, and ready to be merged into its left neighbour.
+    MergePrepared,
+    /// The right neighbour is merged into the shard, which is now this one.
+    Merged(Shard),
+    /// The shard is no longer frozen.
+    MergeAborted,
+}
+
+/// Why a command of a client session was not applied.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SessionError {
+    /// The session was never registered, or it has expired.
+    UnknownSession(u64),
+    /// A later command of the session has been applied already, the response
+    /// to this one is gone.
+    StaleSequence { client_id: u64, sequence: u64 },
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownSession(client_id) => write!(f, "unknown session {}", client_id),
+            Self::StaleSequence {
+                client_id,
+                sequence,
+            } => write!(f, "command {} of session {} is stale", sequence, client_id),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+/// Why a committed command was not applied.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ApplyError {
+    Decode(DecodeError),
+    Session(SessionError),
+}
+
+impl From<DecodeError> for ApplyError {
+    fn from(e: DecodeError) -> Self {
+        Self::Decode(e)
+    }
+}
+
+impl From<SessionError> for ApplyError {
+    fn from(e: SessionError) -> Self {
+        Self::Session(e)
+    }
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Decode(e) => write!(f, "malformed command: {}", e),
+            Self::Session(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ApplyError {}
+
+/// The last command applied for a client, so that retries of the command are
+/// answered without applying it again.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClientSession {
+    pub last_sequence: u64,
+    pub response: Option<ApplyResult>,
+    // Log time of the last command of the session
+    pub last_active: u64,
+}
+
+/// Reads a counter written by Increment, i.e. an integer in decimal.
+fn parse_counter(value: &[u8]) -> Option<i64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// A value in the store. Revisions are the log indexes of the commands that
+/// wrote the key.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct KeyValue {
+    pub value: Vec<u8>,
+    // Revision at which the key was created
+    pub create_revision: u64,
+    // Revision of the last write to the key
+    pub mod_revision: u64,
+    // Number of writes since the key was created, starting at 1
+    pub version: u64,
+    // Lease the key is attached to, 0 if there is none
+    pub lease: u64,
+}
+
+/// A key changed by a command, with the value it had right before.
+pub type Change = (Vec<u8>, Option<KeyValue>);
+
+/// Why a read at a past revision cannot be served.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RevisionError {
+    /// The history of the revision has been dropped. Revisions starting at
+    /// `oldest_revision` can be read.
+    Compacted { oldest_revision: u64 },
+    /// The revision has not been applied yet.
+    Future { current_revision: u64 },
 }
 
-#[derive(Debug, Default)]
+impl fmt::Display for RevisionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Compacted { oldest_revision } => {
+                write!(f, "revision compacted, oldest is {}", oldest_revision)
+            }
+            Self::Future { current_revision } => {
+                write!(f, "revision not reached, current is {}", current_revision)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RevisionError {}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct StateMachine {
-    pub db: HashMap<String, String>,
+    pub db: BTreeMap<Vec<u8>, KeyValue>,
+    #[serde(skip)]
     pub server: usize,
+    pub sessions: HashMap<u64, ClientSession>,
+    // Latest command timestamp seen in the log. Every server applies the same
+    // commands in the same order, so sessions expire at the same point of the
+    // log everywhere.
+    pub log_time: u64,
+    // Revision of the last applied command, see `revision_offset`
+    pub revision: u64,
+    // Keys changed by recent revisions, with the values they had right before.
+    // Used to read past revisions.
+    pub history: BTreeMap<u64, Vec<Change>>,
+    pub leases: HashMap<u64, Lease>,
+    // The part of the keyspace served by this state machine
+    pub shard: Shard,
+    // Set once the shard is about to be merged into its left neighbour
+    pub frozen: bool,
+    // Added to log indexes to get revisions. Shards created by a split start
+    // their log over, but keep the revisions of the shard they come from.
+    pub revision_offset: u64,
+    // Revisions before this one cannot be read, e.g. because the keys of the
+    // shard changed at this revision.
+    pub history_start: u64,
+    #[serde(skip)]
+    pub(crate) watchers: Vec<Watcher>,
+    // Changes made by the command being applied, to be sent to watchers
+    #[serde(skip)]
+    pub(crate) pending_events: Vec<WatchEvent>,
+    // Told about splits and merges, so that the host can add and remove
+    // groups
+    #[serde(skip)]
+    pub(crate) shard_events: Option<tokio::sync::mpsc::UnboundedSender<ShardEvent>>,
 }
 
 impl StateMachine {
-    fn apply(&mut self, cmd: &[u8]) -> Option<Vec<u8>> {
-        let c = decode_command(cmd).unwrap();
-
-        match c.kind {
-            CommandKind::GetCommand => {
-                if let Some(value) = self.db.get(&c.key) {
-                    return Some(value.as_bytes().to_vec());
-                } else {
-                    return None;
-                }
+    // Sessions without any command for this long are removed.
+    const SESSION_TIMEOUT_MILLIS: u64 = 60 * 60 * 1000;
+    // Number of past revisions that can be read.
+    const HISTORY_REVISIONS: u64 = 1000;
+
+    /// Applies the command at log index `index`. Commands of a session are
+    /// applied at most once: a retried command gets the response cached when
+    /// it was first applied.
+    ///
+    /// Watchers are notified of the changes once the command is applied.
+    ///
+    /// A command that cannot be decoded changes nothing but the revision, on
+    /// every server.
+    pub fn apply(&mut self, index: Index, cmd: &[u8]) -> Result<ApplyResult, ApplyError> {
+        let result = self.apply_command_at(index, cmd);
+        self.notify_watchers();
+        result
+    }
+
+    fn apply_command_at(&mut self, index: Index, cmd: &[u8]) -> Result<ApplyResult, ApplyError> {
+        self.revision = self.revision_offset + index as u64;
+        self.compact_history();
+
+        let c = decode_command(cmd)?;
+        self.log_time = self.log_time.max(c.timestamp);
+        self.expire_sessions();
+
+        if let CommandKind::RegisterSessionCommand = c.kind {
+            let client_id = self.revision;
+            self.sessions.insert(
+                client_id,
+                ClientSession {
+                    last_active: self.log_time,
+                    ..Default::default()
+                },
+            );
+            return Ok(ApplyResult::SessionRegistered(client_id));
+        }
+        if c.client_id == 0 {
+            return Ok(self.apply_command(&c));
+        }
+
+        let session = self
+            .sessions
+            .get_mut(&c.client_id)
+            .ok_or(SessionError::UnknownSession(c.client_id))?;
+        session.last_active = self.log_time;
+        if let (true, Some(response)) = (c.sequence == session.last_sequence, &session.response) {
+            return Ok(response.clone());
+        }
+        if c.sequence <= session.last_sequence {
+            return Err(SessionError::StaleSequence {
+                client_id: c.client_id,
+                sequence: c.sequence,
             }
+            .into());
+        }
+
+        let response = self.apply_command(&c);
+        let session = self.sessions.get_mut(&c.client_id).unwrap();
+        session.last_sequence = c.sequence;
+        session.response = Some(response.clone());
+        Ok(response)
+    }
+
+    fn apply_command(&mut self, c: &Command) -> ApplyResult {
+        if let Some(result) = self.check_shard(c) {
+            return result;
+        }
+        match &c.kind {
+            CommandKind::GetCommand => match self.db.get(&c.key) {
+                Some(kv) => ApplyResult::Found(kv.clone()),
+                None => ApplyResult::NotFound,
+            },
             CommandKind::SetCommand => {
-                self.db.insert(c.key.clone(), c.value.clone());
-                return None;
+                if c.lease != 0 && !self.leases.contains_key(&c.lease) {
+                    return ApplyResult::LeaseNotFound(c.lease);
+                }
+                self.put_with_lease(&c.key, c.value.clone(), c.lease);
+                ApplyResult::Stored
+            }
+            CommandKind::RegisterSessionCommand => ApplyResult::Stored,
+            CommandKind::DeleteCommand => match self.delete(&c.key) {
+                Some(kv) => ApplyResult::Found(kv),
+                None => ApplyResult::NotFound,
+            },
+            CommandKind::CompareAndSwapCommand { expected } => {
+                let current = self.value(&c.key);
+                if current != expected.as_deref() {
+                    return ApplyResult::Conflict {
+                        current: current.map(<[u8]>::to_vec),
+                    };
+                }
+                self.put(&c.key, c.value.clone());
+                ApplyResult::Swapped
+            }
+            CommandKind::AppendCommand => {
+                let mut value = self.value(&c.key).unwrap_or_default().to_vec();
+                value.extend_from_slice(&c.value);
+                self.put(&c.key, value);
+                ApplyResult::Stored
+            }
+            CommandKind::IncrementCommand { delta } => {
+                let current = match self.value(&c.key) {
+                    Some(value) => match parse_counter(value) {
+                        Some(current) => current,
+                        None => {
+                            return ApplyResult::NotACounter {
+                                current: value.to_vec(),
+                            }
+                        }
+                    },
+                    None => 0,
+                };
+                let counter = current.wrapping_add(*delta);
+                self.put(&c.key, counter.to_string().into_bytes());
+                ApplyResult::Counter(counter)
+            }
+            CommandKind::TxnCommand {
+                compares,
+                success,
+                failure,
+            } => {
+                let succeeded = compares.iter().all(|compare| self.check(compare));
+                let ops = if succeeded { success } else { failure };
+                for op in ops {
+                    match op {
+                        TxnOp::Put { key, value } => self.put(key, value.clone()),
+                        TxnOp::Delete { key } => {
+                            self.delete(key);
+                        }
+                    }
+                }
+                ApplyResult::Txn { succeeded }
+            }
+            CommandKind::LeaseGrantCommand { ttl_millis } => self.grant_lease(*ttl_millis),
+            CommandKind::LeaseKeepAliveCommand { lease } => self.keep_lease_alive(*lease),
+            CommandKind::LeaseRevokeCommand { lease } => self.revoke_lease(*lease),
+            CommandKind::LeaseExpireCommand { lease } => self.expire_lease(*lease),
+            CommandKind::SplitCommand { new_group } => self.split(&c.key, *new_group),
+            CommandKind::PrepareMergeCommand => self.prepare_merge(),
+            CommandKind::CommitMergeCommand => self.commit_merge(&c.value),
+            CommandKind::AbortMergeCommand => self.abort_merge(),
+        }
+    }
+
+    /// Returns the key as it was right after `revision` was applied.
+    pub fn get(&self, key: &[u8], revision: u64) -> Result<Option<KeyValue>, RevisionError> {
+        if revision > self.revision {
+            return Err(RevisionError::Future {
+                current_revision: self.revision,
+            });
+        }
+        if revision < self.oldest_revision() {
+            return Err(RevisionError::Compacted {
+                oldest_revision: self.oldest_revision(),
+            });
+        }
+
+        // The first change after `revision` knows the value at `revision`.
+        for (_, changes) in self.history.range(revision + 1..) {
+            if let Some((_, previous)) = changes.iter().find(|(changed, _)| changed == key) {
+                return Ok(previous.clone());
             }
         }
+        Ok(self.db.get(key).cloned())
+    }
+
+    /// The oldest revision that can be read by `get()`.
+    pub fn oldest_revision(&self) -> u64 {
+        self.revision
+            .saturating_sub(Self::HISTORY_REVISIONS)
+            .max(self.history_start)
+    }
+
+    fn value(&self, key: &[u8]) -> Option<&[u8]> {
+        self.db.get(key).map(|kv| kv.value.as_slice())
+    }
+
+    /// Writes `value`, keeping the key attached to its current lease.
+    fn put(&mut self, key: &[u8], value: Vec<u8>) {
+        let lease = self.db.get(key).map_or(0, |kv| kv.lease);
+        self.put_with_lease(key, value, lease);
+    }
+
+    fn put_with_lease(&mut self, key: &[u8], value: Vec<u8>, lease: u64) {
+        let previous = self.db.get(key).cloned();
+        self.attach_lease(key, previous.as_ref().map_or(0, |kv| kv.lease), lease);
+        let kv = KeyValue {
+            value,
+            create_revision: previous
+                .as_ref()
+                .map_or(self.revision, |kv| kv.create_revision),
+            mod_revision: self.revision,
+            version: previous.as_ref().map_or(0, |kv| kv.version) + 1,
+            lease,
+        };
+        self.db.insert(key.to_vec(), kv);
+        self.record_change(key, previous);
+    }
+
+    pub(crate) fn delete(&mut self, key: &[u8]) -> Option<KeyValue> {
+        let previous = self.db.remove(key)?;
+        self.attach_lease(key, previous.lease, 0);
+        self.record_change(key, Some(previous.clone()));
+        Some(previous)
+    }
+
+    fn record_change(&mut self, key: &[u8], previous: Option<KeyValue>) {
+        self.history
+            .entry(self.revision)
+            .or_default()
+            .push((key.to_vec(), previous));
+        self.add_watch_event(key, self.db.get(key).cloned());
+    }
+
+    /// Drops the changes that are only needed to read revisions older than
+    /// `oldest_revision()`.
+    fn compact_history(&mut self) {
+        let oldest_revision = self.oldest_revision();
+        while let Some(entry) = self.history.first_entry() {
+            if *entry.key() > oldest_revision {
+                break;
+            }
+            entry.remove();
+        }
+    }
+
+    fn check(&self, compare: &Compare) -> bool {
+        let kv = self.db.get(&compare.key);
+        match &compare.target {
+            CompareTarget::Value(value) => kv.is_some_and(|kv| kv.value == *value),
+            CompareTarget::Exists(exists) => kv.is_some() == *exists,
+            CompareTarget::Version(version) => kv.map_or(0, |kv| kv.version) == *version,
+            CompareTarget::ModRevision(revision) => kv.map_or(0, |kv| kv.mod_revision) == *revision,
+            CompareTarget::CreateRevision(revision) => {
+                kv.map_or(0, |kv| kv.create_revision) == *revision
+            }
+        }
+    }
+
+    fn expire_sessions(&mut self) {
+        let log_time = self.log_time;
+        self.sessions.retain(|_, session| {
+            log_time.saturating_sub(session.last_active) < Self::SESSION_TIMEOUT_MILLIS
+        });
+    }
+
+    /// Serializes the data, its recent history, the leases, the client
+    /// sessions and the shard.
+    pub fn snapshot(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Serialization should not fail")
+    }
+
+    /// Replaces the data, its history, the leases, the client sessions and the
+    /// shard with those in `snapshot`.
+    pub fn restore_snapshot(&mut self, snapshot: &[u8]) -> bincode::Result<()> {
+        let restored: StateMachine = bincode::deserialize(snapshot)?;
+        self.db = restored.db;
+        self.sessions = restored.sessions;
+        self.log_time = restored.log_time;
+        self.revision = restored.revision;
+        self.history = restored.history;
+        self.leases = restored.leases;
+        self.shard = restored.shard;
+        self.frozen = restored.frozen;
+        self.revision_offset = restored.revision_offset;
+        self.history_start = restored.history_start;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_and_swap_flag_must_be_zero_or_one() {
+        let command = Command::new(
+            CommandKind::CompareAndSwapCommand { expected: None },
+            b"key".to_vec(),
+            Some(b"new".to_vec()),
+        );
+        let mut msg = encode_command(command.clone());
+        assert_eq!(decode_command(&msg), Ok(command));
+
+        *msg.last_mut().unwrap() = 2;
+        assert_eq!(decode_command(&msg), Err(DecodeError::UnknownTag(2)));
+    }
+
+    fn all_kinds() -> Vec<CommandKind> {
+        vec![
+            CommandKind::GetCommand,
+            CommandKind::SetCommand,
+            CommandKind::RegisterSessionCommand,
+            CommandKind::DeleteCommand,
+            CommandKind::CompareAndSwapCommand { expected: None },
+            CommandKind::CompareAndSwapCommand {
+                expected: Some(b"old".to_vec()),
+            },
+            CommandKind::AppendCommand,
+            CommandKind::IncrementCommand { delta: -3 },
+            CommandKind::TxnCommand {
+                compares: vec![
+                    Compare {
+                        key: b"a".to_vec(),
+                        target: CompareTarget::Value(b"1".to_vec()),
+                    },
+                    Compare {
+                        key: b"b".to_vec(),
+                        target: CompareTarget::Exists(true),
+                    },
+                    Compare {
+                        key: b"c".to_vec(),
+                        target: CompareTarget::Version(2),
+                    },
+                    Compare {
+                        key: b"d".to_vec(),
+                        target: CompareTarget::ModRevision(7),
+                    },
+                    Compare {
+                        key: b"e".to_vec(),
+                        target: CompareTarget::CreateRevision(6),
+                    },
+                ],
+                success: vec![TxnOp::Put {
+                    key: b"a".to_vec(),
+                    value: b"2".to_vec(),
+                }],
+                failure: vec![TxnOp::Delete { key: b"b".to_vec() }],
+            },
+            CommandKind::LeaseGrantCommand { ttl_millis: 1000 },
+            CommandKind::LeaseKeepAliveCommand { lease: 5 },
+            CommandKind::LeaseRevokeCommand { lease: 5 },
+            CommandKind::LeaseExpireCommand { lease: 5 },
+            CommandKind::SplitCommand { new_group: 9 },
+            CommandKind::PrepareMergeCommand,
+            CommandKind::CommitMergeCommand,
+            CommandKind::AbortMergeCommand,
+        ]
+    }
+
+    fn sample(kind: CommandKind) -> Command {
+        let value = kind.has_value().then(|| b"value\0\xff".to_vec());
+        let lease = if kind == CommandKind::SetCommand {
+            4
+        } else {
+            0
+        };
+        Command::new(kind, b"key\xfe".to_vec(), value)
+            .with_session(3, 8)
+            .with_lease(lease)
+    }
+
+    #[test]
+    fn decode_returns_the_encoded_command() {
+        for kind in all_kinds() {
+            let command = sample(kind);
+            assert_eq!(
+                decode_command(&encode_command(command.clone())),
+                Ok(command)
+            );
+        }
+    }
+
+    #[test]
+    fn decode_rejects_truncated_commands() {
+        for kind in all_kinds() {
+            let msg = encode_command(sample(kind));
+            for len in 0..msg.len() {
+                assert_eq!(
+                    decode_command(&msg[..len]),
+                    Err(DecodeError::Truncated),
+                    "{:?} cut at {}",
+                    msg,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decode_rejects_unknown_versions_kinds_and_tags() {
+        let mut msg = encode_command(sample(CommandKind::GetCommand));
+        msg[0] = FORMAT_VERSION + 1;
+        assert_eq!(
+            decode_command(&msg),
+            Err(DecodeError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+
+        let mut msg = encode_command(sample(CommandKind::GetCommand));
+        msg[1] = 200;
+        assert_eq!(decode_command(&msg), Err(DecodeError::UnknownKind(200)));
+
+        let txn = CommandKind::TxnCommand {
+            compares: vec![],
+            success: vec![TxnOp::Delete { key: vec![] }],
+            failure: vec![],
+        };
+        let mut msg = encode_command(sample(txn));
+        // The tag of the only operation follows the key and the number of
+        // compares and operations.
+        let tag = HEADER_LEN + 8 + b"key\xfe".len() + 8 + 8;
+        assert_eq!(msg[tag], 1);
+        msg[tag] = 5;
+        assert_eq!(decode_command(&msg), Err(DecodeError::UnknownTag(5)));
+
+        // A length that does not fit in memory is not trusted.
+        let mut msg = encode_command(sample(CommandKind::GetCommand));
+        msg[HEADER_LEN..HEADER_LEN + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(decode_command(&msg), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn malformed_commands_only_move_the_revision() {
+        let mut sm = StateMachine::default();
+        sm.apply(1, &set(b"a", b"1")).unwrap();
+        let result = sm.apply(2, &[FORMAT_VERSION, 1]);
+        assert_eq!(result, Err(ApplyError::Decode(DecodeError::Truncated)));
+        assert_eq!(sm.revision, 2);
+        assert_eq!(value_at(&sm, b"a", 2), Some(b"1".to_vec()));
+    }
+
+    fn set(key: &[u8], value: &[u8]) -> Vec<u8> {
+        encode_command(Command::new(
+            CommandKind::SetCommand,
+            key.to_vec(),
+            Some(value.to_vec()),
+        ))
+    }
+
+    fn delete(key: &[u8]) -> Vec<u8> {
+        encode_command(Command::new(CommandKind::DeleteCommand, key.to_vec(), None))
+    }
+
+    fn value_at(sm: &StateMachine, key: &[u8], revision: u64) -> Option<Vec<u8>> {
+        sm.get(key, revision).unwrap().map(|kv| kv.value)
+    }
+
+    #[test]
+    fn get_reads_past_revisions() {
+        let mut sm = StateMachine::default();
+        sm.apply(1, &set(b"a", b"1")).unwrap();
+        sm.apply(2, &set(b"b", b"x")).unwrap();
+        sm.apply(3, &set(b"a", b"2")).unwrap();
+        sm.apply(4, &delete(b"a")).unwrap();
+
+        assert_eq!(value_at(&sm, b"a", 0), None);
+        assert_eq!(value_at(&sm, b"a", 1), Some(b"1".to_vec()));
+        assert_eq!(value_at(&sm, b"a", 2), Some(b"1".to_vec()));
+        assert_eq!(value_at(&sm, b"a", 3), Some(b"2".to_vec()));
+        assert_eq!(value_at(&sm, b"a", 4), None);
+        assert_eq!(value_at(&sm, b"b", 1), None);
+        assert_eq!(value_at(&sm, b"b", 4), Some(b"x".to_vec()));
+
+        let kv = sm.get(b"a", 3).unwrap().unwrap();
+        assert_eq!(kv.create_revision, 1);
+        assert_eq!(kv.mod_revision, 3);
+        assert_eq!(kv.version, 2);
+    }
+
+    #[test]
+    fn get_rejects_future_and_compacted_revisions() {
+        let mut sm = StateMachine::default();
+        sm.apply(1, &set(b"a", b"1")).unwrap();
+        assert_eq!(
+            sm.get(b"a", 2),
+            Err(RevisionError::Future {
+                current_revision: 1
+            })
+        );
+
+        let last = StateMachine::HISTORY_REVISIONS + 10;
+        for index in 2..=last as Index {
+            sm.apply(index, &set(b"b", b"x")).unwrap();
+        }
+        let oldest_revision = last - StateMachine::HISTORY_REVISIONS;
+        assert_eq!(sm.oldest_revision(), oldest_revision);
+        assert_eq!(
+            sm.get(b"a", oldest_revision - 1),
+            Err(RevisionError::Compacted { oldest_revision })
+        );
+        assert_eq!(value_at(&sm, b"a", oldest_revision), Some(b"1".to_vec()));
+        assert!(sm
+            .history
+            .keys()
+            .all(|revision| *revision > oldest_revision));
+    }
+
+    fn append(key: &[u8], value: &[u8]) -> Command {
+        Command::new(
+            CommandKind::AppendCommand,
+            key.to_vec(),
+            Some(value.to_vec()),
+        )
+    }
+
+    /// Registers a session at log time `timestamp`, and returns its ID.
+    fn register(sm: &mut StateMachine, index: Index, timestamp: u64) -> u64 {
+        match sm.apply(index, &at(timestamp, Command::register_session())) {
+            Ok(ApplyResult::SessionRegistered(client_id)) => client_id,
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn retried_commands_are_applied_once() {
+        let mut sm = StateMachine::default();
+        let client = register(&mut sm, 1, 1000);
+        assert_eq!(client, 1);
+
+        let command = at(1000, append(b"a", b"x").with_session(client, 1));
+        assert_eq!(sm.apply(2, &command), Ok(ApplyResult::Stored));
+        assert_eq!(sm.apply(3, &command), Ok(ApplyResult::Stored));
+        assert_eq!(sm.db[b"a".as_slice()].value, b"x");
+        assert_eq!(sm.db[b"a".as_slice()].mod_revision, 2);
+
+        // The cached response is the one of the first attempt.
+        let increment = |sequence| {
+            let command = Command::new(
+                CommandKind::IncrementCommand { delta: 1 },
+                b"n".to_vec(),
+                None,
+            );
+            at(1000, command.with_session(client, sequence))
+        };
+        assert_eq!(sm.apply(4, &increment(2)), Ok(ApplyResult::Counter(1)));
+        let command = Command::new(CommandKind::SetCommand, b"n".to_vec(), Some(b"7".to_vec()));
+        sm.apply(5, &at(1000, command)).unwrap();
+        assert_eq!(sm.apply(6, &increment(2)), Ok(ApplyResult::Counter(1)));
+        assert_eq!(sm.db[b"n".as_slice()].value, b"7");
+    }
+
+    #[test]
+    fn stale_and_unknown_sessions_are_rejected() {
+        let mut sm = StateMachine::default();
+        let client = register(&mut sm, 1, 1000);
+        sm.apply(2, &at(1000, append(b"a", b"1").with_session(client, 1)))
+            .unwrap();
+        sm.apply(3, &at(1000, append(b"a", b"3").with_session(client, 3)))
+            .unwrap();
+
+        // Sequence numbers may skip, but not go back.
+        assert_eq!(
+            sm.apply(4, &at(1000, append(b"a", b"2").with_session(client, 2))),
+            Err(ApplyError::Session(SessionError::StaleSequence {
+                client_id: client,
+                sequence: 2
+            }))
+        );
+        assert_eq!(
+            sm.apply(5, &at(1000, append(b"a", b"1").with_session(client, 1))),
+            Err(ApplyError::Session(SessionError::StaleSequence {
+                client_id: client,
+                sequence: 1
+            }))
+        );
+        assert_eq!(
+            sm.apply(6, &at(1000, append(b"a", b"4").with_session(9, 1))),
+            Err(ApplyError::Session(SessionError::UnknownSession(9)))
+        );
+        assert_eq!(sm.db[b"a".as_slice()].value, b"13");
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let timeout = StateMachine::SESSION_TIMEOUT_MILLIS;
+        let mut sm = StateMachine::default();
+        let client = register(&mut sm, 1, 1000);
+        let idle = register(&mut sm, 2, 1000);
+
+        // Commands keep their session alive, not those of other clients.
+        let active = 1000 + timeout / 2;
+        let command = at(active, append(b"a", b"1").with_session(client, 1));
+        assert_eq!(sm.apply(3, &command), Ok(ApplyResult::Stored));
+        let command = Command::new(CommandKind::SetCommand, b"b".to_vec(), Some(vec![]));
+        sm.apply(4, &at(1000 + timeout - 1, command.clone()))
+            .unwrap();
+        assert!(sm.sessions.contains_key(&idle));
+        sm.apply(5, &at(1000 + timeout, command)).unwrap();
+        assert!(!sm.sessions.contains_key(&idle));
+        assert!(sm.sessions.contains_key(&client));
+
+        let command = at(active + timeout, append(b"a", b"2").with_session(client, 2));
+        assert_eq!(
+            sm.apply(6, &command),
+            Err(ApplyError::Session(SessionError::UnknownSession(client)))
+        );
+        assert_eq!(sm.db[b"a".as_slice()].value, b"1");
+    }
+
+    #[test]
+    fn sessions_survive_snapshots() {
+        let mut sm = StateMachine::default();
+        let client = register(&mut sm, 1, 1000);
+        let command = at(1000, append(b"a", b"x").with_session(client, 1));
+        sm.apply(2, &command).unwrap();
+
+        let mut restored = StateMachine::default();
+        restored.restore_snapshot(&sm.snapshot()).unwrap();
+        assert_eq!(restored.log_time, 1000);
+        assert_eq!(restored.apply(3, &command), Ok(ApplyResult::Stored));
+        assert_eq!(restored.db[b"a".as_slice()].value, b"x");
+        let command = at(1000, append(b"a", b"y").with_session(client, 2));
+        assert_eq!(restored.apply(4, &command), Ok(ApplyResult::Stored));
+        assert_eq!(restored.db[b"a".as_slice()].value, b"xy");
+    }
+
+    fn txn(compares: Vec<Compare>, success: Vec<TxnOp>, failure: Vec<TxnOp>) -> Vec<u8> {
+        let kind = CommandKind::TxnCommand {
+            compares,
+            success,
+            failure,
+        };
+        encode_command(Command::new(kind, vec![], None))
+    }
+
+    fn compare(key: &[u8], target: CompareTarget) -> Compare {
+        Compare {
+            key: key.to_vec(),
+            target,
+        }
+    }
+
+    fn put_op(key: &[u8], value: &[u8]) -> TxnOp {
+        TxnOp::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    #[test]
+    fn txn_compares_check_their_target() {
+        let mut sm = StateMachine::default();
+        sm.apply(1, &set(b"a", b"1")).unwrap();
+        sm.apply(2, &set(b"b", b"x")).unwrap();
+        sm.apply(3, &set(b"a", b"2")).unwrap();
+
+        let cases = [
+            (b"a", CompareTarget::Value(b"2".to_vec()), true),
+            (b"a", CompareTarget::Value(b"1".to_vec()), false),
+            (b"a", CompareTarget::Exists(true), true),
+            (b"a", CompareTarget::Exists(false), false),
+            (b"a", CompareTarget::Version(2), true),
+            (b"a", CompareTarget::Version(1), false),
+            (b"a", CompareTarget::CreateRevision(1), true),
+            (b"a", CompareTarget::CreateRevision(3), false),
+            (b"a", CompareTarget::ModRevision(3), true),
+            (b"a", CompareTarget::ModRevision(1), false),
+            // A missing key has no value, and zero for everything else.
+            (b"z", CompareTarget::Value(vec![]), false),
+            (b"z", CompareTarget::Exists(false), true),
+            (b"z", CompareTarget::Version(0), true),
+            (b"z", CompareTarget::CreateRevision(0), true),
+            (b"z", CompareTarget::ModRevision(0), true),
+        ];
+        for (index, (key, target, holds)) in cases.into_iter().enumerate() {
+            let msg = txn(vec![compare(key, target.clone())], vec![], vec![]);
+            assert_eq!(
+                sm.apply(4 + index as Index, &msg),
+                Ok(ApplyResult::Txn { succeeded: holds }),
+                "{:?}",
+                target
+            );
+        }
+        // Transactions that write nothing change nothing.
+        assert_eq!(sm.db[b"a".as_slice()].mod_revision, 3);
+        assert!(sm.history.keys().all(|revision| *revision <= 3));
+    }
+
+    #[test]
+    fn txn_runs_the_branch_of_its_compares() {
+        let mut sm = StateMachine::default();
+        sm.apply(1, &set(b"a", b"1")).unwrap();
+        let success = vec![put_op(b"b", b"s"), TxnOp::Delete { key: b"a".to_vec() }];
+        let failure = vec![put_op(b"c", b"f")];
+
+        // Every compare must hold.
+        let compares = vec![
+            compare(b"a", CompareTarget::Value(b"1".to_vec())),
+            compare(b"b", CompareTarget::Exists(true)),
+        ];
+        assert_eq!(
+            sm.apply(2, &txn(compares, success.clone(), failure.clone())),
+            Ok(ApplyResult::Txn { succeeded: false })
+        );
+        assert_eq!(sm.db[b"c".as_slice()].value, b"f");
+        assert!(sm.db.contains_key(b"a".as_slice()));
+        assert!(!sm.db.contains_key(b"b".as_slice()));
+
+        let compares = vec![
+            compare(b"a", CompareTarget::Value(b"1".to_vec())),
+            compare(b"b", CompareTarget::Exists(false)),
+        ];
+        assert_eq!(
+            sm.apply(3, &txn(compares, success, failure)),
+            Ok(ApplyResult::Txn { succeeded: true })
+        );
+        assert_eq!(sm.db[b"b".as_slice()].value, b"s");
+        assert!(!sm.db.contains_key(b"a".as_slice()));
+        assert_eq!(sm.db[b"c".as_slice()].mod_revision, 2);
+    }
+
+    #[test]
+    fn txn_writes_are_one_revision() {
+        let mut sm = StateMachine::default();
+        sm.apply(1, &set(b"a", b"1")).unwrap();
+        sm.apply(2, &set(b"b", b"1")).unwrap();
+        let success = vec![
+            put_op(b"a", b"2"),
+            put_op(b"c", b"2"),
+            TxnOp::Delete { key: b"b".to_vec() },
+            put_op(b"a", b"3"),
+        ];
+        sm.apply(3, &txn(vec![], success, vec![])).unwrap();
+
+        assert_eq!(sm.revision, 3);
+        assert_eq!(sm.db[b"a".as_slice()].value, b"3");
+        assert_eq!(sm.db[b"a".as_slice()].mod_revision, 3);
+        assert_eq!(sm.db[b"c".as_slice()].mod_revision, 3);
+        assert_eq!(
+            sm.history.keys().copied().collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+
+        // The revision before shows none of the writes, the revision of the
+        // transaction shows all of them.
+        assert_eq!(value_at(&sm, b"a", 2), Some(b"1".to_vec()));
+        assert_eq!(value_at(&sm, b"b", 2), Some(b"1".to_vec()));
+        assert_eq!(value_at(&sm, b"c", 2), None);
+        assert_eq!(value_at(&sm, b"a", 3), Some(b"3".to_vec()));
+        assert_eq!(value_at(&sm, b"b", 3), None);
+        assert_eq!(value_at(&sm, b"c", 3), Some(b"2".to_vec()));
+    }
+
+    /// Encodes a command proposed at log time `timestamp`.
+    fn at(timestamp: u64, command: Command) -> Vec<u8> {
+        encode_command(Command {
+            timestamp,
+            ..command
+        })
+    }
+
+    fn lease_command(kind: CommandKind) -> Command {
+        Command::new(kind, vec![], None)
+    }
+
+    fn set_with_lease(key: &[u8], value: &[u8], lease: u64) -> Command {
+        Command::new(CommandKind::SetCommand, key.to_vec(), Some(value.to_vec())).with_lease(lease)
+    }
+
+    fn lease_keys(sm: &StateMachine, lease: u64) -> Vec<Vec<u8>> {
+        sm.leases[&lease].keys.iter().cloned().collect()
+    }
+
+    #[test]
+    fn granted_leases_get_unique_ids() {
+        let mut sm = StateMachine::default();
+        let grant = |ttl_millis| lease_command(CommandKind::LeaseGrantCommand { ttl_millis });
+        assert_eq!(
+            sm.apply(1, &at(1000, grant(100))),
+            Ok(ApplyResult::LeaseGranted {
+                lease: 1,
+                ttl_millis: 100
+            })
+        );
+        assert_eq!(
+            sm.apply(2, &at(1000, grant(200))),
+            Ok(ApplyResult::LeaseGranted {
+                lease: 2,
+                ttl_millis: 200
+            })
+        );
+        assert_eq!(sm.leases[&1].expires_at, 1100);
+        assert_eq!(sm.leases[&2].expires_at, 1200);
+    }
+
+    #[test]
+    fn keep_alive_extends_leases_until_they_expire() {
+        let mut sm = StateMachine::default();
+        let keep_alive = || lease_command(CommandKind::LeaseKeepAliveCommand { lease: 1 });
+        sm.apply(
+            1,
+            &at(
+                1000,
+                lease_command(CommandKind::LeaseGrantCommand { ttl_millis: 100 }),
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(
+            sm.apply(2, &at(1050, keep_alive())),
+            Ok(ApplyResult::LeaseKeptAlive { ttl_millis: 100 })
+        );
+        assert_eq!(sm.leases[&1].expires_at, 1150);
+        // Too late, even though the lease is not revoked yet.
+        assert_eq!(
+            sm.apply(3, &at(1150, keep_alive())),
+            Ok(ApplyResult::LeaseNotFound(1))
+        );
+        assert_eq!(sm.leases[&1].expires_at, 1150);
+        assert_eq!(
+            sm.apply(
+                4,
+                &at(
+                    1150,
+                    lease_command(CommandKind::LeaseKeepAliveCommand { lease: 7 })
+                )
+            ),
+            Ok(ApplyResult::LeaseNotFound(7))
+        );
+    }
+
+    #[test]
+    fn revoke_deletes_the_keys_of_the_lease() {
+        let mut sm = StateMachine::default();
+        let revoke = || lease_command(CommandKind::LeaseRevokeCommand { lease: 1 });
+        sm.apply(
+            1,
+            &at(
+                1000,
+                lease_command(CommandKind::LeaseGrantCommand { ttl_millis: 100 }),
+            ),
+        )
+        .unwrap();
+        sm.apply(2, &at(1000, set_with_lease(b"a", b"1", 1)))
+            .unwrap();
+        sm.apply(3, &set(b"b", b"2")).unwrap();
+
+        // Revoking does not wait for the lease to expire.
+        assert_eq!(
+            sm.apply(4, &at(1000, revoke())),
+            Ok(ApplyResult::LeaseRevoked)
+        );
+        assert!(!sm.db.contains_key(b"a".as_slice()));
+        assert!(sm.db.contains_key(b"b".as_slice()));
+        assert!(sm.leases.is_empty());
+        assert_eq!(value_at(&sm, b"a", 3), Some(b"1".to_vec()));
+
+        assert_eq!(
+            sm.apply(5, &at(1000, revoke())),
+            Ok(ApplyResult::LeaseNotFound(1))
+        );
+        assert_eq!(
+            sm.apply(6, &at(1000, set_with_lease(b"c", b"3", 1))),
+            Ok(ApplyResult::LeaseNotFound(1))
+        );
+        assert!(!sm.db.contains_key(b"c".as_slice()));
+    }
+
+    #[test]
+    fn writes_move_keys_between_leases() {
+        let mut sm = StateMachine::default();
+        let grant = || lease_command(CommandKind::LeaseGrantCommand { ttl_millis: 100 });
+        sm.apply(1, &at(1000, grant())).unwrap();
+        sm.apply(2, &at(1000, grant())).unwrap();
+
+        sm.apply(3, &at(1000, set_with_lease(b"a", b"1", 1)))
+            .unwrap();
+        assert_eq!(lease_keys(&sm, 1), vec![b"a".to_vec()]);
+        sm.apply(4, &at(1000, set_with_lease(b"a", b"2", 2)))
+            .unwrap();
+        assert!(lease_keys(&sm, 1).is_empty());
+        assert_eq!(lease_keys(&sm, 2), vec![b"a".to_vec()]);
+
+        // Writes other than Set keep the lease of the key.
+        let append = Command::new(
+            CommandKind::AppendCommand,
+            b"a".to_vec(),
+            Some(b"3".to_vec()),
+        );
+        sm.apply(5, &at(1000, append)).unwrap();
+        assert_eq!(sm.db[b"a".as_slice()].lease, 2);
+        assert_eq!(lease_keys(&sm, 2), vec![b"a".to_vec()]);
+
+        sm.apply(6, &at(1000, set_with_lease(b"a", b"4", 0)))
+            .unwrap();
+        assert_eq!(sm.db[b"a".as_slice()].lease, 0);
+        assert!(lease_keys(&sm, 2).is_empty());
+
+        sm.apply(7, &at(1000, set_with_lease(b"a", b"5", 1)))
+            .unwrap();
+        sm.apply(8, &delete(b"a")).unwrap();
+        assert!(lease_keys(&sm, 1).is_empty());
+    }
+
+    #[test]
+    fn expire_only_revokes_leases_expired_in_log_time() {
+        let mut sm = StateMachine::default();
+        let expire = || lease_command(CommandKind::LeaseExpireCommand { lease: 1 });
+        sm.apply(
+            1,
+            &at(
+                1000,
+                lease_command(CommandKind::LeaseGrantCommand { ttl_millis: 100 }),
+            ),
+        )
+        .unwrap();
+        sm.apply(2, &at(1000, set_with_lease(b"a", b"1", 1)))
+            .unwrap();
+
+        // The leader saw the lease expire, but a keep-alive got ahead of the
+        // expire command.
+        sm.apply(
+            3,
+            &at(
+                1090,
+                lease_command(CommandKind::LeaseKeepAliveCommand { lease: 1 }),
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            sm.apply(4, &at(1100, expire())),
+            Ok(ApplyResult::LeaseNotExpired)
+        );
+        assert!(sm.db.contains_key(b"a".as_slice()));
+
+        assert_eq!(
+            sm.apply(5, &at(1190, expire())),
+            Ok(ApplyResult::LeaseRevoked)
+        );
+        assert!(!sm.db.contains_key(b"a".as_slice()));
+        assert!(sm.leases.is_empty());
+        assert_eq!(
+            sm.apply(6, &at(1190, expire())),
+            Ok(ApplyResult::LeaseNotFound(1))
+        );
     }
 }
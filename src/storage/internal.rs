@@ -0,0 +1,46 @@
+use std::num::ParseIntError;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    log_array::LogEntry, membership::Membership, raft_state::Peer, storage::RaftStoredLogEntry,
+};
+
+pub(crate) fn encode_log_entry<Command: Serialize>(log_entry: &LogEntry<Command>) -> Vec<u8> {
+    bincode::serialize(&log_entry.command).expect("Serialization should not fail")
+}
+
+pub(crate) fn decode_log_entry<Command: DeserializeOwned>(
+    stored: &RaftStoredLogEntry,
+) -> bincode::Result<LogEntry<Command>> {
+    Ok(LogEntry {
+        index: stored.index,
+        term: stored.term,
+        command: bincode::deserialize(&stored.command)?,
+    })
+}
+
+pub(crate) fn encode_voted_for(voted_for: &Option<Peer>) -> String {
+    match voted_for {
+        Some(Peer(index)) => index.to_string(),
+        None => String::new(),
+    }
+}
+
+pub(crate) fn decode_voted_for(stored: &str) -> Result<Option<Peer>, ParseIntError> {
+    if stored.is_empty() {
+        return Ok(None);
+    }
+    stored.parse().map(|index| Some(Peer(index)))
+}
+
+pub(crate) fn encode_membership(membership: &Membership) -> Vec<u8> {
+    bincode::serialize(membership).expect("Serialization should not fail")
+}
+
+pub(crate) fn decode_membership(stored: &[u8]) -> bincode::Result<Option<Membership>> {
+    if stored.is_empty() {
+        return Ok(None);
+    }
+    bincode::deserialize(stored).map(Some)
+}
@@ -1,10 +1,20 @@
+mod internal;
+
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+pub(crate) use internal::{
+    decode_log_entry, decode_membership, decode_voted_for, encode_membership, encode_voted_for,
+};
+
 use crate::{
     log_array::{Index, LogEntry},
+    membership::Membership,
     raft_state::{Peer, Term},
 };
 
 /// Adapter from the internal `LogEntry` type to the public interface.
-impl<Command> RaftLogEntryRef for LogEntry<Command> {
+impl<Command: Serialize> RaftLogEntryRef for LogEntry<Command> {
     fn index(&self) -> Index {
         self.index
     }
@@ -14,8 +24,7 @@ impl<Command> RaftLogEntryRef for LogEntry<Command> {
     }
 
     fn command_bytes(&self) -> Vec<u8> {
-        // encode_log_entry(self)
-        vec![]
+        internal::encode_log_entry(self)
     }
 }
 
@@ -24,9 +33,12 @@ impl RaftStoredState {
         self.current_term
     }
 
-    pub(crate) fn voted_for(&self) -> Option<Peer> {
-        // decode_voted_for(&self.voted_for).expect("Persisted log should not contain error")
-        None
+    pub(crate) fn voted_for(&self) -> Result<Option<Peer>, std::num::ParseIntError> {
+        decode_voted_for(&self.voted_for)
+    }
+
+    pub(crate) fn membership(&self) -> bincode::Result<Option<Membership>> {
+        decode_membership(&self.membership)
     }
 }
 
@@ -43,22 +55,54 @@ pub trait RaftLogEntryRef {
 /// An object that writes data to the underlying storage. A typical disk-based
 /// implementation can be implemented as follows:
 /// 1. A file large enough to store a few integers: term, ID of voted for peer,
-/// and a pair of disk offsets of valid log entries.
+///    and a pair of disk offsets of valid log entries.
 /// 2. A list of continuous disk blocks used to store an array of
-/// `RaftStoredLogEntry` bytes.
+///    `RaftStoredLogEntry` bytes.
 /// 3. Another list of continuous disk blocks that stores the application
-/// snapshot.
+///    snapshot.
 ///
 /// TODO: Add default index range check implementation to `append_one_entry()`
 /// and `append_entries()`.
 pub trait RaftStoragePersisterTrait<LogEntry: RaftLogEntryRef>: Send + Sync + 'static {
-    /// Save the term and vote to storage.
-    fn save_term_vote(&self, term: Term, voted_for: String);
+    /// Save the term and vote to storage, without waiting for them to be
+    /// durable. The receiver returned gets a message once they and
+    /// everything saved before them are durable, or is dropped if they cannot
+    /// be written.
+    fn save_term_vote(&self, term: Term, voted_for: String) -> oneshot::Receiver<()>;
 
     /// Append one entry to the saved log, overriding the existing entry at the
     /// same index if it is previously appended. Any existing entries after the
     /// give index are discarded.
     fn append_one_entry(&self, entry: &LogEntry);
+
+    /// Append `entries`, which must have consecutive indexes, like
+    /// `append_one_entry()`, without waiting for them to be durable. The
+    /// receiver returned gets the index of the last entry once the entries
+    /// and everything saved before them are durable, or is dropped if they
+    /// cannot be written.
+    ///
+    /// Entries saved by concurrent callers can then be written together. The
+    /// default implementation writes each entry synchronously.
+    fn append_entries(&self, entries: &[LogEntry]) -> oneshot::Receiver<Index> {
+        for entry in entries {
+            self.append_one_entry(entry);
+        }
+        let (sender, receiver) = oneshot::channel();
+        if let Some(last) = entries.last() {
+            let _ = sender.send(last.index());
+        }
+        receiver
+    }
+
+    /// Save the servers that are in the cluster before the first entry of the
+    /// saved log. Later changes are saved as log entries.
+    fn save_membership(&self, membership: Vec<u8>);
+
+    /// Makes everything saved so far durable, and stops any background work
+    /// of the storage. Called once when the instance is killed, see
+    /// `RaftJoinHandle::join()`; nothing is saved afterwards. Storage that
+    /// saves synchronously has nothing to do.
+    fn close(&self) {}
 }
 
 /// An object that watches the underlying storage system and help Raft decide
@@ -69,7 +113,7 @@ pub trait RaftStorageMonitorTrait: Send + 'static {
 }
 
 /// A concrete type that holds one log entry read from the storage.
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct RaftStoredLogEntry {
     pub index: Index,
     pub term: Term,
@@ -78,11 +122,12 @@ pub struct RaftStoredLogEntry {
 
 /// A concrete type that holds all information that is needed to restore the
 /// Raft log array and application state right after the instance starts.
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct RaftStoredState {
     pub current_term: Term,
     pub voted_for: String,
     pub log: Vec<RaftStoredLogEntry>,
+    pub membership: Vec<u8>,
     pub snapshot_index: Index,
     pub snapshot_term: Term,
     pub snapshot: Vec<u8>,
@@ -103,6 +148,7 @@ pub trait RaftStorageTrait {
     ) -> std::sync::Arc<Self::RaftStoragePersister<LogEntry>>;
 
     /// Reads out the entire saved state, including term, vote, Raft logs and
-    /// the application snapshot.
+    /// the application snapshot. Storage that has never been written to may
+    /// return an error of kind `NotFound`.
     fn read_state(&self) -> std::io::Result<RaftStoredState>;
 }
@@ -0,0 +1,820 @@
+use std::{
+    pin::pin,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::{
+    future::{BoxFuture, Either},
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    daemons::Daemons,
+    log_array::{Index, LogArray, LogEntryEnum},
+    messages::{AppendEntriesArgs, AppendEntriesReply, ConflictHint},
+    raft::{Raft, ReplicableCommand},
+    raft_config::RaftConfig,
+    raft_state::{Peer, RaftState, State, Term},
+    remote::remote_peer::RemotePeer,
+    storage::RaftLogEntryRef,
+};
+
+// An `AppendEntries` request waiting for its reply
+struct SentAppendEntries {
+    term: Term,
+    // Sequence number of the request, see `ClusterMember::sent_appends`
+    sequence: u64,
+    prev_log_index: Index,
+    // The index the peer matches our log up to if the request succeeds
+    matched: Index,
+    // Size of the entries of the request
+    bytes: usize,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SyncLogEntriesDaemon {
+    // `None` wakes up the tasks of all peers
+    sender: broadcast::Sender<Option<Peer>>,
+    // Number of proposals not sent to peers yet
+    batch_size: Arc<Mutex<usize>>,
+    batch_delay: Duration,
+    max_batch_size: usize,
+}
+
+impl SyncLogEntriesDaemon {
+    pub fn create(peer_size: usize, config: &RaftConfig) -> Self {
+        let (sender, _) = broadcast::channel(peer_size.max(1));
+        Self {
+            sender,
+            batch_size: Arc::new(Mutex::new(0)),
+            batch_delay: config.proposal_batch_delay(),
+            max_batch_size: config.max_entries_per_append,
+        }
+    }
+
+    /// Wakes up the task that syncs log entries to `peer`, or the tasks of all
+    /// peers if `peer` is `None`.
+    pub fn trigger(&self, peer: Option<Peer>) {
+        let _ = self.sender.send(peer);
+    }
+
+    /// Adds a new proposal to the current batch. The tasks of all peers are
+    /// woken up once the batch is full, or `proposal_batch_delay_micros` of
+    /// the config after the first proposal of the batch.
+    pub fn add_to_batch(&self, daemons: &Daemons, runtime: &tokio::runtime::Handle) {
+        let mut batch_size = self.batch_size.lock().unwrap();
+        *batch_size += 1;
+        if *batch_size >= self.max_batch_size || self.batch_delay.is_zero() {
+            *batch_size = 0;
+            self.trigger(None);
+        } else if *batch_size == 1 {
+            let this = self.clone();
+            daemons.spawn(runtime, async move {
+                tokio::time::sleep(this.batch_delay).await;
+                this.flush_batch();
+            });
+        }
+    }
+
+    fn flush_batch(&self) {
+        let mut batch_size = self.batch_size.lock().unwrap();
+        if *batch_size > 0 {
+            *batch_size = 0;
+            self.trigger(None);
+        }
+    }
+}
+
+impl<Command: ReplicableCommand> Raft<Command> {
+    /// Schedules a task that replicates log entries to `peer`.
+    ///
+    /// One task is scheduled for each peer. The task waits until it is
+    /// triggered, then keeps sending `AppendEntries` until the peer has every
+    /// entry in our log, or until we are no longer the leader. Requests are
+    /// sent without waiting for replies while the peer is not paused, see
+    /// `ClusterMember::is_paused()`, including for entries proposed while
+    /// requests are in flight. The task exits when the peer leaves the
+    /// cluster.
+    pub(crate) fn schedule_sync_log_entries(&self, peer: RemotePeer<Command, Peer>) {
+        let this = self.clone();
+        let mut trigger = self.sync_log_entries_daemon.sender.subscribe();
+
+        self.daemons.spawn(&self.thread_pool, async move {
+            while this.keep_running.load(Ordering::Relaxed) && !peer.is_stopped() {
+                if !Self::triggered(&mut trigger, peer.unique_id).await {
+                    break;
+                }
+                this.sync_log_entries(&peer, &mut trigger).await;
+                // Requests still in flight are dropped.
+                this.clear_inflight(peer.unique_id);
+            }
+        });
+    }
+
+    /// Waits until the task of `peer` is triggered. Returns false if it can
+    /// no longer be.
+    async fn triggered(trigger: &mut broadcast::Receiver<Option<Peer>>, peer: Peer) -> bool {
+        loop {
+            match trigger.recv().await {
+                Ok(Some(target)) if target != peer => continue,
+                Ok(_) | Err(RecvError::Lagged(_)) => return true,
+                Err(RecvError::Closed) => return false,
+            }
+        }
+    }
+
+    async fn sync_log_entries(
+        &self,
+        peer: &RemotePeer<Command, Peer>,
+        trigger: &mut broadcast::Receiver<Option<Peer>>,
+    ) {
+        let mut inflight = FuturesUnordered::new();
+        while self.keep_running.load(Ordering::Relaxed) {
+            while let Some((args, sequence)) = self.build_append_entries(peer.unique_id) {
+                // Sized out of `inner_state`, since entries are encoded.
+                let bytes = args
+                    .entries
+                    .iter()
+                    .map(|entry| entry.command_bytes().len())
+                    .sum();
+                self.add_inflight_bytes(peer.unique_id, bytes);
+                let sent = SentAppendEntries {
+                    term: args.term,
+                    sequence,
+                    prev_log_index: args.prev_log_index,
+                    matched: args.prev_log_index + args.entries.len(),
+                    bytes,
+                };
+                inflight.push(async move {
+                    let reply = peer.append_entries(args).await;
+                    (sent, reply)
+                });
+            }
+
+            if inflight.is_empty() {
+                return;
+            }
+            // New entries are sent as they are proposed, not only once a
+            // reply makes room for them.
+            let next_reply = pin!(inflight.next());
+            let triggered = pin!(Self::triggered(trigger, peer.unique_id));
+            let (sent, reply) = match futures_util::future::select(next_reply, triggered).await {
+                Either::Left((Some(next_reply), _)) => next_reply,
+                Either::Left((None, _)) | Either::Right((false, _)) => return,
+                Either::Right((true, _)) => continue,
+            };
+            let Ok(reply) = reply else {
+                // Requests in flight might be lost as well. Their entries are
+                // sent again next time.
+                self.reset_next_index(peer.unique_id);
+                return;
+            };
+            if !self.handle_append_entries_reply(peer.unique_id, sent, reply) {
+                return;
+            }
+        }
+    }
+
+    /// Builds the `AppendEntries` request that carries the entries the peer
+    /// is missing, at most `max_entries_per_append` of them, and assumes they
+    /// will be accepted. Returns the request and its sequence number. The
+    /// request is counted as in flight, its size is added by the caller.
+    ///
+    /// Returns `None` if we are not the leader, or the peer is known to have
+    /// all entries, or all entries are in flight, or the peer is paused.
+    fn build_append_entries(&self, peer: Peer) -> Option<(AppendEntriesArgs<Command>, u64)> {
+        let mut rf = self.inner_state.lock().unwrap();
+        if !rf.is_leader() {
+            return None;
+        }
+
+        let log_start = rf.log.start();
+        let log_end = rf.log.end();
+        let member = rf.cluster.get_mut(&peer)?;
+        if member.is_paused(&self.config)
+            || (member.inflight_appends > 0 && member.next_index >= log_end)
+        {
+            return None;
+        }
+        if member.next_index >= log_end && member.match_index + 1 == log_end {
+            return None;
+        }
+
+        let next_index = member.next_index.clamp(log_start + 1, log_end);
+        let entries_end = log_end.min(next_index + self.config.max_entries_per_append);
+        member.next_index = entries_end;
+        member.inflight_appends += 1;
+        member.sent_appends += 1;
+        let sequence = member.sent_appends;
+        let entries = rf.log.between(next_index, entries_end).to_vec();
+
+        let prev_log = rf.log.at(next_index - 1);
+        let args = AppendEntriesArgs {
+            term: rf.current_term,
+            leader_id: self.peer,
+            prev_log_index: prev_log.index,
+            prev_log_term: prev_log.term,
+            entries,
+            leader_commit: rf.commit_index,
+        };
+        Some((args, sequence))
+    }
+
+    fn add_inflight_bytes(&self, peer: Peer, bytes: usize) {
+        let mut rf = self.inner_state.lock().unwrap();
+        if let Some(member) = rf.cluster.get_mut(&peer) {
+            member.inflight_bytes += bytes;
+        }
+    }
+
+    /// Records the reply to an `AppendEntries` request `sent`.
+    ///
+    /// Returns true if there might be more entries to sync.
+    fn handle_append_entries_reply(
+        &self,
+        peer: Peer,
+        sent: SentAppendEntries,
+        reply: AppendEntriesReply,
+    ) -> bool {
+        let SentAppendEntries {
+            term,
+            sequence,
+            prev_log_index,
+            matched,
+            bytes,
+        } = sent;
+        let mut rf = self.inner_state.lock().unwrap();
+        if reply.term > rf.current_term {
+            self.step_down(&mut rf, reply.term);
+            return false;
+        }
+        if rf.current_term != term || !rf.is_leader() {
+            return false;
+        }
+
+        let retry_index = match reply.conflict {
+            Some(conflict) => Self::conflict_retry_index(&rf.log, conflict),
+            None => prev_log_index,
+        };
+        let Some(member) = rf.cluster.get_mut(&peer) else {
+            return false;
+        };
+        member.inflight_appends = member.inflight_appends.saturating_sub(1);
+        member.inflight_bytes = member.inflight_bytes.saturating_sub(bytes);
+        member.record_reply(sequence, reply.success);
+        if reply.success {
+            member.match_index = member.match_index.max(matched);
+            member.next_index = member.next_index.max(member.match_index + 1);
+            self.update_commit_index(&mut rf);
+        } else {
+            // The peer does not have the entry at `prev_log_index`, retry
+            // from where its log diverges from ours, or with the entry before
+            // if it did not say. Requests sent after this one are rejected as
+            // well, and must not move `next_index` forward.
+            member.next_index = member
+                .next_index
+                .min(retry_index)
+                .min(prev_log_index)
+                .max(member.match_index + 1);
+        }
+        true
+    }
+
+    /// The first entry to send again to a peer that rejected a request with
+    /// `conflict`.
+    ///
+    /// If we have entries of the conflicting term, the logs agree up to our
+    /// last entry of that term, and the entries after it are sent. Otherwise
+    /// every entry of the peer from that term on is replaced.
+    fn conflict_retry_index(log: &LogArray<Command>, conflict: ConflictHint) -> Index {
+        match conflict.term {
+            Some(term) => log
+                .last_index_of_term(term)
+                .map_or(conflict.index, |index| index + 1),
+            None => conflict.index,
+        }
+    }
+
+    /// Forgets the entries assumed to be accepted by `peer`, so that they are
+    /// sent again, one request at a time.
+    fn reset_next_index(&self, peer: Peer) {
+        let mut rf = self.inner_state.lock().unwrap();
+        if let Some(member) = rf.cluster.get_mut(&peer) {
+            member.next_index = member.match_index + 1;
+            member.probe();
+        }
+    }
+
+    /// Forgets the requests in flight to `peer`, once they are dropped.
+    fn clear_inflight(&self, peer: Peer) {
+        let mut rf = self.inner_state.lock().unwrap();
+        if let Some(member) = rf.cluster.get_mut(&peer) {
+            member.inflight_appends = 0;
+            member.inflight_bytes = 0;
+        }
+    }
+
+    /// Moves the commit index to the highest entry of the current term that is
+    /// replicated on a quorum of the cluster.
+    ///
+    /// A committed joint configuration is followed by the new configuration.
+    /// A leader that is not a voter any more steps down once the configuration
+    /// entry that removed it is committed.
+    pub(crate) fn update_commit_index(&self, rf: &mut RaftState<Command>) {
+        // We count for the entries we have written, not for every entry in
+        // our log.
+        let durable_index = rf.durable_index;
+        let quorum_index = rf.membership.quorum_index(|peer| {
+            if peer == self.peer {
+                durable_index
+            } else {
+                rf.cluster.get(&peer).map_or(0, |member| member.match_index)
+            }
+        });
+        if quorum_index > rf.commit_index && rf.log.at(quorum_index).term == rf.current_term {
+            rf.commit_index = quorum_index;
+            self.apply_command_signal.notify_one();
+        }
+
+        self.leave_joint_membership(rf);
+        if rf.membership_index <= rf.commit_index && !rf.membership.is_voter(self.peer) {
+            log::info!("{:?} is removed from the cluster", self.peer);
+            let term = rf.current_term;
+            self.step_down(rf, term);
+        }
+    }
+
+    /// Appends the entries of `args` to the log and hands them to storage.
+    /// The reply returned reports success only once they are written.
+    ///
+    /// Requests that are processed one after the other hand their entries to
+    /// storage in the same order, but their replies can be waited for
+    /// concurrently.
+    pub fn process_append_entries(
+        &self,
+        args: AppendEntriesArgs<Command>,
+    ) -> PendingAppendEntriesReply {
+        let mut rf = self.inner_state.lock().unwrap();
+        let mut reply = self.handle_append_entries(&mut rf, args);
+        // Our term may have been moved forward by the request.
+        reply.term_vote = self.wait_term_vote(&rf);
+        reply
+    }
+
+    fn handle_append_entries(
+        &self,
+        rf: &mut RaftState<Command>,
+        args: AppendEntriesArgs<Command>,
+    ) -> PendingAppendEntriesReply {
+        // A killed instance accepts no entries.
+        if args.term < rf.current_term || !self.keep_running.load(Ordering::Relaxed) {
+            return AppendEntriesReply {
+                term: rf.current_term,
+                success: false,
+                conflict: None,
+            }
+            .into();
+        }
+        if args.term > rf.current_term || matches!(rf.state, State::Leader | State::Candidate) {
+            self.step_down(rf, args.term);
+        }
+        rf.leader_id = Some(args.leader_id);
+        rf.leader_contact = Some(Instant::now());
+        self.election.reset_election_timer();
+
+        let conflict = if args.prev_log_index >= rf.log.end() {
+            Some(ConflictHint {
+                term: None,
+                index: rf.log.end(),
+            })
+        } else {
+            let term = rf.log.at(args.prev_log_index).term;
+            (term != args.prev_log_term).then(|| ConflictHint {
+                term: Some(term),
+                index: rf
+                    .log
+                    .first_index_of_term(term)
+                    .expect("The term of an entry in the log should be found"),
+            })
+        };
+        if conflict.is_some() {
+            return AppendEntriesReply {
+                term: rf.current_term,
+                success: false,
+                conflict,
+            }
+            .into();
+        }
+
+        let has_entries = !args.entries.is_empty();
+        let last_new_index = args.prev_log_index + args.entries.len();
+        for entry in args.entries {
+            if entry.index < rf.log.end() {
+                if rf.log.at(entry.index).term == entry.term {
+                    continue;
+                }
+                assert!(
+                    entry.index > rf.commit_index,
+                    "Committed entries should never be overwritten"
+                );
+                rf.log.truncate(entry.index);
+                rf.durable_index = rf.durable_index.min(entry.index - 1);
+                rf.saved_index = rf.saved_index.min(entry.index - 1);
+                rf.truncations += 1;
+                self.signal_durable(rf);
+                if rf.membership_index >= entry.index {
+                    self.restore_membership(rf);
+                }
+            }
+            let membership = match &entry.command {
+                LogEntryEnum::Membership(membership) => Some(membership.clone()),
+                _ => None,
+            };
+            let index = entry.index;
+            rf.log.push(entry);
+            // A configuration takes effect as soon as it is appended.
+            if let Some(membership) = membership {
+                self.set_membership(rf, membership, index);
+            }
+        }
+
+        if args.leader_commit > rf.commit_index {
+            rf.commit_index = rf.commit_index.max(args.leader_commit.min(last_new_index));
+            self.apply_command_signal.notify_one();
+        }
+
+        let reply = AppendEntriesReply {
+            term: rf.current_term,
+            success: true,
+            conflict: None,
+        };
+        // Heartbeats do not move the match index of the leader, and need not
+        // wait for earlier entries.
+        if !has_entries || rf.durable_index >= last_new_index {
+            return reply.into();
+        }
+        if rf.saved_index >= last_new_index {
+            // Sent again, while an earlier request is waiting for them to be
+            // written. The reply waits for them as well.
+            return PendingAppendEntriesReply {
+                reply,
+                term_vote: None,
+                durable: Some(self.wait_durable(rf, last_new_index)),
+            };
+        }
+        let durable = self.save_log_entries(rf);
+        PendingAppendEntriesReply {
+            reply,
+            term_vote: None,
+            durable: Some(async move { durable.await.unwrap_or(false) }.boxed()),
+        }
+    }
+
+    // Returns true once the entries up to `index` are written, or false if
+    // the log is truncated before.
+    fn wait_durable(&self, rf: &RaftState<Command>, index: Index) -> BoxFuture<'static, bool> {
+        let truncations = rf.truncations;
+        let mut durable = self.durable_signal.subscribe();
+        async move {
+            durable
+                .wait_for(|(current, durable_index)| {
+                    *current != truncations || *durable_index >= index
+                })
+                .await
+                .is_ok_and(|durable| durable.0 == truncations)
+        }
+        .boxed()
+    }
+}
+
+/// The reply to an `AppendEntries` request, which may have to wait for the
+/// entries of the request to be written to storage.
+pub struct PendingAppendEntriesReply {
+    reply: AppendEntriesReply,
+    // `None` if there is nothing to wait for
+    term_vote: Option<BoxFuture<'static, ()>>,
+    // `None` if there is nothing to wait for
+    durable: Option<BoxFuture<'static, bool>>,
+}
+
+impl PendingAppendEntriesReply {
+    /// Waits for the entries to be written, if there are any. The reply is a
+    /// rejection if they cannot be, or are replaced in the meantime.
+    pub async fn wait(self) -> AppendEntriesReply {
+        if let Some(term_vote) = self.term_vote {
+            term_vote.await;
+        }
+        if let Some(durable) = self.durable {
+            if !durable.await {
+                return AppendEntriesReply {
+                    success: false,
+                    ..self.reply
+                };
+            }
+        }
+        self.reply
+    }
+}
+
+impl From<AppendEntriesReply> for PendingAppendEntriesReply {
+    fn from(reply: AppendEntriesReply) -> Self {
+        Self {
+            reply,
+            term_vote: None,
+            durable: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicBool, thread};
+
+    use super::*;
+    use crate::{
+        log_array::LogEntry,
+        raft::ReplicationState,
+        test_utils::{wait_for_leader, wait_until, Command, HeldStorage, LocalNetwork},
+    };
+
+    // A log with the terms 1, 1, 2, 2, 2 and 4 at the indexes 1 to 6.
+    fn log() -> LogArray<Command> {
+        let mut log = LogArray::create();
+        for term in [1, 1, 2, 2, 2, 4] {
+            log.add_command(Term(term), term.to_string());
+        }
+        log
+    }
+
+    #[test]
+    fn retry_after_the_last_entry_of_the_conflicting_term() {
+        let retry_index =
+            |term, index| Raft::conflict_retry_index(&log(), ConflictHint { term, index });
+        // The logs agree up to our last entry of the term.
+        assert_eq!(retry_index(Some(Term(2)), 3), 6);
+        assert_eq!(retry_index(Some(Term(1)), 1), 3);
+        // We have no entry of term 3, every entry of the follower from index
+        // 5 on is replaced.
+        assert_eq!(retry_index(Some(Term(3)), 5), 5);
+        // The log of the follower ends at index 4.
+        assert_eq!(retry_index(None, 4), 4);
+    }
+
+    #[test]
+    fn rejections_hint_where_logs_diverge() {
+        let (_network, rafts) = LocalNetwork::cluster(3);
+        let raft = &rafts[0];
+        let term = Term(1000);
+        let entries: Vec<LogEntry<Command>> = log().after(1).to_vec();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let heartbeat = |prev_log_index, prev_log_term| {
+            let reply = raft.process_append_entries(AppendEntriesArgs {
+                term,
+                leader_id: Peer(1),
+                prev_log_index,
+                prev_log_term,
+                entries: vec![],
+                leader_commit: 0,
+            });
+            runtime.block_on(reply.wait())
+        };
+        let _pending = raft.process_append_entries(AppendEntriesArgs {
+            term,
+            leader_id: Peer(1),
+            prev_log_index: 0,
+            prev_log_term: Term(0),
+            entries,
+            leader_commit: 0,
+        });
+
+        let reply = heartbeat(4, Term(3));
+        assert!(!reply.success);
+        let conflict = reply.conflict.unwrap();
+        assert_eq!(conflict.term, Some(Term(2)));
+        assert_eq!(conflict.index, 3);
+
+        let reply = heartbeat(9, Term(4));
+        let conflict = reply.conflict.unwrap();
+        assert_eq!(conflict.term, None);
+        assert_eq!(conflict.index, 7);
+
+        assert!(heartbeat(6, Term(4)).success);
+
+        for raft in rafts {
+            raft.kill().join();
+        }
+    }
+
+    #[test]
+    fn entries_sent_again_wait_to_be_written() {
+        let network = Arc::new(LocalNetwork::default());
+        let storage = HeldStorage::default();
+        let raft = Raft::new(
+            network.clients(Peer(0), 3),
+            0,
+            storage.clone(),
+            network.connector(Peer(0)),
+            RaftConfig::default(),
+            |_, _: Command| {},
+        );
+        let args = AppendEntriesArgs {
+            term: Term(1000),
+            leader_id: Peer(1),
+            prev_log_index: 0,
+            prev_log_term: Term(0),
+            entries: log().after(1).to_vec(),
+            leader_commit: 0,
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let first = runtime.spawn(raft.process_append_entries(args.clone()).wait());
+        // The leader timed out, and sends the same entries again.
+        let again = runtime.spawn(raft.process_append_entries(args).wait());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!first.is_finished());
+        assert!(!again.is_finished());
+
+        storage.release();
+        assert!(runtime.block_on(first).unwrap().success);
+        assert!(runtime.block_on(again).unwrap().success);
+
+        raft.kill().join();
+    }
+
+    #[test]
+    fn proposals_wait_for_the_batch_to_fill_or_time_out() {
+        let config = RaftConfig {
+            max_entries_per_append: 3,
+            proposal_batch_delay_micros: 100_000,
+            ..Default::default()
+        };
+        let daemon = SyncLogEntriesDaemon::create(1, &config);
+        let mut trigger = daemon.sender.subscribe();
+        let daemons = Daemons::create();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let add = || daemon.add_to_batch(&daemons, runtime.handle());
+
+        // The batch is sent once full, before the delay.
+        add();
+        add();
+        assert!(trigger.try_recv().is_err());
+        add();
+        assert_eq!(trigger.try_recv(), Ok(None));
+
+        // And otherwise once the delay has passed since its first proposal.
+        // The timer of the batch above has nothing to send by then.
+        thread::sleep(config.proposal_batch_delay() * 2);
+        assert!(trigger.try_recv().is_err());
+        let started = Instant::now();
+        add();
+        add();
+        thread::sleep(config.proposal_batch_delay() / 2);
+        assert!(trigger.try_recv().is_err());
+        let flushed = runtime.block_on(trigger.recv());
+        assert_eq!(flushed, Ok(None));
+        assert!(started.elapsed() >= config.proposal_batch_delay());
+        thread::sleep(config.proposal_batch_delay());
+        assert!(trigger.try_recv().is_err());
+
+        // Without a delay, each proposal is sent right away.
+        let daemon = SyncLogEntriesDaemon::create(1, &RaftConfig::default());
+        let mut trigger = daemon.sender.subscribe();
+        daemon.add_to_batch(&daemons, runtime.handle());
+        assert_eq!(trigger.try_recv(), Ok(None));
+    }
+
+    // Creates a cluster of `size` whose storage only writes when released.
+    // Returns once there is a leader that has replicated its first entry,
+    // with its index.
+    fn held_cluster(
+        size: usize,
+        config: RaftConfig,
+    ) -> (Vec<Raft<Command>>, Vec<HeldStorage>, usize) {
+        let network = Arc::new(LocalNetwork::default());
+        let storages: Vec<_> = (0..size).map(|_| HeldStorage::default()).collect();
+        let rafts: Vec<_> = (0..size)
+            .map(|index| {
+                let raft = Raft::new(
+                    network.clients(Peer(index), size),
+                    index,
+                    storages[index].clone(),
+                    network.connector(Peer(index)),
+                    config.clone(),
+                    |_, _: Command| {},
+                );
+                network.add(Peer(index), raft.clone());
+                raft
+            })
+            .collect();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let releaser = {
+            let storages = storages.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    storages.iter().for_each(HeldStorage::release);
+                    thread::sleep(Duration::from_millis(2));
+                }
+            })
+        };
+        let leader = wait_for_leader(&rafts);
+        assert!(wait_until(Duration::from_secs(2), || {
+            let rf = rafts[leader].inner_state.lock().unwrap();
+            rf.commit_index >= 1
+                && rf
+                    .cluster
+                    .iter()
+                    .filter(|(peer, _)| peer.0 != leader)
+                    .all(|(_, member)| {
+                        member.replication == ReplicationState::Replicate
+                            && member.inflight_appends == 0
+                    })
+        }));
+        stop.store(true, Ordering::Relaxed);
+        releaser.join().unwrap();
+        (rafts, storages, leader)
+    }
+
+    // Proposes commands to the leader while followers cannot answer, and
+    // returns how many `AppendEntries` each follower has in flight.
+    fn inflight_appends(config: RaftConfig) -> Vec<usize> {
+        let (rafts, storages, leader) = held_cluster(3, config);
+        for index in 0..10 {
+            rafts[leader].start(index.to_string()).unwrap();
+        }
+        // Well within the deadline of the requests.
+        thread::sleep(Duration::from_millis(30));
+        let inflight = {
+            let rf = rafts[leader].inner_state.lock().unwrap();
+            rf.cluster
+                .iter()
+                .filter(|(peer, _)| peer.0 != leader)
+                .map(|(_, member)| member.inflight_appends)
+                .collect()
+        };
+
+        for storage in &storages {
+            storage.release();
+        }
+        for raft in rafts {
+            raft.kill().join();
+        }
+        inflight
+    }
+
+    #[test]
+    fn inflight_appends_are_capped() {
+        let config = RaftConfig {
+            max_entries_per_append: 1,
+            max_inflight_appends: 2,
+            ..Default::default()
+        };
+        assert_eq!(inflight_appends(config), vec![2, 2]);
+    }
+
+    #[test]
+    fn inflight_bytes_are_capped() {
+        // Each request is over the limit by itself.
+        let config = RaftConfig {
+            max_entries_per_append: 1,
+            max_inflight_appends: 8,
+            max_inflight_bytes: 1,
+            ..Default::default()
+        };
+        assert_eq!(inflight_appends(config), vec![1, 1]);
+    }
+
+    #[test]
+    fn entries_commit_once_the_leader_has_written_them() {
+        // The follower alone is not a quorum.
+        let (rafts, storages, leader) = held_cluster(2, RaftConfig::default());
+        let follower = 1 - leader;
+        let index = rafts[leader].start("command".to_owned()).unwrap().index;
+        let replicated = wait_until(Duration::from_secs(1), || {
+            storages[follower].release();
+            let rf = rafts[leader].inner_state.lock().unwrap();
+            rf.cluster[&Peer(follower)].match_index >= index
+        });
+        assert!(replicated);
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(rafts[leader].inner_state.lock().unwrap().commit_index < index);
+        storages[leader].release();
+        assert!(wait_until(Duration::from_secs(1), || rafts[leader]
+            .inner_state
+            .lock()
+            .unwrap()
+            .commit_index
+            >= index));
+
+        for storage in &storages {
+            storage.release();
+        }
+        for raft in rafts {
+            raft.kill().join();
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! An in-process cluster for tests: Raft instances call each other directly,
+//! and links between them can be cut.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::sync::oneshot;
+
+use crate::{
+    kv::storage::KVStorage,
+    log_array::Index,
+    messages::{
+        AppendEntriesArgs, AppendEntriesReply, RequestVoteArgs, RequestVoteReply, TimeoutNowArgs,
+        TimeoutNowReply,
+    },
+    raft::Raft,
+    raft_config::RaftConfig,
+    raft_state::{Peer, Term},
+    remote::remote_raft::{RemoteRaft, RemoteRaftConnector},
+    storage::{RaftLogEntryRef, RaftStoragePersisterTrait, RaftStorageTrait, RaftStoredState},
+};
+
+pub(crate) type Command = String;
+
+#[derive(Default)]
+pub(crate) struct LocalNetwork {
+    instances: Mutex<HashMap<Peer, Raft<Command>>>,
+    // Servers that cannot send or receive anything
+    disconnected: Mutex<HashSet<Peer>>,
+}
+
+impl LocalNetwork {
+    /// Creates a cluster of `size` servers, with the default config.
+    pub fn cluster(size: usize) -> (Arc<Self>, Vec<Raft<Command>>) {
+        let network = Arc::new(LocalNetwork::default());
+        let rafts = (0..size)
+            .map(|index| {
+                let raft = Raft::new(
+                    network.clients(Peer(index), size),
+                    index,
+                    KVStorage::default(),
+                    network.connector(Peer(index)),
+                    RaftConfig::default(),
+                    |_, _: Command| {},
+                );
+                network.add(Peer(index), raft.clone());
+                raft
+            })
+            .collect();
+        (network, rafts)
+    }
+
+    /// Clients of the servers `0..size`, as seen from `from`.
+    pub fn clients(self: &Arc<Self>, from: Peer, size: usize) -> Vec<LocalRaft> {
+        (0..size)
+            .map(|index| LocalRaft {
+                network: self.clone(),
+                from,
+                to: Peer(index),
+            })
+            .collect()
+    }
+
+    /// Connects `from` to servers that join later. Their address is their
+    /// index.
+    pub fn connector(self: &Arc<Self>, from: Peer) -> LocalConnector {
+        LocalConnector {
+            network: self.clone(),
+            from,
+        }
+    }
+
+    pub fn add(&self, peer: Peer, raft: Raft<Command>) {
+        self.instances.lock().unwrap().insert(peer, raft);
+    }
+
+    pub fn disconnect(&self, peer: Peer) {
+        self.disconnected.lock().unwrap().insert(peer);
+    }
+
+    pub fn reconnect(&self, peer: Peer) {
+        self.disconnected.lock().unwrap().remove(&peer);
+    }
+
+    fn target(&self, from: Peer, to: Peer) -> io::Result<Raft<Command>> {
+        let disconnected = self.disconnected.lock().unwrap();
+        if disconnected.contains(&from) || disconnected.contains(&to) {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let instances = self.instances.lock().unwrap();
+        instances
+            .get(&to)
+            .cloned()
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
+
+pub(crate) struct LocalRaft {
+    network: Arc<LocalNetwork>,
+    from: Peer,
+    to: Peer,
+}
+
+#[async_trait]
+impl RemoteRaft<Command> for LocalRaft {
+    async fn request_vote(&self, args: RequestVoteArgs) -> io::Result<RequestVoteReply> {
+        let target = self.network.target(self.from, self.to)?;
+        Ok(target.process_request_vote(args).await)
+    }
+
+    async fn append_entries(
+        &self,
+        args: AppendEntriesArgs<Command>,
+    ) -> io::Result<AppendEntriesReply> {
+        let target = self.network.target(self.from, self.to)?;
+        let reply = target.process_append_entries(args);
+        Ok(reply.wait().await)
+    }
+
+    async fn timeout_now(&self, args: TimeoutNowArgs) -> io::Result<TimeoutNowReply> {
+        let target = self.network.target(self.from, self.to)?;
+        Ok(target.process_timeout_now(args))
+    }
+}
+
+pub(crate) struct LocalConnector {
+    network: Arc<LocalNetwork>,
+    from: Peer,
+}
+
+impl RemoteRaftConnector<Command> for LocalConnector {
+    fn connect(&self, address: &str) -> io::Result<Arc<dyn RemoteRaft<Command>>> {
+        let to = address
+            .parse()
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(Arc::new(LocalRaft {
+            network: self.network.clone(),
+            from: self.from,
+            to: Peer(to),
+        }))
+    }
+}
+
+// Storage that reports entries, terms and votes written only when told
+// to.
+#[derive(Clone, Default)]
+pub(crate) struct HeldStorage {
+    persister: Arc<HeldPersister>,
+}
+
+#[derive(Default)]
+pub(crate) struct HeldPersister {
+    held: Mutex<Vec<(oneshot::Sender<Index>, Index)>>,
+    held_term_votes: Mutex<Vec<oneshot::Sender<()>>>,
+}
+
+impl HeldStorage {
+    /// Reports everything saved so far written.
+    pub fn release(&self) {
+        for sender in self.persister.held_term_votes.lock().unwrap().drain(..) {
+            let _ = sender.send(());
+        }
+        for (sender, index) in self.persister.held.lock().unwrap().drain(..) {
+            let _ = sender.send(index);
+        }
+    }
+}
+
+impl RaftStorageTrait for HeldStorage {
+    type RaftStoragePersister<LogEntry: RaftLogEntryRef> = HeldPersister;
+
+    fn persister<LogEntry: RaftLogEntryRef>(self) -> Arc<HeldPersister> {
+        self.persister
+    }
+
+    fn read_state(&self) -> io::Result<RaftStoredState> {
+        Err(io::ErrorKind::NotFound.into())
+    }
+}
+
+impl<LogEntry: RaftLogEntryRef> RaftStoragePersisterTrait<LogEntry> for HeldPersister {
+    fn save_term_vote(&self, _term: Term, _voted_for: String) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.held_term_votes.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn append_one_entry(&self, _entry: &LogEntry) {}
+
+    fn append_entries(&self, entries: &[LogEntry]) -> oneshot::Receiver<Index> {
+        let (sender, receiver) = oneshot::channel();
+        if let Some(last) = entries.last() {
+            self.held.lock().unwrap().push((sender, last.index()));
+        }
+        receiver
+    }
+
+    fn save_membership(&self, _membership: Vec<u8>) {}
+}
+
+/// Polls `condition` until it holds, for up to `timeout`. Returns whether it
+/// held.
+pub(crate) fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+/// Waits for one of `rafts` to be the only leader, and returns its index.
+pub(crate) fn wait_for_leader(rafts: &[Raft<Command>]) -> usize {
+    let mut leader = None;
+    let elected = wait_until(Duration::from_secs(5), || {
+        let leaders: Vec<_> = (0..rafts.len())
+            .filter(|index| rafts[*index].get_state().1)
+            .collect();
+        leader = leaders.first().copied();
+        leaders.len() == 1
+    });
+    assert!(elected, "No leader was elected");
+    leader.unwrap()
+}
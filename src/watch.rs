@@ -0,0 +1,232 @@
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::state_machine::{KeyValue, StateMachine};
+
+/// A change of one key, made by the command at `revision`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WatchEvent {
+    pub revision: u64,
+    pub key: Vec<u8>,
+    pub kind: WatchEventKind,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WatchEventKind {
+    /// The key is written, and now holds this value.
+    Put(KeyValue),
+    Delete,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WatchMessage {
+    Event(WatchEvent),
+    /// The watch started at a revision whose history has been dropped. No
+    /// event is sent after this message. Revisions after `oldest_revision`
+    /// can be watched.
+    Compacted {
+        oldest_revision: u64,
+    },
+    /// The receiver fell too far behind. No event is sent after this message.
+    /// Events of `next_revision` and later have not been sent, and can be
+    /// watched again starting at it.
+    Canceled {
+        next_revision: u64,
+    },
+}
+
+// Messages a watcher can have queued before it is canceled
+const WATCH_BUFFER: usize = 1024;
+
+/// A subscriber to the changes of a key, or of all keys under a prefix.
+#[derive(Debug)]
+pub(crate) struct Watcher {
+    key: Vec<u8>,
+    prefix: bool,
+    sender: Sender<WatchMessage>,
+}
+
+impl Watcher {
+    /// Sends the events of one revision, all of them or none. If they do not
+    /// fit in the buffer next to a cancel message, the watcher is canceled
+    /// instead. Returns false if the watcher is canceled or its receiver is
+    /// dropped.
+    fn send_revision<'a>(
+        &self,
+        revision: u64,
+        events: impl Iterator<Item = &'a WatchEvent>,
+    ) -> bool {
+        let events: Vec<_> = events.filter(|event| self.matches(&event.key)).collect();
+        if events.len() >= self.sender.capacity() {
+            let _ = self.sender.try_send(WatchMessage::Canceled {
+                next_revision: revision,
+            });
+            return false;
+        }
+        events.into_iter().all(|event| {
+            self.sender
+                .try_send(WatchMessage::Event(event.clone()))
+                .is_ok()
+        })
+    }
+
+    fn matches(&self, key: &[u8]) -> bool {
+        if self.prefix {
+            key.starts_with(&self.key)
+        } else {
+            key == self.key
+        }
+    }
+}
+
+impl StateMachine {
+    /// Subscribes to the changes of `key`, or of all keys that start with
+    /// `key` if `prefix` is set.
+    ///
+    /// Changes made at and after `start_revision` that are still in the
+    /// history are sent first. If `start_revision` is `None`, only changes
+    /// made by commands applied from now on are sent. The watch stops when the
+    /// receiver is dropped, or when the receiver does not keep up and the
+    /// buffered messages reach `WATCH_BUFFER`, see `WatchMessage::Canceled`.
+    pub fn watch(
+        &mut self,
+        key: Vec<u8>,
+        prefix: bool,
+        start_revision: Option<u64>,
+    ) -> Receiver<WatchMessage> {
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        let watcher = Watcher {
+            key,
+            prefix,
+            sender,
+        };
+
+        if let Some(start_revision) = start_revision {
+            let oldest_revision = self.oldest_revision();
+            if oldest_revision > 0 && start_revision <= oldest_revision {
+                let _ = watcher
+                    .sender
+                    .try_send(WatchMessage::Compacted { oldest_revision });
+                return receiver;
+            }
+
+            let changes = self.history.range(start_revision..);
+            for (revision, changes) in changes {
+                let events: Vec<_> = changes
+                    .iter()
+                    .map(|(key, _)| {
+                        let current = self
+                            .get(key, *revision)
+                            .expect("Revisions in the history should be readable");
+                        Self::build_watch_event(*revision, key, current)
+                    })
+                    .collect();
+                if !watcher.send_revision(*revision, events.iter()) {
+                    return receiver;
+                }
+            }
+        }
+
+        self.watchers.push(watcher);
+        receiver
+    }
+
+    /// Records that `key` is changed to `current` by the command being
+    /// applied. Events are sent when the command has been applied.
+    pub(crate) fn add_watch_event(&mut self, key: &[u8], current: Option<KeyValue>) {
+        if self.watchers.is_empty() {
+            return;
+        }
+        let event = Self::build_watch_event(self.revision, key, current);
+        self.pending_events.push(event);
+    }
+
+    /// Sends the events of the last applied command to the watchers, and
+    /// forgets watchers that are canceled or whose receiver is dropped.
+    pub(crate) fn notify_watchers(&mut self) {
+        let events = std::mem::take(&mut self.pending_events);
+        let revision = self.revision;
+        self.watchers.retain(|watcher| {
+            watcher.send_revision(revision, events.iter()) && !watcher.sender.is_closed()
+        });
+    }
+
+    fn build_watch_event(revision: u64, key: &[u8], current: Option<KeyValue>) -> WatchEvent {
+        WatchEvent {
+            revision,
+            key: key.to_vec(),
+            kind: match current {
+                Some(kv) => WatchEventKind::Put(kv),
+                None => WatchEventKind::Delete,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_machine::{encode_command, Command, CommandKind};
+
+    fn set(sm: &mut StateMachine, index: usize, key: &[u8]) {
+        let command = Command::new(CommandKind::SetCommand, key.to_vec(), Some(vec![1]));
+        sm.apply(index, &encode_command(command)).unwrap();
+    }
+
+    fn revisions(receiver: &mut Receiver<WatchMessage>) -> Vec<u64> {
+        let mut revisions = vec![];
+        while let Ok(message) = receiver.try_recv() {
+            match message {
+                WatchMessage::Event(event) => revisions.push(event.revision),
+                message => panic!("unexpected {:?}", message),
+            }
+        }
+        revisions
+    }
+
+    #[test]
+    fn watch_sends_history_then_new_changes() {
+        let mut sm = StateMachine::default();
+        set(&mut sm, 1, b"a/1");
+        set(&mut sm, 2, b"b");
+        set(&mut sm, 3, b"a/2");
+
+        let mut receiver = sm.watch(b"a/".to_vec(), true, Some(2));
+        set(&mut sm, 4, b"a/1");
+        set(&mut sm, 5, b"b");
+        assert_eq!(revisions(&mut receiver), vec![3, 4]);
+    }
+
+    #[test]
+    fn watch_is_canceled_when_the_receiver_falls_behind() {
+        let mut sm = StateMachine::default();
+        let mut receiver = sm.watch(b"a".to_vec(), false, None);
+        let last = WATCH_BUFFER + 10;
+        for index in 1..=last {
+            set(&mut sm, index, b"a");
+        }
+        assert!(sm.watchers.is_empty());
+
+        let mut next = 1;
+        while let Ok(message) = receiver.try_recv() {
+            match message {
+                WatchMessage::Event(event) => {
+                    assert_eq!(event.revision, next);
+                    next += 1;
+                }
+                WatchMessage::Canceled { next_revision } => {
+                    assert_eq!(next_revision, next);
+                    assert!(receiver.try_recv().is_err());
+                }
+                message => panic!("unexpected {:?}", message),
+            }
+        }
+        assert_eq!(next as usize, WATCH_BUFFER);
+
+        // Nothing is lost by watching again from where the watch stopped.
+        let mut receiver = sm.watch(b"a".to_vec(), false, Some(next));
+        assert_eq!(
+            revisions(&mut receiver),
+            (next..=last as u64).collect::<Vec<_>>()
+        );
+    }
+}